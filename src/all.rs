@@ -1,58 +1,119 @@
 //! Provides functions to interact with all hosts at once.
 
-use crate::help;
+use crate::args;
+use crate::cli::{AllArgs, AllCommand};
+use crate::facts;
+use crate::filter;
+use crate::guard;
+use crate::health;
+use crate::history;
 use crate::host;
-use crate::ssh_con::{execute_remote_command, check_host};
-use crate::host::{get_hosts, Host};
-
-/// This function handles all `$rman host` commands.
-pub fn base(args: std::vec::Vec<String>) {
-    if args.len() < 3 {
-        // If the user does not specify a particular command to execute then print the host help message.
-        help::all();
-    }
-    else {
-        // If the user specifies a command, execute that command
-        let cmd: &str = &args[2];
-        //println!("{}", cmd);
-        match cmd {                     // Run various commands based on user input...
-            "status" => run_host_cmd(String::from("uptime")),// all "status"
-            "exec" => exec_cmd(args),   // all "exec"
-            _ => help::all()            // If the user typed something wrong then display the host help message
+use crate::modules;
+use crate::render::Renderer;
+use crate::template;
+use crate::executor::{Executor, HostOutcome};
+use crate::ssh_con::CmdResult;
+use crate::host::Host;
+
+/// This function handles all `$rman all` commands.
+pub fn base(all: AllArgs) {
+    // Narrow down the targeted hosts if a `--where` expression was given.
+    let hosts = match select_hosts(all.where_expr.as_deref(), all.fresh) {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    if hosts.is_empty() {
+        match all.where_expr {
+            Some(expr) => println!("No host matches '{}'", expr),
+            None => println!("{}", host::NO_HOSTS),
         }
+        return;
+    }
+    match all.command {                 // Run various commands based on user input...
+        AllCommand::Status => fleet_status(hosts),
+        AllCommand::Exec { command } => exec_cmd(hosts, &command.join(" ")),
+        AllCommand::Facts => gather_facts(hosts),
+        AllCommand::Template(template) => template::run(hosts, template),
+        AllCommand::Module(module) => modules::run_cli(hosts, module),
+    }
+}
+
+/// Loads all hosts and keeps those matching the `--where` expression, if any. `fresh` forces facts to be re-gathered.
+fn select_hosts(expr: Option<&str>, fresh: bool) -> Result<std::vec::Vec<Host>, String> {
+    match expr {
+        Some(expr) => filter::select(host::get_hosts()?, expr, fresh),
+        None => host::get_hosts(),
     }
 }
 
-fn exec_cmd(args: std::vec::Vec<String>) {
-    // Assemble the command from args
-    let mut cmd = String::new();
-    if args.len() < 3 {
-        help::all();
-    } else {
-        for i in 3..args.len() {
-            cmd.push_str(format!("{} ", args[i]).as_str());
+fn exec_cmd(hosts: std::vec::Vec<Host>, cmd: &str) {
+    if args::check_mode() {
+        preview(&hosts, &[cmd]);
+        return;
+    }
+    let commands = render_commands(&hosts, cmd);
+    guard::confirm_command("run", cmd, &hosts, &commands);
+    let targets = hosts.iter().map(|host| host.alias.clone()).collect();
+    let results = run_commands(hosts, &commands);
+    history::record("all exec", cmd, targets, history::results(&results));
+}
+
+/// Runs `commands[i]` on the i-th host, printing each host's output under its alias, and returns the results by alias.
+pub fn run_commands(hosts: std::vec::Vec<Host>, commands: &[String]) -> std::vec::Vec<HostOutcome<CmdResult>> {
+    let run = args::cancellation();
+    Executor::new(hosts).with_limits(args::limits()).with_cancellation(run.token()).run_each(commands, |outcome| println!("{}:\n{}", outcome.alias, outcome.result.output))
+}
+
+/// Renders a command for every host, exiting before anything is sent if it can't be rendered for one of them.
+pub fn render_commands(hosts: &[Host], cmd: &str) -> std::vec::Vec<String> {
+    match Renderer::load().commands(hosts, cmd) {
+        Ok(commands) => commands,
+        Err(err) => {
+            println!("Unable to render the command for {}", err);
+            std::process::exit(1);
         }
-        run_host_cmd(cmd);
     }
 }
 
-fn run_host_cmd(cmd: String) {
-    // Assemble each host's response into one string to print
-    let mut print_string = String::new();
-    let hosts = host::get_hosts();
-    for host in hosts {
-        print_string.push_str(format!("{}:\n{}\n", &host.alias, execute_remote_command(&host, &cmd)).as_str());
+/// Prints which hosts would be contacted and the commands they would be sent, used by `--check`.
+pub fn preview(hosts: &[Host], cmds: &[&str]) {
+    println!("Check mode, would contact {} hosts:", hosts.len());
+    if cmds.iter().any(|cmd| cmd.contains("{{")) {
+        // Templated commands differ per host.
+        let rendered: std::vec::Vec<std::vec::Vec<String>> = cmds.iter().map(|cmd| render_commands(hosts, cmd)).collect();
+        for (i, host) in hosts.iter().enumerate() {
+            println!("    {}\t{}@{}", host.alias, host.ssh_user, host.ip);
+            for commands in rendered.iter() {
+                println!("        {}", commands[i]);
+            }
+        }
+        return;
+    }
+    for host in hosts.iter() {
+        println!("    {}\t{}@{}", host.alias, host.ssh_user, host.ip);
+    }
+    println!("and send:");
+    for cmd in cmds.iter() {
+        println!("    {}", cmd);
     }
-    print!("{}", print_string);
 }
 
-pub fn check_up_hosts() -> i32 {
-    let mut num_of_hosts = 0;
-    let hosts = get_hosts();
+/// Prints a health summary table of the hosts, exiting with a non-zero status if any of them is critical.
+fn fleet_status(hosts: std::vec::Vec<Host>) {
+    let thresholds = health::Thresholds::load();
+    let reports = health::reports(hosts, thresholds);
+    health::print_summary(&reports);
+    health::exit_on_critical(&reports);
+}
+
+/// Gathers fresh facts from every host and prints them.
+fn gather_facts(hosts: std::vec::Vec<Host>) {
     for host in hosts.iter() {
-        if check_host(host) {
-            num_of_hosts += 1;
-        }
+        println!("{}:", host.alias);
+        facts::print(&facts::gather(host));
+        println!();
     }
-    num_of_hosts
-}
\ No newline at end of file
+}
@@ -0,0 +1,133 @@
+//! Holds the global options parsed from the command line that every module may consult.
+
+use crate::executor::Limits;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio_util::sync::CancellationToken;
+
+/// Set by the global `--check` flag, mutating commands then only report what they would do.
+static CHECK_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--yes-i-am-sure`, destructive operations on protected hosts then run without asking.
+static CONFIRMED: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--concurrency`, `--host-timeout` and `--run-timeout`.
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Set by `--config`.
+static CONFIG: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set by `--context`.
+static CONTEXT: OnceLock<String> = OnceLock::new();
+
+/// Token of the run in progress, cancelled by the first Ctrl-C. Without one Ctrl-C exits right away.
+static CANCEL: Mutex<Option<CancellationToken>> = Mutex::new(None);
+
+/// Turns check mode on or off for the rest of the process.
+pub fn set_check_mode(check: bool) {
+    CHECK_MODE.store(check, Ordering::Relaxed);
+}
+
+/// Whether `--check` was given, in which case nothing on the hosts or in the inventory may change.
+pub fn check_mode() -> bool {
+    CHECK_MODE.load(Ordering::Relaxed)
+}
+
+/// Confirms destructive operations on protected hosts up front.
+pub fn set_confirmed(confirmed: bool) {
+    CONFIRMED.store(confirmed, Ordering::Relaxed);
+}
+
+/// Whether `--yes-i-am-sure` was given.
+pub fn confirmed() -> bool {
+    CONFIRMED.load(Ordering::Relaxed)
+}
+
+/// Sets the concurrency and timeouts used whenever several hosts are worked on.
+pub fn set_limits(limits: Limits) {
+    let _ = LIMITS.set(limits);
+}
+
+/// The concurrency and timeouts given on the command line, or the defaults.
+pub fn limits() -> Limits {
+    LIMITS.get().copied().unwrap_or_default()
+}
+
+/// Sets the configuration file given with `--config`.
+pub fn set_config(path: PathBuf) {
+    let _ = CONFIG.set(path);
+}
+
+/// The configuration file given with `--config`, if any.
+pub fn config() -> Option<PathBuf> {
+    CONFIG.get().cloned()
+}
+
+/// Sets the context given with `--context`.
+pub fn set_context(name: String) {
+    let _ = CONTEXT.set(name);
+}
+
+/// The context given with `--context`, if any.
+pub fn context() -> Option<String> {
+    CONTEXT.get().cloned()
+}
+
+/// Makes a run of the command line cancellable by Ctrl-C, see `interrupt`, until the returned
+/// guard is dropped. Keep it for as long as the run takes.
+pub fn cancellation() -> Cancellable {
+    let token = CancellationToken::new();
+    *CANCEL.lock().unwrap_or_else(|err| err.into_inner()) = Some(token.clone());
+    Cancellable { token }
+}
+
+/// Keeps Ctrl-C cancelling a run rather than exiting, see `cancellation`.
+pub struct Cancellable {
+    token: CancellationToken,
+}
+
+impl Cancellable {
+    /// The token to cancel the run with.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for Cancellable {
+    fn drop(&mut self) {
+        *CANCEL.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+}
+
+/// Cancels the run in progress on Ctrl-C. Returns false if there is nothing left to cancel, the
+/// process should then exit.
+pub fn interrupt() -> bool {
+    match *CANCEL.lock().unwrap_or_else(|err| err.into_inner()) {
+        Some(ref cancel) if !cancel.is_cancelled() => {
+            cancel.cancel();
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctrl_c_only_cancels_while_a_run_is_in_progress() {
+        assert!(!interrupt());
+        let run = cancellation();
+        assert!(interrupt());
+        assert!(run.token().is_cancelled());
+        // A second Ctrl-C exits.
+        assert!(!interrupt());
+        drop(run);
+        let next = cancellation();
+        assert!(!next.token().is_cancelled());
+        drop(next);
+        assert!(!interrupt());
+    }
+}
@@ -0,0 +1,330 @@
+//! Provides the tamper-evident hash chain over the history and `$rman audit`.
+//!
+//! Each history entry stores the hash of the entry before it (`prev_hash`) and its own hash,
+//! computed over its JSON with `hash` and `signature` left empty. Editing an entry changes its
+//! hash, and deleting or reordering entries breaks the `prev_hash` links. The number of entries
+//! and the last hash are also kept in `history.head`, so truncating the log is noticed too.
+//!
+//! If a signing key has been created with `$rman audit keygen`, every hash is also signed with
+//! ed25519 so exported logs can be checked against the public key alone. As anyone can recompute
+//! the plain hashes, a log checked against a key has to be signed throughout. Entries written
+//! before the key was created are only accepted ahead of the signed `audit keygen` entry that
+//! introduced the key, whose `prev_hash` pins down how they end.
+
+use crate::cli::AuditCommand;
+use crate::history::{self, Run};
+use crate::style;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// `prev_hash` of the first entry.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Action of the entry recording the creation of a signing key, with the public key as command.
+pub const KEY_INTRODUCED: &str = "audit keygen";
+
+/// Hash of a run, covering everything but its own `hash` and `signature`.
+pub fn hash(run: &Run) -> String {
+    let mut unsealed = run.clone();
+    unsealed.hash = String::new();
+    unsealed.signature = None;
+    let json = serde_json::to_string(&unsealed).expect("history entries always serialize");
+    hex::encode(Sha256::digest(json.as_bytes()))
+}
+
+/// Links a run to the previous entry, hashes it, and signs it if a signing key exists.
+pub fn seal(run: &mut Run, prev_hash: &str) {
+    seal_with(run, prev_hash, signing_key().as_ref());
+}
+
+/// Links a run to the previous entry, hashes it, and signs it with `key` if given.
+fn seal_with(run: &mut Run, prev_hash: &str, key: Option<&SigningKey>) {
+    run.prev_hash = prev_hash.to_string();
+    run.hash = hash(run);
+    run.signature = key.map(|key| hex::encode(key.sign(run.hash.as_bytes()).to_bytes()));
+}
+
+/// Path of the file holding the entry count and last hash of the history.
+pub fn head_path() -> PathBuf {
+    history::history_path().with_extension("head")
+}
+
+/// Reads the entry count and last hash recorded in the head file.
+pub fn read_head() -> Option<(usize, String)> {
+    let contents = fs::read_to_string(head_path()).ok()?;
+    let mut parts = contents.split_whitespace();
+    Some((parts.next()?.parse().ok()?, parts.next()?.to_string()))
+}
+
+/// Records the entry count and last hash in the head file.
+pub fn write_head(count: usize, last_hash: &str) -> std::io::Result<()> {
+    fs::write(head_path(), format!("{} {}\n", count, last_hash))
+}
+
+/// Path of the local signing key.
+fn key_path() -> PathBuf {
+    crate::context::config_dir().join("audit.key")
+}
+
+/// Loads the local signing key, if one has been created.
+fn signing_key() -> Option<SigningKey> {
+    let bytes = hex::decode(fs::read_to_string(key_path()).ok()?.trim()).ok()?;
+    let secret: [u8; 32] = bytes.as_slice().try_into().ok()?;
+    Some(SigningKey::from_bytes(&secret))
+}
+
+/// This function handles all `$rman audit` commands.
+pub fn base(command: AuditCommand) {
+    match command {
+        AuditCommand::Verify { file, pubkey } => verify(file, pubkey),   // audit "verify"
+        AuditCommand::Keygen => keygen(),                                 // audit "keygen"
+        AuditCommand::Pubkey => match signing_key() {                     // audit "pubkey"
+            Some(key) => println!("{}", hex::encode(key.verifying_key().to_bytes())),
+            None => println!("No signing key, create one with \"$rman audit keygen\""),
+        },
+    }
+}
+
+/// Creates a new signing key readable only by the current user.
+fn keygen() {
+    let path = key_path();
+    if path.exists() {
+        println!("A signing key already exists at {}", path.display());
+        return;
+    }
+    let mut secret = [0u8; 32];
+    if let Err(err) = fs::File::open("/dev/urandom").and_then(|mut random| random.read_exact(&mut secret)) {
+        println!("Unable to generate a key: {}", err);
+        return;
+    }
+    let key = SigningKey::from_bytes(&secret);
+    let written = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)
+        .and_then(|mut file| writeln!(file, "{}", hex::encode(secret)));
+    match written {
+        Ok(_) => {
+            let public = hex::encode(key.verifying_key().to_bytes());
+            // Signed with the new key, vouching for the unsigned entries before it.
+            history::record(KEY_INTRODUCED, &public, vec!(), vec!());
+            println!("Signing key written to {}", path.display());
+            println!("Public key: {}", public);
+        }
+        Err(err) => println!("Unable to write {}: {}", path.display(), err),
+    }
+}
+
+/// Parses a public key given in hex, or as a path to a file containing it.
+fn parse_pubkey(pubkey: &str) -> Result<VerifyingKey, String> {
+    let text = if Path::new(pubkey).is_file() {
+        fs::read_to_string(pubkey).map_err(|err| err.to_string())?
+    } else {
+        pubkey.to_string()
+    };
+    let bytes = hex::decode(text.trim()).map_err(|err| format!("Invalid public key: {}", err))?;
+    let bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| String::from("Invalid public key: expected 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|err| format!("Invalid public key: {}", err))
+}
+
+/// What checking a history found.
+#[derive(Default)]
+struct Report {
+    problems: std::vec::Vec<String>,
+    count: usize,               // Entries in the log.
+    last_hash: String,          // Hash of the last hashed entry.
+    legacy: usize,              // Leading entries written before hashing was introduced.
+    unsigned: usize,            // Entries written before the signing key was introduced.
+}
+
+/// Checks the hash chain of a history and, with a key, its signatures.
+fn check(contents: &str, key: Option<&VerifyingKey>) -> Report {
+    let mut report = Report { last_hash: String::from(GENESIS_HASH), ..Report::default() };
+    let mut signed = 0;
+    // Line of the first unsigned entry that still has to be vouched for by a key introduction.
+    let mut pending: Option<usize> = None;
+    for (i, line) in contents.lines().enumerate() {
+        let entry = i + 1;
+        let run: Run = match serde_json::from_str(line) {
+            Ok(run) => run,
+            Err(err) => {
+                report.problems.push(format!("line {}: not a valid entry: {}", entry, err));
+                continue;
+            }
+        };
+        report.count += 1;
+        if run.hash.is_empty() {
+            // Entries written before hashing was introduced can only lead the log.
+            if report.count == report.legacy + 1 {
+                report.legacy += 1;
+                if key.is_some() {
+                    pending = pending.or(Some(entry));
+                }
+            } else {
+                report.problems.push(format!("line {} ({}): entry has no hash", entry, run.id));
+            }
+            continue;
+        }
+        if run.prev_hash != report.last_hash {
+            report.problems.push(format!("line {} ({}): does not follow the previous entry, entries were deleted, inserted or reordered", entry, run.id));
+        }
+        if hash(&run) != run.hash {
+            report.problems.push(format!("line {} ({}): hash mismatch, the entry was edited", entry, run.id));
+        }
+        report.last_hash = run.hash.clone();
+        let key = match key {
+            Some(key) => key,
+            None => continue,
+        };
+        if run.signature.is_none() && signed == 0 {
+            report.unsigned += 1;
+            pending = pending.or(Some(entry));
+            continue;
+        }
+        signed += 1;
+        let valid = run.signature.as_ref()
+            .and_then(|signature| hex::decode(signature).ok())
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .is_some_and(|signature| key.verify(run.hash.as_bytes(), &signature).is_ok());
+        if !valid {
+            report.problems.push(format!("line {} ({}): missing or invalid signature", entry, run.id));
+        }
+        if let Some(first) = pending.take() {
+            // Only the entry introducing this very key vouches for the entries before it.
+            if !valid || run.action != KEY_INTRODUCED || run.command != hex::encode(key.to_bytes()) {
+                report.problems.push(format!("lines {} to {}: entries are not signed and no signed key introduction follows them, signatures may have been stripped", first, entry - 1));
+            }
+        }
+    }
+    if let Some(first) = pending {
+        report.problems.push(format!("lines {} to {}: entries are not signed and no signed key introduction follows them, signatures may have been stripped", first, report.count));
+    }
+    report
+}
+
+/// Checks the hash chain, signatures and head of a history file, exiting non-zero on any problem.
+/// `file` verifies an exported log instead of the local one, which skips the head check.
+fn verify(file: Option<PathBuf>, pubkey: Option<String>) {
+    let verifying_key = match pubkey {
+        Some(pubkey) => match parse_pubkey(&pubkey) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => signing_key().map(|key| key.verifying_key()),
+    };
+    let exported = file.is_some();
+    let path = file.unwrap_or_else(history::history_path);
+    let contents = fs::read_to_string(&path).unwrap_or_default();
+    let Report { mut problems, count, last_hash: prev_hash, legacy, unsigned } = check(&contents, verifying_key.as_ref());
+
+    if !exported {
+        match read_head() {
+            Some((head_count, head_hash)) if head_count != count || head_hash != prev_hash => problems.push(format!(
+                "history holds {} entries ending in {}, but {} entries ending in {} were written, the log was truncated or replaced",
+                count, &prev_hash[..12], head_count, &head_hash[..head_hash.len().min(12)])),
+            None if count > legacy => problems.push(String::from("the history head file is missing")),
+            _ => (),
+        }
+    }
+
+    if problems.is_empty() {
+        let signed = if verifying_key.is_some() { ", signatures valid" } else { "" };
+        println!("{} {} entries verified{}", style::green("OK"), count - legacy, signed);
+        if legacy > 0 {
+            println!("{} entries predate hashing and could not be verified", legacy);
+        }
+        if unsigned > 0 {
+            println!("{} entries predate the signing key and are not signed", unsigned);
+        }
+    } else {
+        for problem in problems.iter() {
+            println!("{} {}", style::red("FAIL"), problem);
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::test_run as run;
+
+    /// Chains the runs, signing them with `key` from the entry at `signed_from` on.
+    fn log(runs: &[Run], key: &SigningKey, signed_from: usize) -> String {
+        let mut prev_hash = String::from(GENESIS_HASH);
+        let mut lines = vec!();
+        for (i, run) in runs.iter().enumerate() {
+            let mut run = run.clone();
+            seal_with(&mut run, &prev_hash, Some(key).filter(|_| i >= signed_from));
+            prev_hash = run.hash.clone();
+            lines.push(serde_json::to_string(&run).unwrap());
+        }
+        lines.join("\n")
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn history(key: &SigningKey) -> std::vec::Vec<Run> {
+        vec!(
+            run("1", "host exec", "uptime"),
+            run("2", KEY_INTRODUCED, &hex::encode(key.verifying_key().to_bytes())),
+            run("3", "all exec", "apt-get -y upgrade"),
+            run("4", "host del", "web01"),
+        )
+    }
+
+    #[test]
+    fn accepts_a_signed_log_with_entries_before_the_key_introduction() {
+        let key = key();
+        let report = check(&log(&history(&key), &key, 1), Some(&key.verifying_key()));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!((report.count, report.unsigned), (4, 1));
+    }
+
+    #[test]
+    fn rejects_a_log_rehashed_without_signatures() {
+        let key = key();
+        let mut runs = history(&key);
+        runs[2].command = String::from("true");
+        let forged = log(&runs, &key, runs.len());
+        // The plain hash chain of the forgery is intact, only the key catches it.
+        assert!(check(&forged, None).problems.is_empty());
+        assert!(!check(&forged, Some(&key.verifying_key())).problems.is_empty());
+    }
+
+    #[test]
+    fn rejects_unsigned_entries_not_followed_by_the_key_introduction() {
+        let key = key();
+        let mut runs = history(&key);
+        runs.remove(1);
+        let report = check(&log(&runs, &key, 1), Some(&key.verifying_key()));
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+    }
+
+    #[test]
+    fn rejects_unsigned_entries_after_signed_ones() {
+        let key = key();
+        let mut lines: std::vec::Vec<String> = log(&history(&key), &key, 1).lines().map(String::from).collect();
+        let mut last: Run = serde_json::from_str(&lines[3]).unwrap();
+        last.signature = None;
+        lines[3] = serde_json::to_string(&last).unwrap();
+        assert_eq!(check(&lines.join("\n"), Some(&key.verifying_key())).problems.len(), 1);
+    }
+
+    #[test]
+    fn detects_edits_and_reordering_without_a_key() {
+        let key = key();
+        let mut lines: std::vec::Vec<String> = log(&history(&key), &key, 4).lines().map(String::from).collect();
+        lines.swap(2, 3);
+        assert!(!check(&lines.join("\n"), None).problems.is_empty());
+        let edited = log(&history(&key), &key, 4).replace("uptime", "reboot");
+        assert!(!check(&edited, None).problems.is_empty());
+    }
+}
@@ -0,0 +1,719 @@
+//! Provides the typed command tree of rman, from which help, completions and man pages are generated.
+//!
+//! Every command and flag is declared here once. `main` parses the arguments into a `Cli` and hands
+//! the typed values to the module implementing the command.
+
+use crate::context;
+use crate::executor::{self, Limits};
+use crate::host;
+use crate::inventory::Inventory;
+use crate::probe::{self, Level};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A simple server management tool.
+#[derive(Parser)]
+#[command(name = "rman", version, propagate_version = true)]
+pub struct Cli {
+    /// Show what would change or be sent to which hosts without changing anything.
+    #[arg(long, global = true)]
+    pub check: bool,
+
+    /// Run reboots, shutdowns and denylisted commands on protected hosts and contexts without asking.
+    #[arg(long = "yes-i-am-sure", global = true)]
+    pub yes_i_am_sure: bool,
+
+    /// Hosts worked on at the same time.
+    #[arg(long, global = true, value_name = "N", default_value_t = executor::DEFAULT_CONCURRENCY)]
+    pub concurrency: usize,
+
+    /// Seconds a single host may take before it is reported as timed out.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub host_timeout: Option<u64>,
+
+    /// Seconds the whole run may take, hosts not done by then are reported as timed out.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub run_timeout: Option<u64>,
+
+    /// Use this configuration file, overriding `RMAN_CONFIG` and any context.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Use the inventory of this context instead of the current one, overriding `RMAN_CONTEXT`.
+    #[arg(long, global = true, value_name = "NAME", add = ArgValueCompleter::new(complete_contexts))]
+    pub context: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    /// The concurrency and timeouts of the executor.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            concurrency: self.concurrency,
+            host_timeout: self.host_timeout.map(Duration::from_secs),
+            timeout: self.run_timeout.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Create the configuration file, asking for the defaults most hosts share and a first few hosts.
+    Init {
+        /// ssh user most hosts share, written to `[defaults]`.
+        #[arg(long)]
+        ssh_user: Option<String>,
+        /// Private key most hosts share, written to `[defaults]`.
+        #[arg(long)]
+        pk_path: Option<String>,
+    },
+
+    /// Probe every host and display whether it is up, down or fails authentication.
+    #[command(visible_alias = "s")]
+    Status(ProbeArgs),
+
+    /// Interact with selected hosts.
+    #[command(visible_alias = "h", subcommand)]
+    Host(HostCommand),
+
+    /// Interact with all hosts at once.
+    #[command(visible_alias = "a")]
+    All(AllArgs),
+
+    /// Execute the steps of a TOML runbook in order, exiting non-zero if a host failed.
+    ///
+    /// Steps (`[[step]]`) have a name and exactly one of `command`, `push = { src, dest, mode }`,
+    /// `module = { type, ... }` or `reboot = true` (with `wait = seconds`). They may also set
+    /// `targets`, `where`, `when`, `register`, `changed_when`, `ignore_errors` and
+    /// `notify = [handler names]`. Handlers (`[[handler]]`) take the same keys and run once at the
+    /// end on hosts where a notifying step changed something. `targets` and `where` at the top of
+    /// the file select the hosts of the whole runbook.
+    Run {
+        /// The runbook file.
+        file: PathBuf,
+    },
+
+    /// Print a command with {{placeholders}} as each host would receive it.
+    ///
+    /// Placeholders are the host's fields ({{alias}}, {{ip}}, {{ssh_user}}, {{description}},
+    /// {{tags}}), its variables ({{vars.name}}, from `[groups.<tag>.vars]` overridden by
+    /// `[vars.<alias>]`) and its cached facts ({{facts.os}} or {{os}}). Values are inserted as
+    /// single-quoted shell words, {{name | raw}} inserts a trusted value unquoted.
+    Render {
+        /// The hosts to render for.
+        #[arg(add = ArgValueCompleter::new(complete_aliases))]
+        targets: String,
+        /// The command to render.
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Compare remote files or directory trees across hosts, exiting non-zero on drift or unreachable hosts.
+    Drift(DriftArgs),
+
+    /// Evaluate the compliance checks of a TOML file on every host, exiting non-zero on failures.
+    ///
+    /// Each `[[check]]` has a name and one of: `file` (with `mode`, `owner`, `group`), `port`
+    /// (with `protocol = "udp"`), `process`, `package` (with `version = ">=3.0.2"`), `sysctl` (with
+    /// `value`) or `command` (with `matches = regex` and `rc`). `targets` and `where` at the top of
+    /// the file select the hosts to check.
+    Verify {
+        /// The checks file.
+        file: PathBuf,
+        /// Also write the results as JUnit XML, one test suite per host.
+        #[arg(long, value_name = "PATH")]
+        junit: Option<PathBuf>,
+        /// Do not shrink the matrix to the terminal width.
+        #[arg(long)]
+        wide: bool,
+    },
+
+    /// Re-run the command of a recorded run on its failed and unreachable hosts.
+    ///
+    /// Results are merged into the original run, see `rman history show`. Hosts that timed out or
+    /// were cancelled are not retried, the command may have run on them.
+    Retry {
+        /// The run to retry, defaults to the last exec.
+        run_id: Option<String>,
+        /// Only retry hosts where the command failed.
+        #[arg(long)]
+        failed: bool,
+        /// Only retry hosts that could not be reached.
+        #[arg(long)]
+        unreachable: bool,
+    },
+
+    /// Review past runs, reboots, shutdowns and inventory changes.
+    ///
+    /// Set `capture_output = true` in the `[history]` section of rman.toml to keep command output.
+    History(HistoryArgs),
+
+    /// Verify the history has not been tampered with.
+    #[command(subcommand)]
+    Audit(AuditCommand),
+
+    /// Review and undo changes to the configuration file.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Switch between named inventories such as prod and staging.
+    #[command(subcommand)]
+    Context(ContextCommand),
+
+    /// Print the shell completion script, e.g. `source <(rman completions bash)`.
+    Completions {
+        /// The shell to complete for.
+        shell: Shell,
+    },
+
+    /// Generate man pages.
+    Man {
+        /// Write a page for every command into this directory instead of printing the main page.
+        #[arg(long, value_name = "DIR")]
+        out: Option<PathBuf>,
+    },
+}
+
+/// Shells completions are generated for.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// How deep to probe hosts.
+#[derive(Args)]
+pub struct ProbeArgs {
+    /// How far to probe: a TCP connect, the ssh banner or a full ssh login.
+    #[arg(long, value_enum, default_value = "banner")]
+    pub level: Level,
+    /// Milliseconds to wait for each host.
+    #[arg(long, value_name = "MS", default_value_t = probe::DEFAULT_TIMEOUT_MS)]
+    pub timeout: u64,
+}
+
+impl ProbeArgs {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+}
+
+#[derive(Subcommand)]
+pub enum HostCommand {
+    /// Print the host list as a table and whether each host is up.
+    Ls(ListArgs),
+
+    /// Add hosts to the host list, ranges such as `web[01:20] web[01:20].example.com` add several.
+    Add {
+        /// Alias of the host, or a range of aliases.
+        alias: String,
+        /// IP address or host name, or a range paired with the aliases.
+        ip: String,
+        /// User to log in as, inherited from the groups or `[defaults]` if left out.
+        ssh_user: Option<String>,
+        /// Path of the private key, inherited from the groups or `[defaults]` if left out.
+        pk_path: Option<String>,
+        /// A short description.
+        description: Option<String>,
+        /// Comma separated tags.
+        #[arg(long, add = ArgValueCompleter::new(complete_tags))]
+        tags: Option<String>,
+        /// Port of the ssh server, inherited from the groups or `[defaults]` if left out.
+        #[arg(long)]
+        port: Option<u16>,
+    },
+
+    /// Display the settings and variables of the hosts, and which of them are inherited.
+    Show {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+    },
+
+    /// Remove the hosts from the host list.
+    Del {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+    },
+
+    /// Display a health report of the hosts, exiting non-zero if a metric is critical.
+    Status {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+    },
+
+    /// Execute a command on the hosts, {{placeholders}} are rendered per host (see `rman render`).
+    Exec {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        /// The command to run.
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+
+    /// Reboot the hosts.
+    Reboot {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+    },
+
+    /// Shut down the hosts.
+    Shutdown {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+    },
+
+    /// Display the cached facts of the hosts.
+    Facts {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        /// Gather the facts again.
+        #[arg(long)]
+        fresh: bool,
+    },
+
+    /// Replace the tags of the hosts, or clear them if none are given.
+    Tag {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        /// Comma separated tags.
+        #[arg(add = ArgValueCompleter::new(complete_tags))]
+        tags: Option<String>,
+    },
+
+    /// Set the variables of the hosts, `key=` removes one. Without pairs the variables are displayed.
+    Var {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        /// Variables to set as key=value.
+        pairs: Vec<String>,
+    },
+
+    /// Render a template for the hosts and upload it where it changed.
+    Template {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        template: TemplateArgs,
+    },
+
+    #[command(flatten)]
+    Module(HostModule),
+}
+
+/// Help of every targets argument.
+const TARGETS_HELP: &str = "Comma separated aliases, globs (web-*), regexes (~^db\\d+$) or ranges (web[01:12])";
+
+/// Flags of `rman host ls`.
+#[derive(Args)]
+pub struct ListArgs {
+    /// Columns to show: alias, ip, user, key, desc, tags, status, rtt or any fact name.
+    #[arg(long, value_delimiter = ',')]
+    pub columns: Option<Vec<String>>,
+    /// Show more columns and do not shrink the table to the terminal width.
+    #[arg(long)]
+    pub wide: bool,
+    /// Do not probe the hosts.
+    #[arg(long)]
+    pub no_status: bool,
+    /// Only list aliases matching this glob.
+    #[arg(long, value_name = "GLOB")]
+    pub alias: Option<String>,
+    /// Only list hosts with all of these comma separated tags.
+    #[arg(long, add = ArgValueCompleter::new(complete_tags))]
+    pub tag: Option<String>,
+    /// Only list hosts whose cached facts match the expression.
+    #[arg(long = "where", value_name = "EXPR")]
+    pub where_expr: Option<String>,
+    /// Sort by a column, prefixed with - to sort descending.
+    #[arg(long, value_name = "[-]COLUMN", allow_hyphen_values = true)]
+    pub sort: Option<String>,
+    #[command(flatten)]
+    pub probe: ProbeArgs,
+}
+
+/// `rman all` and its host selection.
+#[derive(Args)]
+pub struct AllArgs {
+    /// Only target hosts whose facts match the expression, e.g. 'os=ubuntu && kernel<5.15' or 'disk_free_pct<10'.
+    #[arg(long = "where", value_name = "EXPR", global = true)]
+    pub where_expr: Option<String>,
+    /// Gather facts again instead of using the cached ones when evaluating --where.
+    #[arg(long, global = true)]
+    pub fresh: bool,
+    #[command(subcommand)]
+    pub command: AllCommand,
+}
+
+#[derive(Subcommand)]
+pub enum AllCommand {
+    /// Display a health summary of all hosts, exiting non-zero if any host is critical.
+    Status,
+    /// Execute a command on all hosts, {{placeholders}} are rendered per host (see `rman render`).
+    Exec {
+        /// The command to run.
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Gather and display the facts of all hosts.
+    Facts,
+    /// Render a template for all hosts and upload it where it changed.
+    Template(TemplateArgs),
+    #[command(flatten)]
+    Module(ModuleCommand),
+}
+
+/// Arguments of `template`.
+///
+/// The template uses the placeholders of `rman render`, inserted without quoting.
+#[derive(Args)]
+pub struct TemplateArgs {
+    /// The local template.
+    pub src: PathBuf,
+    /// Where to install the rendered file on the hosts.
+    pub dest: String,
+    /// Mode of the uploaded file, defaults to the current mode or 644 for new files.
+    #[arg(long, value_parser = parse_mode)]
+    pub mode: Option<usize>,
+    /// Keep the old file as dest.YYYYmmddHHMMSS on the host.
+    #[arg(long)]
+    pub backup: bool,
+    /// Run this command on the hosts where the file changed, e.g. 'systemctl reload nginx'.
+    #[arg(long, value_name = "CMD")]
+    pub on_change: Option<String>,
+}
+
+/// Idempotent modules, which check the current state first and only act when needed.
+#[derive(Subcommand)]
+pub enum ModuleCommand {
+    /// Install or remove a package with apt, dnf, pacman or apk.
+    Pkg(PkgArgs),
+    /// Manage a file or directory.
+    File(FileArgs),
+    /// Manage a systemd service.
+    Service(ServiceArgs),
+    /// Manage a user and its supplementary groups.
+    User(UserArgs),
+    /// Manage a group.
+    Group(GroupArgs),
+    /// Ensure a line is in a file, replacing the first line matching --regexp.
+    Line(LineArgs),
+}
+
+/// The modules of `ModuleCommand`, applied to target hosts.
+#[derive(Subcommand)]
+pub enum HostModule {
+    /// Install or remove a package with apt, dnf, pacman or apk.
+    Pkg {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        args: PkgArgs,
+    },
+    /// Manage a file or directory.
+    File {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        args: FileArgs,
+    },
+    /// Manage a systemd service.
+    Service {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        args: ServiceArgs,
+    },
+    /// Manage a user and its supplementary groups.
+    User {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        args: UserArgs,
+    },
+    /// Manage a group.
+    Group {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        args: GroupArgs,
+    },
+    /// Ensure a line is in a file, replacing the first line matching --regexp.
+    Line {
+        #[arg(add = ArgValueCompleter::new(complete_aliases), help = TARGETS_HELP)]
+        targets: String,
+        #[command(flatten)]
+        args: LineArgs,
+    },
+}
+
+impl HostModule {
+    /// Splits the target hosts from the module.
+    pub fn split(self) -> (String, ModuleCommand) {
+        match self {
+            HostModule::Pkg { targets, args } => (targets, ModuleCommand::Pkg(args)),
+            HostModule::File { targets, args } => (targets, ModuleCommand::File(args)),
+            HostModule::Service { targets, args } => (targets, ModuleCommand::Service(args)),
+            HostModule::User { targets, args } => (targets, ModuleCommand::User(args)),
+            HostModule::Group { targets, args } => (targets, ModuleCommand::Group(args)),
+            HostModule::Line { targets, args } => (targets, ModuleCommand::Line(args)),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct PkgArgs {
+    pub action: PkgAction,
+    /// The package.
+    pub name: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum PkgAction {
+    Install,
+    Remove,
+}
+
+#[derive(Args)]
+pub struct FileArgs {
+    /// The remote path.
+    pub path: String,
+    /// Contents of the file.
+    #[arg(long, value_name = "TEXT")]
+    pub content: Option<String>,
+    /// Octal mode, e.g. 644.
+    #[arg(long)]
+    pub mode: Option<String>,
+    #[arg(long, value_enum, default_value = "present")]
+    pub state: FileState,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FileState {
+    Present,
+    Absent,
+    Directory,
+}
+
+#[derive(Args)]
+pub struct ServiceArgs {
+    /// The systemd unit.
+    pub name: String,
+    pub action: Option<ServiceAction>,
+    /// Start the service at boot.
+    #[arg(long, conflicts_with = "disable")]
+    pub enable: bool,
+    /// Do not start the service at boot.
+    #[arg(long)]
+    pub disable: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ServiceAction {
+    Start,
+    Stop,
+    Restart,
+}
+
+#[derive(Args)]
+pub struct UserArgs {
+    /// The user name.
+    pub name: String,
+    #[arg(value_enum, default_value = "present")]
+    pub state: PresenceState,
+    /// Comma separated supplementary groups.
+    #[arg(long, value_delimiter = ',')]
+    pub groups: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct GroupArgs {
+    /// The group name.
+    pub name: String,
+    #[arg(value_enum, default_value = "present")]
+    pub state: PresenceState,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum PresenceState {
+    Present,
+    Absent,
+}
+
+#[derive(Args)]
+pub struct LineArgs {
+    /// The remote file.
+    pub path: String,
+    /// The line.
+    pub line: String,
+    /// Replace the first line matching this regex, the line is appended if nothing matches.
+    #[arg(long, value_name = "RE")]
+    pub regexp: Option<String>,
+    /// Remove the line, or every line matching --regexp, instead.
+    #[arg(long)]
+    pub absent: bool,
+}
+
+/// Arguments of `rman drift`.
+#[derive(Args)]
+pub struct DriftArgs {
+    /// Remote files or directories to compare.
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+    /// Compare against this host instead of the checksum most hosts agree on.
+    #[arg(long, value_name = "ALIAS", add = ArgValueCompleter::new(complete_aliases))]
+    pub golden: Option<String>,
+    /// Only compare these hosts.
+    #[arg(long, add = ArgValueCompleter::new(complete_aliases))]
+    pub targets: Option<String>,
+    /// Only compare hosts whose cached facts match the expression.
+    #[arg(long = "where", value_name = "EXPR")]
+    pub where_expr: Option<String>,
+    /// Only list the differing hosts instead of showing unified diffs of text files.
+    #[arg(long)]
+    pub no_diff: bool,
+}
+
+/// Arguments of `rman history`.
+#[derive(Args)]
+pub struct HistoryArgs {
+    /// Only list runs targeting hosts matching this glob.
+    #[arg(long, value_name = "PATTERN", global = true)]
+    pub host: Option<String>,
+    /// Only list runs since 30m, 12h, 2d, 1w or a date such as 2026-10-01.
+    #[arg(long, global = true)]
+    pub since: Option<String>,
+    /// Only list runs whose action or command matches this regex.
+    #[arg(long, value_name = "REGEX", global = true)]
+    pub grep: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<HistoryCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommand {
+    /// Display the result of every host of a run.
+    Show {
+        run_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommand {
+    /// Check the history for edited, deleted or reordered entries and invalid signatures, exiting non-zero on failure.
+    Verify {
+        /// Verify an exported history file instead of the local one.
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+        /// Public key in hex, or a file containing it.
+        #[arg(long, value_name = "HEX|PATH")]
+        pubkey: Option<String>,
+    },
+    /// Create a local key used to sign every new history entry.
+    Keygen,
+    /// Print the public key auditors need to verify exported logs.
+    Pubkey,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// List the backups kept of the configuration file, newest first, with the hosts each change added (+) and removed (-).
+    History,
+    /// Restore the configuration file as it was before its last change.
+    Undo,
+    /// Validate the configuration file, listing errors and warnings by line, exiting non-zero on errors.
+    Check,
+    /// Run the dynamic inventory sources again instead of using their cached output, listing the hosts each returned.
+    Refresh,
+}
+
+#[derive(Subcommand)]
+pub enum ContextCommand {
+    /// List the contexts, marking the one in use with `*`.
+    Ls,
+    /// Add a context using a configuration file, which is created on the first `host add`.
+    Add {
+        /// Name of the context.
+        name: String,
+        /// Its configuration file.
+        config: PathBuf,
+        /// Ask to type the context name before reboots, shutdowns and denylisted commands.
+        #[arg(long)]
+        protected: bool,
+    },
+    /// Ask to type the context name before reboots, shutdowns and denylisted commands.
+    Protect {
+        /// Name of the context.
+        #[arg(add = ArgValueCompleter::new(complete_contexts))]
+        name: String,
+    },
+    /// Stop asking for confirmation in a context.
+    Unprotect {
+        /// Name of the context.
+        #[arg(add = ArgValueCompleter::new(complete_contexts))]
+        name: String,
+    },
+    /// Make a context the current one.
+    Use {
+        /// Name of the context.
+        #[arg(add = ArgValueCompleter::new(complete_contexts))]
+        name: String,
+    },
+    /// Go back to the default configuration file.
+    Unset,
+    /// Remove a context, keeping its configuration file.
+    Rm {
+        /// Name of the context.
+        #[arg(add = ArgValueCompleter::new(complete_contexts))]
+        name: String,
+    },
+    /// Print the name of the context in use, e.g. for a shell prompt.
+    Current,
+}
+
+/// Parses an octal file mode.
+fn parse_mode(mode: &str) -> Result<usize, String> {
+    usize::from_str_radix(mode, 8).map_err(|_| format!("invalid mode '{}', expected octal such as 644", mode))
+}
+
+/// Completes the last entry of a comma separated list from `values`.
+fn complete_list(current: &OsStr, values: Vec<String>) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    let (done, last) = match current.rfind(',') {
+        Some(comma) => (&current[..=comma], &current[comma + 1..]),
+        None => ("", &current[..]),
+    };
+    values.into_iter()
+        .filter(|value| value.starts_with(last))
+        .map(|value| CompletionCandidate::new(format!("{}{}", done, value)))
+        .collect()
+}
+
+/// Hosts of the inventory, none if it can't be read, as completions must not print errors. Only
+/// those of the configuration file, dynamic sources aren't run for completions.
+fn inventory_hosts() -> Vec<host::Host> {
+    Inventory::load().map(|inventory| inventory.hosts().to_vec()).unwrap_or_default()
+}
+
+/// Completes host aliases from the inventory.
+fn complete_aliases(current: &OsStr) -> Vec<CompletionCandidate> {
+    complete_list(current, inventory_hosts().into_iter().map(|host| host.alias).collect())
+}
+
+/// Completes the names of contexts.
+fn complete_contexts(current: &OsStr) -> Vec<CompletionCandidate> {
+    complete_list(current, context::load().contexts.into_keys().collect())
+}
+
+/// Completes the tags used in the inventory.
+fn complete_tags(current: &OsStr) -> Vec<CompletionCandidate> {
+    let mut tags: Vec<String> = inventory_hosts().into_iter().flat_map(|host| host.tags).collect();
+    tags.sort();
+    tags.dedup();
+    complete_list(current, tags)
+}
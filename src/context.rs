@@ -0,0 +1,277 @@
+//! Provides named contexts, each with its own inventory, and resolves the configuration file rman uses.
+//!
+//! The configuration file is, in order of precedence, the one given with `--config`, `$RMAN_CONFIG`,
+//! the inventory of the context given with `--context` (or `$RMAN_CONTEXT`), that of the context
+//! selected with `rman context use`, and finally `$XDG_CONFIG_HOME/rman/rman.toml`, which defaults to
+//! `~/.config/rman/rman.toml`. Contexts are kept in `contexts.toml` next to the default file:
+//!
+//! ```toml
+//! current = "prod"
+//!
+//! [contexts.prod]
+//! config = "/home/me/inventories/prod.toml"
+//! protected = true
+//! ```
+
+use crate::args;
+use crate::cli::ContextCommand;
+use crate::store;
+use crate::style;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The contexts file.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Contexts {
+    pub current: Option<String>,                    // Context selected with `rman context use`.
+    #[serde(default)]
+    pub contexts: BTreeMap<String, Context>,        // Contexts by name.
+}
+
+/// A named inventory.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Context {
+    pub config: PathBuf,        // The configuration file of the context.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub protected: bool,        // Whether destructive operations have to be confirmed, see `guard`.
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Directory holding rman's configuration, `$XDG_CONFIG_HOME/rman` or `~/.config/rman`.
+pub fn config_dir() -> PathBuf {
+    let mut path = match std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        // The XDG spec asks to ignore relative paths.
+        Some(buf) if buf.is_absolute() => buf,
+        _ => match dirs::home_dir() {
+            Some(buf) => buf.join(".config"),
+            _ => panic!("Error getting home directory"),
+        },
+    };
+    path.push("rman");
+    path
+}
+
+/// Path of the contexts file.
+fn contexts_path() -> PathBuf {
+    config_dir().join("contexts.toml")
+}
+
+/// Path of the configuration file in use.
+pub fn config_path() -> PathBuf {
+    if let Some(path) = explicit_config() {
+        return path;
+    }
+    match active().and_then(|name| load().contexts.remove(&name)) {
+        Some(context) => context.config,
+        None => config_dir().join("rman.toml"),
+    }
+}
+
+/// The configuration file given with `--config` or `$RMAN_CONFIG`, which override any context.
+fn explicit_config() -> Option<PathBuf> {
+    args::config().or_else(|| std::env::var_os("RMAN_CONFIG").filter(|path| !path.is_empty()).map(PathBuf::from))
+}
+
+/// Name of the context in use, if any: the one given with `--context` or `$RMAN_CONTEXT`, else
+/// the current one. None if `--config` or `$RMAN_CONFIG` point at a file directly.
+pub fn active() -> Option<String> {
+    if explicit_config().is_some() {
+        return None;
+    }
+    args::context()
+        .or_else(|| std::env::var("RMAN_CONTEXT").ok().filter(|name| !name.is_empty()))
+        .or_else(|| load().current)
+}
+
+/// Loads the contexts file, without contexts if there is none or it can't be read.
+pub fn load() -> Contexts {
+    let contents = match fs::read_to_string(contexts_path()) {
+        Ok(contents) => contents,
+        Err(_) => return Contexts::default(),
+    };
+    match toml::from_str(&contents) {
+        Ok(contexts) => contexts,
+        Err(err) => {
+            println!("Ignoring {}: {}", contexts_path().display(), err);
+            Contexts::default()
+        }
+    }
+}
+
+/// Writes the contexts file and prints `done`, only printing what would change in check mode.
+fn save(contexts: &Contexts, change: &str, done: &str) {
+    if args::check_mode() {
+        println!("Would {}", change);
+        return;
+    }
+    let path = contexts_path();
+    let written = toml::to_string(contexts).map_err(|err| err.to_string()).and_then(|contents| {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        }
+        store::write(&path, &contents).map_err(|err| err.to_string())
+    });
+    match written {
+        Ok(()) => println!("{}", done),
+        Err(err) => println!("Unable to save {}: {}", path.display(), err),
+    }
+}
+
+/// Checks a context named with `--context` or `$RMAN_CONTEXT` exists, so a typo doesn't silently
+/// fall back to another inventory.
+pub fn check_selected() -> Result<(), String> {
+    match active() {
+        Some(name) if !load().contexts.contains_key(&name) => {
+            Err(format!("Unknown context '{}', see `rman context ls`", name))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether the context with the given name is protected.
+pub fn is_protected(name: &str) -> bool {
+    load().contexts.get(name).map(|context| context.protected).unwrap_or(false)
+}
+
+/// Name of the protected context whose inventory is in use, if any. Contexts are matched by their
+/// configuration file as well, so selecting a protected inventory with `--config` or
+/// `$RMAN_CONFIG` doesn't bypass its protection.
+pub fn protected_in_use() -> Option<String> {
+    if let Some(name) = active().filter(|name| is_protected(name)) {
+        return Some(name);
+    }
+    let in_use = file_key(&config_path());
+    load().contexts.into_iter()
+        .find(|(_, context)| context.protected && file_key(&context.config) == in_use)
+        .map(|(name, _)| name)
+}
+
+/// Identifies a file however its path is written, following symbolic links if it exists.
+fn file_key(path: &Path) -> String {
+    match fs::canonicalize(path) {
+        Ok(path) => store::path_key(&path),
+        Err(_) => store::path_key(path),
+    }
+}
+
+/// Describes the inventory in use, e.g. `context prod (/home/me/prod.toml)`.
+pub fn describe() -> String {
+    match active() {
+        Some(name) if is_protected(&name) => format!("context {} ({}, protected)", style::bold(&name), config_path().display()),
+        Some(name) => format!("context {} ({})", style::bold(&name), config_path().display()),
+        None => format!("{}", config_path().display()),
+    }
+}
+
+/// This function handles all `$rman context` commands.
+pub fn base(command: ContextCommand) {
+    match command {
+        ContextCommand::Ls => list(),                                       // context "ls"
+        ContextCommand::Add { name, config, protected } => add(&name, &config, protected),  // context "add"
+        ContextCommand::Protect { name } => protect(&name, true),           // context "protect"
+        ContextCommand::Unprotect { name } => protect(&name, false),        // context "unprotect"
+        ContextCommand::Use { name } => select(&name),                      // context "use"
+        ContextCommand::Unset => unset(),                                   // context "unset"
+        ContextCommand::Rm { name } => remove(&name),                       // context "rm"
+        ContextCommand::Current => {                                        // context "current"
+            if let Some(name) = active() {
+                println!("{}", name);
+            }
+        }
+    }
+}
+
+/// Lists the contexts, marking the one in use.
+fn list() {
+    let contexts = load();
+    if contexts.contexts.is_empty() {
+        println!("No contexts, add one with `rman context add <name> <config>`.");
+        return;
+    }
+    let active = active();
+    let width = contexts.contexts.keys().map(|name| name.len()).max().unwrap_or(0);
+    for (name, context) in contexts.contexts.iter() {
+        let marker = if active.as_deref() == Some(name.as_str()) { "*" } else { " " };
+        let protected = if context.protected { "  (protected)" } else { "" };
+        println!("{} {:<width$}  {}{}", marker, name, context.config.display(), protected, width = width);
+    }
+}
+
+/// Adds a context using the given configuration file, which doesn't need to exist yet.
+fn add(name: &str, config: &Path, protected: bool) {
+    let mut contexts = load();
+    if contexts.contexts.contains_key(name) {
+        println!("Context {} already exists!", name);
+        return;
+    }
+    // Relative paths would change meaning with the working directory.
+    let config = match std::path::absolute(config) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Unable to resolve {}: {}", config.display(), err);
+            return;
+        }
+    };
+    let change = format!("add context {} using {}", name, config.display());
+    contexts.contexts.insert(name.to_string(), Context { config, protected });
+    save(&contexts, &change, &format!("Added context {}, switch to it with `rman context use {}`", name, name));
+}
+
+/// Makes a context the current one.
+fn select(name: &str) {
+    let mut contexts = load();
+    if !contexts.contexts.contains_key(name) {
+        println!("Unknown context '{}', see `rman context ls`", name);
+        return;
+    }
+    contexts.current = Some(name.to_string());
+    save(&contexts, &format!("switch to context {}", name), &format!("Switched to context {}", style::green(name)));
+}
+
+/// Turns the protection of a context on or off.
+fn protect(name: &str, protected: bool) {
+    let mut contexts = load();
+    match contexts.contexts.get_mut(name) {
+        Some(context) => context.protected = protected,
+        None => {
+            println!("Unknown context '{}', see `rman context ls`", name);
+            return;
+        }
+    }
+    let (change, done) = if protected {
+        (format!("protect context {}", name), format!("Context {} is protected", name))
+    } else {
+        (format!("unprotect context {}", name), format!("Context {} is no longer protected", name))
+    };
+    save(&contexts, &change, &done);
+}
+
+/// Goes back to the default configuration file.
+fn unset() {
+    let mut contexts = load();
+    if contexts.current.take().is_none() {
+        println!("No context is in use.");
+        return;
+    }
+    save(&contexts, "switch back to the default configuration file", "Switched back to the default configuration file");
+}
+
+/// Removes a context, leaving its configuration file in place.
+fn remove(name: &str) {
+    let mut contexts = load();
+    if contexts.contexts.remove(name).is_none() {
+        println!("Unknown context '{}', see `rman context ls`", name);
+        return;
+    }
+    if contexts.current.as_deref() == Some(name) {
+        contexts.current = None;
+    }
+    save(&contexts, &format!("remove context {}", name), &format!("Removed context {}", name));
+}
@@ -0,0 +1,27 @@
+//! Provides unified diffs for showing what a change would do before it is made.
+
+use crate::style;
+use similar::TextDiff;
+
+/// Renders a unified diff between two texts, coloring added and removed lines.
+/// Returns an empty string if they are equal.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    let diff = TextDiff::from_lines(old, new);
+    let text = diff.unified_diff().context_radius(3).header(old_name, new_name).to_string();
+    let mut lines = vec!();
+    for line in text.lines() {
+        lines.push(if line.starts_with("+++") || line.starts_with("---") {
+            style::bold(line)
+        } else if line.starts_with('+') {
+            style::green(line)
+        } else if line.starts_with('-') {
+            style::red(line)
+        } else {
+            line.to_string()
+        });
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,183 @@
+//! Provides `$rman drift`, which compares remote files or directory trees across hosts.
+//!
+//! Every file below the given paths is checksummed on each selected host. A file drifted on a
+//! host when its checksum differs from the golden host's (`--golden`) or, without one, from the
+//! checksum most hosts agree on. Differing text files are shown as unified diffs against the
+//! reference copy.
+
+use crate::cli::DriftArgs;
+use crate::diff;
+use crate::filter;
+use crate::host::{self, Host};
+use crate::ssh_con::{run_remote_command, shell_quote};
+use crate::style;
+use std::collections::BTreeMap;
+
+/// Files larger than this are compared by checksum only.
+const MAX_DIFF_BYTES: usize = 256 * 1024;
+
+/// Checksums of every file found on one host, by path. `None` marks a requested path that is missing.
+type Checksums = BTreeMap<String, Option<String>>;
+
+/// This function handles `$rman drift`.
+/// Exits with status 1 if any file drifted or a host couldn't be checked.
+pub fn base(args: DriftArgs) {
+    let DriftArgs { paths, golden, targets: spec, where_expr: expr, no_diff } = args;
+
+    let hosts = match spec {
+        Some(ref spec) => host::resolve_targets(spec),
+        None => host::get_hosts(),
+    };
+    let mut hosts = match hosts {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(ref expr) = expr {
+        hosts = match filter::select(hosts, expr, false) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(ref golden) = golden {
+        if !hosts.iter().any(|host| &host.alias == golden) {
+            println!("Golden host {} is not among the selected hosts", golden);
+            std::process::exit(1);
+        }
+    }
+
+    // Checksum everything on every host.
+    let mut checksums: std::vec::Vec<(&Host, Checksums)> = vec!();
+    let mut unreachable = 0;
+    for host in hosts.iter() {
+        match checksum(host, &paths) {
+            Ok(sums) => checksums.push((host, sums)),
+            Err(err) => {
+                unreachable += 1;
+                println!("{}: {} ({})", style::red("unreachable"), host.alias, err.trim());
+            }
+        }
+    }
+    if checksums.is_empty() {
+        println!("No host could be checked");
+        std::process::exit(1);
+    }
+    if let Some(ref golden) = golden {
+        if !checksums.iter().any(|(host, _)| &host.alias == golden) {
+            println!("Golden host {} could not be checked", golden);
+            std::process::exit(1);
+        }
+    }
+    let files: std::collections::BTreeSet<&String> = checksums.iter().flat_map(|(_, sums)| sums.keys()).collect();
+
+    let mut drifted = 0;
+    for file in files {
+        let values: std::vec::Vec<(&Host, Option<&String>)> = checksums.iter()
+            .map(|(host, sums)| (*host, sums.get(file).and_then(Option::as_ref)))
+            .collect();
+        let (reference_host, reference, label) = match golden {
+            Some(ref golden) => {
+                let (host, value) = values.iter().find(|(host, _)| &host.alias == golden).copied().expect("the golden host was checked");
+                (host, value, format!("golden host {}", golden))
+            }
+            None => {
+                let (host, value, count) = majority(&values);
+                (host, value, format!("{} of {} hosts", count, values.len()))
+            }
+        };
+        let differing: std::vec::Vec<&(&Host, Option<&String>)> = values.iter().filter(|(_, value)| *value != reference).collect();
+        if differing.is_empty() {
+            continue;
+        }
+        drifted += 1;
+        println!("{} {} (reference: {})", style::bold("DRIFT"), file, label);
+        let missing: std::vec::Vec<&str> = differing.iter().filter(|(_, value)| value.is_none()).map(|(host, _)| host.alias.as_str()).collect();
+        let changed: std::vec::Vec<&str> = differing.iter().filter(|(_, value)| value.is_some()).map(|(host, _)| host.alias.as_str()).collect();
+        if reference.is_none() {
+            println!("    {} {}", style::yellow("present on:"), changed.join(", "));
+        } else {
+            if !changed.is_empty() {
+                println!("    {} {}", style::red("differs on:"), changed.join(", "));
+            }
+            if !missing.is_empty() {
+                println!("    {} {}", style::red("missing on:"), missing.join(", "));
+            }
+        }
+        if no_diff || reference.is_none() {
+            continue;
+        }
+        // Show how each differing copy deviates from the reference copy.
+        let reference_contents = match fetch(reference_host, file) {
+            Some(contents) => contents,
+            None => continue,
+        };
+        for (host, _) in differing.iter().filter(|(_, value)| value.is_some()) {
+            match fetch(host, file) {
+                Some(contents) => println!("{}", diff::unified(&reference_contents, &contents, &format!("{}:{}", reference_host.alias, file), &format!("{}:{}", host.alias, file))),
+                None => println!("    {}: binary or large file, checksums differ", host.alias),
+            }
+        }
+    }
+
+    if drifted == 0 {
+        println!("{} no drift across {} hosts", style::green("OK"), checksums.len());
+    } else {
+        println!("{} files drifted across {} hosts", drifted, checksums.len());
+    }
+    if unreachable > 0 {
+        println!("{} of {} hosts could not be checked", unreachable, hosts.len());
+    }
+    if drifted > 0 || unreachable > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Checksums every file below the given paths on a host.
+fn checksum(host: &Host, paths: &[String]) -> Result<Checksums, String> {
+    let quoted: std::vec::Vec<String> = paths.iter().map(|path| shell_quote(path)).collect();
+    let cmd = format!("for p in {}; do if [ -e \"$p\" ]; then find \"$p\" -type f -exec sha256sum {{}} +; else echo \"missing $p\"; fi; done", quoted.join(" "));
+    let result = run_remote_command(host, &cmd);
+    if !result.connected {
+        return Err(result.output);
+    }
+    let mut sums = Checksums::new();
+    for line in result.output.lines() {
+        if let Some(path) = line.strip_prefix("missing ") {
+            sums.insert(path.to_string(), None);
+        } else if let Some((sum, path)) = line.split_once("  ") {
+            sums.insert(path.to_string(), Some(sum.to_string()));
+        }
+    }
+    Ok(sums)
+}
+
+/// The value most hosts agree on, the first host having it and how many hosts have it.
+/// Ties go to the value seen first, following the order of the inventory.
+fn majority<'a>(values: &[(&'a Host, Option<&'a String>)]) -> (&'a Host, Option<&'a String>, usize) {
+    let mut best = (values[0].0, values[0].1, 0);
+    for (host, value) in values.iter() {
+        let count = values.iter().filter(|(_, other)| other == value).count();
+        if count > best.2 {
+            best = (host, *value, count);
+        }
+    }
+    best
+}
+
+/// Reads a remote text file for diffing, or `None` if it is binary, too large or unreadable.
+fn fetch(host: &Host, path: &str) -> Option<String> {
+    let result = run_remote_command(host, &format!("head -c {} -- {}", MAX_DIFF_BYTES + 1, shell_quote(path)));
+    if !result.connected || result.exit_status.unwrap_or(0) != 0 {
+        return None;
+    }
+    let binary = result.output.contains('\0') || result.output.contains('\u{FFFD}');
+    if binary || result.output.len() > MAX_DIFF_BYTES {
+        return None;
+    }
+    Some(result.output)
+}
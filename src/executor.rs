@@ -0,0 +1,328 @@
+//! Provides `Executor`, which runs commands on and uploads files to a set of hosts.
+//!
+//! Hosts are worked on concurrently on a tokio runtime. ssh sessions block, so each one occupies
+//! a thread of tokio's blocking pool while holding a permit of a semaphore: at most
+//! `Limits::concurrency` sessions, and so threads, are busy at a time however many hosts there
+//! are. libssh can't be interrupted, so a host that timed out keeps its thread and permit until
+//! its session really ends, and tokio's blocking pool stops at 512 threads, so a higher
+//! concurrency only queues more sessions. Cancelling only stops hosts still waiting for a permit,
+//! sessions already started are waited for. Every host gets a `HostOutcome`, unreachable, timed
+//! out and cancelled hosts included.
+//!
+//! The async API (`exec`, `exec_each`, `each`) yields outcomes as hosts finish. The blocking API
+//! (`run`, `run_each`, `run_template`, `push`) drives the same engine on its own runtime and
+//! returns the outcomes in host order. Signals are left to the caller, cancel a run through
+//! `cancellation` or give it a token with `with_cancellation`.
+
+use crate::host::Host;
+use crate::render::Renderer;
+use crate::ssh_con::{self, CmdResult};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+/// Hosts worked on at the same time unless configured otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 64;
+
+/// What happened on one host.
+pub struct HostOutcome<T> {
+    pub alias: String,      // Alias of the host.
+    pub result: T,          // The command result or transfer outcome.
+}
+
+/// How many hosts are worked on at once and how long they may take.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub concurrency: usize,                 // Hosts worked on at the same time.
+    pub host_timeout: Option<Duration>,     // Time a single host may take, waiting for a permit excluded. Overrides `Host::timeout`.
+    pub timeout: Option<Duration>,          // Time the whole run may take.
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { concurrency: DEFAULT_CONCURRENCY, host_timeout: None, timeout: None }
+    }
+}
+
+/// Why the work on a host didn't finish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupted {
+    Cancelled,              // The run was cancelled.
+    HostTimeout(Duration),  // The host took longer than `Limits::host_timeout`.
+    Timeout(Duration),      // The run took longer than `Limits::timeout`.
+    Panicked,               // The work on the host panicked.
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Interrupted::Cancelled => write!(f, "cancelled"),
+            Interrupted::HostTimeout(timeout) => write!(f, "timed out after {}s", timeout.as_secs_f64()),
+            Interrupted::Timeout(timeout) => write!(f, "run timed out after {}s", timeout.as_secs_f64()),
+            Interrupted::Panicked => write!(f, "rman crashed while working on the host"),
+        }
+    }
+}
+
+/// Runs commands and transfers over a set of hosts.
+/// # Examples
+/// ```no_run
+/// use rman::{Executor, Inventory};
+///
+/// let inventory = Inventory::load()?;
+/// let executor = Executor::new(inventory.select("web-*")?);
+/// for outcome in executor.run("uptime") {
+///     match outcome.result.exit_status {
+///         Some(0) => println!("{}: {}", outcome.alias, outcome.result.output.trim()),
+///         _ => println!("{} failed: {}", outcome.alias, outcome.result.output.trim()),
+///     }
+/// }
+/// let uploads = executor.push(b"nameserver 10.0.0.2\n", "/etc/resolv.conf", 0o644);
+/// assert!(uploads.iter().all(|outcome| outcome.result.is_ok()));
+/// # Ok::<(), String>(())
+/// ```
+///
+/// Within an async program, outcomes can be streamed as hosts finish:
+/// ```no_run
+/// use rman::executor::{Executor, Limits};
+/// use rman::Inventory;
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+///
+/// # async fn example() -> Result<(), String> {
+/// let hosts = Inventory::load()?.select("*")?;
+/// let limits = Limits { concurrency: 200, host_timeout: Some(Duration::from_secs(30)), timeout: None };
+/// let executor = Executor::new(hosts).with_limits(limits);
+/// let cancel = executor.cancellation();
+/// let mut outcomes = executor.exec("systemctl is-active nginx").await;
+/// while let Some(outcome) = outcomes.next().await {
+///     if outcome.result.exit_status != Some(0) {
+///         // Stop at the first failure, hosts that haven't started report being cancelled.
+///         cancel.cancel();
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Executor {
+    hosts: std::vec::Vec<Host>,
+    limits: Limits,
+    cancel: CancellationToken,
+}
+
+impl Executor {
+    /// An executor for the given hosts with the default limits.
+    pub fn new(hosts: std::vec::Vec<Host>) -> Executor {
+        Executor { hosts, limits: Limits::default(), cancel: CancellationToken::new() }
+    }
+
+    /// Uses the given concurrency and timeouts.
+    pub fn with_limits(mut self, limits: Limits) -> Executor {
+        self.limits = limits;
+        self
+    }
+
+    /// Cancels the runs of this executor through `cancel`, e.g. one shared with a Ctrl-C handler.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Executor {
+        self.cancel = cancel;
+        self
+    }
+
+    /// The hosts commands are run on.
+    pub fn hosts(&self) -> &[Host] {
+        &self.hosts
+    }
+
+    /// A token that cancels the runs of this executor. Hosts already running finish and report
+    /// their results, hosts still waiting for their turn report `Interrupted::Cancelled`.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Runs `cmd` on every host, yielding each host's result as soon as it is done.
+    pub async fn exec(&self, cmd: &str) -> impl Stream<Item = HostOutcome<CmdResult>> + Unpin {
+        self.exec_each(vec!(cmd.to_string(); self.hosts.len())).await
+    }
+
+    /// Runs `commands[i]` on the i-th host, yielding each host's result as soon as it is done.
+    pub async fn exec_each(&self, commands: std::vec::Vec<String>) -> impl Stream<Item = HostOutcome<CmdResult>> + Unpin {
+        let commands = Arc::new(commands);
+        self.each(move |index, host| ssh_con::run_remote_command(host, &commands[index])).await
+            .map(|outcome| HostOutcome { alias: outcome.alias, result: outcome.result.unwrap_or_else(interrupted_result) })
+    }
+
+    /// Calls `work` with the index and host of every host on the blocking pool, yielding its
+    /// result as soon as a host is done. Each call occupies a thread until it returns, even once
+    /// its host timed out. Once cancelled, hosts that haven't started are skipped.
+    pub async fn each<T, F>(&self, work: F) -> impl Stream<Item = HostOutcome<Result<T, Interrupted>>> + Unpin
+    where
+        T: Send + 'static,
+        F: Fn(usize, &Host) -> T + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(self.hosts.len().max(1));
+        let permits = Arc::new(Semaphore::new(self.limits.concurrency.max(1)));
+        let work = Arc::new(work);
+        let deadline = self.limits.timeout.map(|timeout| (tokio::time::Instant::now() + timeout, timeout));
+        for (index, host) in self.hosts.iter().cloned().enumerate() {
+            let (sender, permits, work, cancel) = (sender.clone(), permits.clone(), work.clone(), self.cancel.clone());
+            let host_timeout = self.limits.host_timeout.or_else(|| host.timeout.map(Duration::from_secs));
+            tokio::spawn(async move {
+                let alias = host.alias.clone();
+                let run = async {
+                    // Cancelling only stops hosts waiting for their turn, a started session can't
+                    // be taken back and is waited for.
+                    let permit = tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => return Err(Interrupted::Cancelled),
+                        permit = permits.acquire_owned() => permit.expect("the semaphore is never closed"),
+                    };
+                    // The permit moves along with the session, so a host that timed out keeps its
+                    // slot until its session really ends.
+                    let session = tokio::task::spawn_blocking(move || {
+                        let _permit = permit;
+                        work(index, &host)
+                    });
+                    match host_timeout {
+                        Some(timeout) => match tokio::time::timeout(timeout, session).await {
+                            Ok(done) => done.map_err(|_| Interrupted::Panicked),
+                            Err(_) => Err(Interrupted::HostTimeout(timeout)),
+                        },
+                        None => session.await.map_err(|_| Interrupted::Panicked),
+                    }
+                };
+                let result = tokio::select! {
+                    result = run => result,
+                    timeout = until(deadline) => Err(Interrupted::Timeout(timeout)),
+                };
+                let _ = sender.send(HostOutcome { alias, result }).await;
+            });
+        }
+        ReceiverStream::new(receiver)
+    }
+
+    /// Runs the same command on every host.
+    pub fn run(&self, cmd: &str) -> std::vec::Vec<HostOutcome<CmdResult>> {
+        let commands = vec!(cmd.to_string(); self.hosts.len());
+        self.run_each(&commands, |_| ())
+    }
+
+    /// Renders `{{placeholders}}` in the command for every host and runs the results.
+    /// Nothing is run if the command can't be rendered for one of the hosts.
+    pub fn run_template(&self, template: &str) -> Result<std::vec::Vec<HostOutcome<CmdResult>>, String> {
+        let commands = Renderer::load().commands(&self.hosts, template)?;
+        Ok(self.run_each(&commands, |_| ()))
+    }
+
+    /// Runs `commands[i]` on the i-th host, calling `on_result` as soon as each host is done.
+    pub fn run_each<F: FnMut(&HostOutcome<CmdResult>)>(&self, commands: &[String], mut on_result: F) -> std::vec::Vec<HostOutcome<CmdResult>> {
+        let outcomes = block_on(async {
+            let mut outcomes = vec!();
+            let mut stream = self.exec_each(commands.to_vec()).await;
+            while let Some(outcome) = stream.next().await {
+                on_result(&outcome);
+                outcomes.push(outcome);
+            }
+            outcomes
+        });
+        self.in_host_order(outcomes)
+    }
+
+    /// Uploads `contents` to `dest` on every host with the given file mode.
+    pub fn push(&self, contents: &[u8], dest: &str, mode: usize) -> std::vec::Vec<HostOutcome<Result<(), String>>> {
+        let (contents, dest) = (contents.to_vec(), dest.to_string());
+        let outcomes = block_on(async {
+            self.each(move |_, host| ssh_con::push_file(host, &contents, &dest, mode)).await
+                .map(|outcome| HostOutcome { alias: outcome.alias, result: outcome.result.unwrap_or_else(|err| Err(err.to_string())) })
+                .collect::<std::vec::Vec<_>>().await
+        });
+        self.in_host_order(outcomes)
+    }
+
+    /// Sorts outcomes back into the order of the hosts.
+    fn in_host_order<T>(&self, mut outcomes: std::vec::Vec<HostOutcome<T>>) -> std::vec::Vec<HostOutcome<T>> {
+        outcomes.sort_by_key(|outcome| self.hosts.iter().position(|host| host.alias == outcome.alias));
+        outcomes
+    }
+}
+
+/// Runs a future to completion on a new runtime, for callers that aren't async themselves.
+/// Must not be called from within a runtime. Sessions still blocked in libssh, those of hosts that
+/// timed out, are left to end in the background rather than waited for.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let runtime = runtime();
+    let output = runtime.block_on(future);
+    runtime.shutdown_background();
+    output
+}
+
+/// A multi-threaded runtime for one blocking call.
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread().enable_all().build().expect("unable to start the async runtime")
+}
+
+/// Resolves with the timeout once the deadline passed, never if there is none.
+async fn until(deadline: Option<(tokio::time::Instant, Duration)>) -> Duration {
+    match deadline {
+        Some((at, timeout)) => {
+            tokio::time::sleep_until(at).await;
+            timeout
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The result recorded for a host whose command didn't finish. It may have run all the same, so
+/// the host isn't taken for unreachable.
+fn interrupted_result(interrupted: Interrupted) -> CmdResult {
+    let duration = match interrupted {
+        Interrupted::Cancelled | Interrupted::Panicked => Duration::default(),
+        Interrupted::HostTimeout(timeout) | Interrupted::Timeout(timeout) => timeout,
+    };
+    CmdResult { output: interrupted.to_string(), exit_status: None, duration, connected: false, interrupted: true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc as std_mpsc;
+
+    fn hosts(aliases: &[&str]) -> std::vec::Vec<Host> {
+        aliases.iter().map(|alias| Host::new(alias, "10.0.0.1", "ops", "/tmp/key")).collect()
+    }
+
+    #[test]
+    fn cancelling_waits_for_running_hosts_and_skips_waiting_ones() {
+        let limits = Limits { concurrency: 1, host_timeout: None, timeout: None };
+        let executor = Executor::new(hosts(&["web01", "web02"])).with_limits(limits);
+        let cancel = executor.cancellation();
+        let (started, wait) = std_mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        let mut outcomes = block_on(async {
+            let stream = executor.each(move |index, _| {
+                started.lock().unwrap().send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(100));
+                index
+            }).await;
+            // The first host holds the only permit once it started.
+            wait.recv().unwrap();
+            cancel.cancel();
+            stream.collect::<std::vec::Vec<_>>().await
+        });
+        outcomes.sort_by(|a, b| a.alias.cmp(&b.alias));
+        assert_eq!(outcomes[0].result, Ok(0));
+        assert_eq!(outcomes[1].result, Err(Interrupted::Cancelled));
+    }
+
+    #[test]
+    fn interrupted_hosts_are_not_taken_for_unreachable() {
+        let result = interrupted_result(Interrupted::HostTimeout(Duration::from_secs(5)));
+        assert!(result.interrupted);
+        assert_eq!(result.duration, Duration::from_secs(5));
+        assert_eq!(result.output, "timed out after 5s");
+    }
+}
@@ -0,0 +1,117 @@
+//! Provides gathering and caching of per-host facts such as the OS, kernel and free disk space.
+//!
+//! Facts are collected by running a small shell snippet on the remote machine that prints one
+//! `key=value` pair per line. The result is cached under `~/.cache/rman/facts/<inventory>/<alias>`
+//! in the same format so later host selections don't need to contact every host again.
+
+use crate::args;
+use crate::host::{self, Host};
+use crate::ssh_con::execute_remote_command;
+use crate::store;
+extern crate dirs;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Facts about a single host, keyed by fact name.
+pub type Facts = HashMap<String, String>;
+
+/// Names of the facts `GATHER_CMD` collects.
+pub const NAMES: [&str; 8] = ["os", "os_version", "kernel", "arch", "hostname", "cpus", "mem_total_mb", "disk_free_pct"];
+
+/// Shell snippet run on the remote machine to collect facts, one `key=value` pair per line.
+const GATHER_CMD: &str = ". /etc/os-release 2>/dev/null; \
+echo \"os=$ID\"; \
+echo \"os_version=$VERSION_ID\"; \
+echo \"kernel=$(uname -r)\"; \
+echo \"arch=$(uname -m)\"; \
+echo \"hostname=$(hostname)\"; \
+echo \"cpus=$(nproc 2>/dev/null || getconf _NPROCESSORS_ONLN)\"; \
+awk '/^MemTotal:/ {print \"mem_total_mb=\" int($2 / 1024)}' /proc/meminfo; \
+df -P / | awk 'NR == 2 {sub(\"%\", \"\", $5); print \"disk_free_pct=\" 100 - $5}'";
+
+/// Returns the facts of `host`, gathering them from the remote machine if `fresh` is set or nothing is cached yet.
+pub fn get(host: &Host, fresh: bool) -> Facts {
+    if !fresh {
+        if let Some(facts) = cached(host) {
+            return facts;
+        }
+    }
+    gather(host)
+}
+
+/// Gathers facts from the remote machine and refreshes the cache, unless in check mode where
+/// nothing local may change either.
+pub fn gather(host: &Host) -> Facts {
+    let output = execute_remote_command(host, GATHER_CMD);
+    let gathered = parse(&output);
+    // Only cache the result if the host could actually be reached.
+    if !gathered.is_empty() && !args::check_mode() {
+        if let Err(err) = store(&host.alias, &output) {
+            println!("Unable to cache facts for {}: {}", host.alias, err);
+        }
+    }
+    with_host_fields(host, gathered)
+}
+
+/// Reads the cached facts of `host`, if any.
+pub fn cached(host: &Host) -> Option<Facts> {
+    let contents = fs::read_to_string(cache_path(&host.alias)).ok()?;
+    Some(with_host_fields(host, parse(&contents)))
+}
+
+/// Parses `key=value` lines into `Facts`, ignoring anything else.
+fn parse(output: &str) -> Facts {
+    let mut facts = Facts::new();
+    for line in output.lines() {
+        if let Some(eq) = line.find('=') {
+            let key = line[..eq].trim();
+            if !key.is_empty() && !key.contains(' ') {
+                facts.insert(key.to_string(), line[eq + 1..].trim().to_string());
+            }
+        }
+    }
+    facts
+}
+
+/// Adds the inventory fields of `host` so they can be used in expressions next to the gathered facts.
+pub fn with_host_fields(host: &Host, mut facts: Facts) -> Facts {
+    facts.insert(String::from("alias"), host.alias.clone());
+    facts.insert(String::from("ip"), host.ip.clone());
+    facts.insert(String::from("ssh_user"), host.ssh_user.clone());
+    facts.insert(String::from("description"), host.description.clone());
+    facts.insert(String::from("tags"), host.tags.join(","));
+    facts
+}
+
+/// Writes raw gathered output into the cache file for `alias`.
+fn store(alias: &str, output: &str) -> std::io::Result<()> {
+    let path = cache_path(alias);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, output)
+}
+
+/// Path of the facts cache file for `alias`. Each configuration file has its own cache, however
+/// it was selected, as the same alias may name different hosts in different inventories.
+fn cache_path(alias: &str) -> PathBuf {
+    let mut path = match dirs::cache_dir() {
+        Some(buf) => buf,
+        _ => panic!("Error getting cache directory"),
+    };
+    path.push("rman");
+    path.push("facts");
+    path.push(store::path_key(&host::config_path()));
+    path.push(alias);
+    path
+}
+
+/// Prints facts as sorted `key = value` lines.
+pub fn print(facts: &Facts) {
+    let mut keys: std::vec::Vec<&String> = facts.keys().collect();
+    keys.sort();
+    for key in keys {
+        println!("{} = {}", key, facts[key]);
+    }
+}
@@ -0,0 +1,333 @@
+//! Provides the `--where` expression language used to select hosts by their facts.
+//!
+//! An expression is made of comparisons joined by `&&`, `||` and `!` (or `and`, `or`, `not`),
+//! optionally grouped with parentheses:
+//!
+//! `os=ubuntu && kernel<5.15`, `disk_free_pct<10 || !(arch=x86_64)`, `hostname~web-*`
+//!
+//! Supported operators are `=`/`==`, `!=`, `<`, `<=`, `>`, `>=`, `~` (glob match) and `!~`.
+//! Ordering compares whole numbers numerically, values with dots such as `1.10` or
+//! `5.15.0-91-generic` component by component like versions (so `1.10 > 1.9`), and anything else
+//! as plain strings. A bare fact name is true when
+//! the fact is set and not empty, `0` or `false`. Comparisons against facts a host doesn't
+//! have are always false.
+
+use crate::facts::{self, Facts};
+use crate::host::Host;
+use glob::Pattern;
+use std::cmp::Ordering;
+
+/// A parsed `--where` expression.
+#[derive(Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(String, Op, String),    // fact, operator, value
+    Has(String),                // bare fact name
+}
+
+/// Comparison operators.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Glob,
+    NotGlob,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// Selects the hosts matching `expr`, using cached facts unless `fresh` is set.
+/// # Examples
+/// ```no_run
+/// use rman::filter::select;
+/// use rman::host::get_hosts;
+///
+/// let ubuntu_hosts = select(get_hosts()?, "os=ubuntu", false)?;
+/// # Ok::<(), String>(())
+/// ```
+pub fn select(hosts: std::vec::Vec<Host>, expr: &str, fresh: bool) -> Result<std::vec::Vec<Host>, String> {
+    let expr = parse(expr)?;
+    Ok(hosts.into_iter().filter(|host| expr.eval(&facts::get(host, fresh))).collect())
+}
+
+/// Parses an expression.
+pub fn parse(input: &str) -> Result<Expr, String> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.or()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?} in where expression", token)),
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression against a host's facts.
+    pub fn eval(&self, facts: &Facts) -> bool {
+        match self {
+            Expr::And(lhs, rhs) => lhs.eval(facts) && rhs.eval(facts),
+            Expr::Or(lhs, rhs) => lhs.eval(facts) || rhs.eval(facts),
+            Expr::Not(inner) => !inner.eval(facts),
+            Expr::Has(key) => match facts.get(key) {
+                Some(value) => !(value.is_empty() || value == "0" || value == "false"),
+                None => false,
+            },
+            Expr::Cmp(key, op, expected) => match facts.get(key) {
+                Some(actual) => compare(actual, *op, expected),
+                None => false,
+            },
+        }
+    }
+}
+
+/// Applies `op` to a fact value and the value from the expression.
+fn compare(actual: &str, op: Op, expected: &str) -> bool {
+    match op {
+        Op::Glob | Op::NotGlob => {
+            let matched = match Pattern::new(expected) {
+                Ok(pattern) => pattern.matches(actual),
+                Err(_) => actual == expected,
+            };
+            matched == (op == Op::Glob)
+        }
+        _ => {
+            let ordering = order(actual, expected);
+            match op {
+                Op::Eq => ordering == Ordering::Equal,
+                Op::Ne => ordering != Ordering::Equal,
+                Op::Lt => ordering == Ordering::Less,
+                Op::Le => ordering != Ordering::Greater,
+                Op::Gt => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }
+        }
+    }
+}
+
+/// Orders two values numerically, as versions, or as strings, in that order of preference.
+/// Values with a dot are versions, so `1.10` is greater than `1.9`.
+fn order(lhs: &str, rhs: &str) -> Ordering {
+    let plain_number = |s: &str| !s.contains('.');
+    if plain_number(lhs) && plain_number(rhs) {
+        if let (Ok(l), Ok(r)) = (lhs.parse::<f64>(), rhs.parse::<f64>()) {
+            return l.partial_cmp(&r).unwrap_or(Ordering::Equal);
+        }
+    }
+    match (version(lhs), version(rhs)) {
+        (Some(l), Some(r)) => {
+            // Missing trailing components count as zero, so `5.15` == `5.15.0`.
+            for i in 0..l.len().max(r.len()) {
+                let ordering = l.get(i).unwrap_or(&0).cmp(r.get(i).unwrap_or(&0));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            Ordering::Equal
+        }
+        _ => lhs.cmp(rhs),
+    }
+}
+
+/// Splits a version-like value such as `5.15.0-91-generic` into its numeric components.
+fn version(value: &str) -> Option<std::vec::Vec<u64>> {
+    if !value.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(value
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .filter_map(|part| part.parse().ok())
+        .collect())
+}
+
+/// Splits an expression into tokens.
+fn tokenize(input: &str) -> Result<std::vec::Vec<Token>, String> {
+    let chars: std::vec::Vec<char> = input.chars().collect();
+    let mut tokens = vec!();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            ' ' | '\t' | '\n' => i += 1,
+            '(' => { tokens.push(Token::Open); i += 1; }
+            ')' => { tokens.push(Token::Close); i += 1; }
+            '&' if next == Some('&') => { tokens.push(Token::And); i += 2; }
+            '|' if next == Some('|') => { tokens.push(Token::Or); i += 2; }
+            '!' if next == Some('=') => { tokens.push(Token::Op(Op::Ne)); i += 2; }
+            '!' if next == Some('~') => { tokens.push(Token::Op(Op::NotGlob)); i += 2; }
+            '!' => { tokens.push(Token::Not); i += 1; }
+            '=' if next == Some('=') => { tokens.push(Token::Op(Op::Eq)); i += 2; }
+            '=' => { tokens.push(Token::Op(Op::Eq)); i += 1; }
+            '<' if next == Some('=') => { tokens.push(Token::Op(Op::Le)); i += 2; }
+            '<' => { tokens.push(Token::Op(Op::Lt)); i += 1; }
+            '>' if next == Some('=') => { tokens.push(Token::Op(Op::Ge)); i += 2; }
+            '>' => { tokens.push(Token::Op(Op::Gt)); i += 1; }
+            '~' => { tokens.push(Token::Op(Op::Glob)); i += 1; }
+            '\'' | '"' => {
+                // Quoted value, taken verbatim up to the closing quote.
+                let end = match chars[i + 1..].iter().position(|&q| q == c) {
+                    Some(offset) => i + 1 + offset,
+                    None => return Err(String::from("unterminated quote in where expression")),
+                };
+                tokens.push(Token::Word(chars[i + 1..end].iter().collect()));
+                i = end + 1;
+            }
+            '&' | '|' => return Err(format!("expected '{}{}' in where expression", c, c)),
+            _ => {
+                let start = i;
+                while i < chars.len() && !" \t\n()&|!=<>~'\"".contains(chars[i]) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the token list.
+struct Parser {
+    tokens: std::vec::Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// or := and ('||' and)*
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    /// and := unary ('&&' unary)*
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    /// unary := '!' unary | '(' or ')' | fact [op value]
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(String::from("expected ')' in where expression")),
+                }
+            }
+            Some(Token::Word(key)) => match self.peek() {
+                Some(Token::Op(op)) => {
+                    let op = *op;
+                    self.pos += 1;
+                    match self.next() {
+                        Some(Token::Word(value)) => Ok(Expr::Cmp(key, op, value)),
+                        _ => Err(format!("expected a value after '{}' in where expression", key)),
+                    }
+                }
+                _ => Ok(Expr::Has(key)),
+            },
+            Some(token) => Err(format!("unexpected {:?} in where expression", token)),
+            None => Err(String::from("unexpected end of where expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(pairs: &[(&str, &str)]) -> Facts {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn orders_dotted_values_as_versions() {
+        assert_eq!(order("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(order("10.10", "10.9"), Ordering::Greater);
+        assert_eq!(order("5.15", "5.15.0"), Ordering::Equal);
+        assert_eq!(order("5.15.0-91-generic", "5.4.0"), Ordering::Greater);
+        assert_eq!(order("5", "5.15"), Ordering::Less);
+    }
+
+    #[test]
+    fn orders_whole_numbers_numerically_and_words_as_strings() {
+        assert_eq!(order("9", "10"), Ordering::Less);
+        assert_eq!(order("-3", "2"), Ordering::Less);
+        assert_eq!(order("focal", "jammy"), Ordering::Less);
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expr = parse("os=debian || os=ubuntu && kernel>=5.15").unwrap();
+        assert!(expr.eval(&facts(&[("os", "debian"), ("kernel", "4.19")])));
+        assert!(!expr.eval(&facts(&[("os", "ubuntu"), ("kernel", "5.4")])));
+        assert!(expr.eval(&facts(&[("os", "ubuntu"), ("kernel", "5.15.0-91-generic")])));
+    }
+
+    #[test]
+    fn parses_words_negation_and_parentheses() {
+        let expr = parse("not (arch=x86_64 or arch==aarch64) and hostname~'web-*'").unwrap();
+        assert!(expr.eval(&facts(&[("arch", "riscv64"), ("hostname", "web-01")])));
+        assert!(!expr.eval(&facts(&[("arch", "x86_64"), ("hostname", "web-01")])));
+        assert!(!expr.eval(&facts(&[("arch", "riscv64"), ("hostname", "db-01")])));
+    }
+
+    #[test]
+    fn bare_facts_and_missing_facts() {
+        let expr = parse("virtual && disk_free_pct<10").unwrap();
+        assert!(expr.eval(&facts(&[("virtual", "kvm"), ("disk_free_pct", "9")])));
+        assert!(!expr.eval(&facts(&[("virtual", "0"), ("disk_free_pct", "9")])));
+        assert!(!expr.eval(&facts(&[("virtual", "kvm")])));
+        assert!(parse("os!~ubu*").unwrap().eval(&facts(&[("os", "debian")])));
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        assert!(parse("os=").is_err());
+        assert!(parse("(os=ubuntu").is_err());
+        assert!(parse("os=ubuntu)").is_err());
+        assert!(parse("os=ubuntu & arch=x86_64").is_err());
+        assert!(parse("hostname='web").is_err());
+        assert!(parse("").is_err());
+    }
+}
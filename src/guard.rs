@@ -0,0 +1,138 @@
+//! Provides guardrails against destructive operations on protected hosts and contexts.
+//!
+//! Hosts are protected in the `[guard]` section of the configuration file, whole contexts with
+//! `rman context protect`:
+//!
+//! ```toml
+//! [guard]
+//! protected = ["db-*", "pay01"]           # Target specifications of protected hosts.
+//! denylist = ['\bmkfs', 'rm\s+-rf']       # Optional, replaces the default patterns below.
+//! ```
+//!
+//! Reboots, shutdowns and commands matching a denylist pattern only run on protected hosts once
+//! the context name or the aliases of the protected hosts have been typed, or with
+//! `--yes-i-am-sure`. Commands are matched as each host would receive them, after rendering their
+//! `{{placeholders}}`. Without a terminal to ask on they are refused.
+
+use crate::args;
+use crate::context;
+use crate::host::{self, Host};
+use crate::style;
+use crate::targets;
+use config::Config;
+use regex::Regex;
+use std::io::{IsTerminal, Write};
+
+/// Commands considered destructive unless the configuration file lists its own.
+pub const DEFAULT_DENYLIST: [&str; 7] = [
+    r"\brm\s+(-\S+\s+)*-[a-zA-Z]*[rR]",     // Recursive removal, e.g. `rm -rf` or `rm -f -r`.
+    r"\bmkfs",                              // Creating file systems.
+    r"\bdd\b",                              // Raw writes to devices.
+    r"\bwipefs\b",
+    r"\b(shutdown|reboot|poweroff|halt)\b",
+    r"\b(fdisk|sfdisk|parted)\b",
+    r">\s*/dev/(sd|nvme|vd|xvd)",           // Overwriting a disk.
+];
+
+/// The `[guard]` section of the configuration file.
+pub struct Guard {
+    protected: std::vec::Vec<String>,       // Target specifications of protected hosts.
+    denylist: std::vec::Vec<Regex>,         // Patterns of destructive commands.
+}
+
+impl Guard {
+    /// Loads the `[guard]` section of the rman config, using the default denylist if it has none.
+    pub fn load() -> Guard {
+        let mut settings = Config::new();
+        if settings.merge(config::File::from(host::config_path()).required(false)).is_err() {
+            return Guard::new(vec!(), DEFAULT_DENYLIST.iter().map(|pattern| pattern.to_string()).collect());
+        }
+        let protected = settings.get::<std::vec::Vec<String>>("guard.protected").unwrap_or_default();
+        let denylist = settings.get::<std::vec::Vec<String>>("guard.denylist")
+            .unwrap_or_else(|_| DEFAULT_DENYLIST.iter().map(|pattern| pattern.to_string()).collect());
+        Guard::new(protected, denylist)
+    }
+
+    /// A guard protecting the hosts matching `protected`, skipping invalid denylist patterns.
+    fn new(protected: std::vec::Vec<String>, denylist: std::vec::Vec<String>) -> Guard {
+        let denylist = denylist.iter().filter_map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(err) => {
+                println!("Ignoring invalid denylist pattern '{}': {}", pattern, err);
+                None
+            }
+        }).collect();
+        Guard { protected, denylist }
+    }
+
+    /// Whether a command matches one of the denylist patterns.
+    pub fn is_destructive(&self, command: &str) -> bool {
+        self.denylist.iter().any(|regex| regex.is_match(command))
+    }
+
+    /// The protected hosts among `hosts`.
+    pub fn protected(&self, hosts: &[Host]) -> std::vec::Vec<Host> {
+        // A part matching none of these hosts only means none of them is protected by it.
+        let matched: std::vec::Vec<Host> = self.protected.iter()
+            .flat_map(|spec| targets::split_list(spec))
+            .flat_map(|part| targets::resolve(&part, hosts).unwrap_or_default())
+            .collect();
+        hosts.iter().filter(|host| matched.iter().any(|protected| protected.alias == host.alias)).cloned().collect()
+    }
+}
+
+/// Asks for confirmation before `command` runs on `hosts` if one of them is protected and would
+/// receive a destructive command, exiting if it isn't given. `rendered[i]` is the command as the
+/// i-th host receives it.
+pub fn confirm_command(action: &str, command: &str, hosts: &[Host], rendered: &[String]) {
+    let guard = Guard::load();
+    let destructive: std::vec::Vec<Host> = hosts.iter().zip(rendered)
+        .filter(|(_, rendered)| guard.is_destructive(rendered))
+        .map(|(host, _)| host.clone())
+        .collect();
+    confirm_with(&guard, &format!("{} \"{}\"", action, command), &destructive);
+}
+
+/// Asks for confirmation before the destructive `action` runs on `hosts` if one of them is
+/// protected, exiting if it isn't given.
+pub fn confirm(action: &str, hosts: &[Host]) {
+    confirm_with(&Guard::load(), action, hosts);
+}
+
+/// Asks to type the context name or the aliases of the protected hosts, unless `--yes-i-am-sure`
+/// was given. Nothing is asked in check mode, as nothing is run.
+fn confirm_with(guard: &Guard, action: &str, hosts: &[Host]) {
+    if args::check_mode() || hosts.is_empty() {
+        return;
+    }
+    let (subject, expected) = match context::protected_in_use() {
+        Some(name) => (format!("{} hosts of the protected context {}", hosts.len(), name), name),
+        None => {
+            let protected = guard.protected(hosts);
+            match protected.as_slice() {
+                [] => return,
+                [host] => (format!("the protected host {}", host.alias), host.alias.clone()),
+                _ => {
+                    let aliases: std::vec::Vec<&str> = protected.iter().map(|host| host.alias.as_str()).collect();
+                    (format!("{} protected hosts", protected.len()), aliases.join(","))
+                }
+            }
+        }
+    };
+    if args::confirmed() {
+        println!("Confirmed with --yes-i-am-sure: {} on {}", action, subject);
+        return;
+    }
+    if !std::io::stdin().is_terminal() {
+        println!("Refusing to {} on {} without a terminal to confirm on, pass --yes-i-am-sure to override", action, subject);
+        std::process::exit(1);
+    }
+    print!("{} {} on {}.\nType {} to continue: ", style::red("About to"), action, subject, style::bold(&expected));
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    // Spaces are ignored, so the aliases may be typed as `db01, db02`.
+    if std::io::stdin().read_line(&mut answer).is_err() || answer.split_whitespace().collect::<String>() != expected {
+        println!("Aborted, nothing was run");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,383 @@
+//! Provides parsed, thresholded health reports for `$rman host status` and `$rman all status`.
+//!
+//! A single shell snippet collects load, disk, inode, memory and systemd information from the
+//! remote machine. The output is parsed into metrics, and each metric is checked against the
+//! warn/critical thresholds from the `[thresholds]` section of the rman config, e.g.
+//!
+//! ```toml
+//! [thresholds]
+//! load_warn = 1.0   # 1 minute load average per CPU
+//! load_crit = 2.0
+//! disk_warn = 80    # percent used, per mount
+//! disk_crit = 90
+//! ```
+
+use crate::args;
+use crate::executor::{self, Executor};
+use crate::host::{self, Host};
+use crate::ssh_con::execute_remote_command;
+use crate::style;
+use config::Config;
+use std::collections::HashMap;
+use tokio_stream::StreamExt;
+
+/// Shell snippet run on the remote machine, each section is introduced by a `## name` line.
+const HEALTH_CMD: &str = "echo '## cpus'; nproc 2>/dev/null || getconf _NPROCESSORS_ONLN; \
+echo '## loadavg'; cat /proc/loadavg; \
+echo '## df'; df -P -x tmpfs -x devtmpfs -x squashfs -x overlay 2>/dev/null; \
+echo '## dfi'; df -Pi -x tmpfs -x devtmpfs -x squashfs -x overlay 2>/dev/null; \
+echo '## meminfo'; cat /proc/meminfo; \
+echo '## failed'; systemctl list-units --state=failed --no-legend --plain 2>/dev/null";
+
+/// Severity of a check, ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Ok,
+    Warn,
+    Crit,
+}
+
+/// Warn and critical thresholds for every metric.
+pub struct Thresholds {
+    pub load: (f64, f64),           // 1 minute load average divided by the CPU count.
+    pub disk: (f64, f64),           // Percent of blocks used on a mount.
+    pub inode: (f64, f64),          // Percent of inodes used on a mount.
+    pub mem: (f64, f64),            // Percent of memory used, not counting caches.
+    pub swap: (f64, f64),           // Percent of swap used.
+    pub failed_units: (f64, f64),   // Number of failed systemd units.
+}
+
+/// Usage of a single mount.
+pub struct MountUsage {
+    pub mount: String,
+    pub used_pct: f64,
+}
+
+/// Structured metrics parsed from a host.
+pub struct Metrics {
+    pub cpus: f64,
+    pub load: (f64, f64, f64),      // Load averages divided by the CPU count.
+    pub disks: std::vec::Vec<MountUsage>,
+    pub inodes: std::vec::Vec<MountUsage>,
+    pub mem_pct: f64,
+    pub swap_pct: f64,
+    pub failed_units: std::vec::Vec<String>,
+}
+
+/// A single metric compared against its thresholds.
+pub struct Check {
+    pub name: String,
+    pub value: String,
+    pub level: Level,
+}
+
+/// Health of a single host. `metrics` is `None` if the host couldn't be reached.
+pub struct Report {
+    pub alias: String,
+    pub metrics: Option<Metrics>,
+    pub checks: std::vec::Vec<Check>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds {
+            load: (1.0, 2.0),
+            disk: (80.0, 90.0),
+            inode: (80.0, 90.0),
+            mem: (90.0, 95.0),
+            swap: (50.0, 80.0),
+            failed_units: (1.0, 3.0),
+        }
+    }
+}
+
+impl Thresholds {
+    /// Loads thresholds from the `[thresholds]` section of the rman config, falling back to the defaults.
+    pub fn load() -> Thresholds {
+        let mut settings = Config::new();
+        if settings.merge(config::File::from(host::config_path()).required(false)).is_err() {
+            return Thresholds::default();
+        }
+        let get = |name: &str, default: f64| settings.get::<f64>(&format!("thresholds.{}", name)).unwrap_or(default);
+        let defaults = Thresholds::default();
+        Thresholds {
+            load: (get("load_warn", defaults.load.0), get("load_crit", defaults.load.1)),
+            disk: (get("disk_warn", defaults.disk.0), get("disk_crit", defaults.disk.1)),
+            inode: (get("inode_warn", defaults.inode.0), get("inode_crit", defaults.inode.1)),
+            mem: (get("mem_warn", defaults.mem.0), get("mem_crit", defaults.mem.1)),
+            swap: (get("swap_warn", defaults.swap.0), get("swap_crit", defaults.swap.1)),
+            failed_units: (get("failed_units_warn", defaults.failed_units.0), get("failed_units_crit", defaults.failed_units.1)),
+        }
+    }
+}
+
+impl Report {
+    /// Worst level of all checks.
+    pub fn level(&self) -> Level {
+        self.checks.iter().map(|check| check.level).max().unwrap_or(Level::Ok)
+    }
+
+    /// Worst level of the checks whose name starts with `prefix`.
+    fn level_of(&self, prefix: &str) -> Level {
+        self.checks.iter().filter(|check| check.name.starts_with(prefix)).map(|check| check.level).max().unwrap_or(Level::Ok)
+    }
+}
+
+/// Collects and checks the health of a host.
+pub fn report(host: &Host, thresholds: &Thresholds) -> Report {
+    let output = execute_remote_command(host, HEALTH_CMD);
+    match parse(&output) {
+        Some(metrics) => {
+            let checks = check(&metrics, thresholds);
+            Report { alias: host.alias.clone(), metrics: Some(metrics), checks }
+        }
+        None => Report {
+            alias: host.alias.clone(),
+            metrics: None,
+            checks: vec!(Check { name: String::from("reachable"), value: output.trim().to_string(), level: Level::Crit }),
+        },
+    }
+}
+
+/// Collects and checks the health of every host concurrently, returning reports in host order.
+/// Hosts that time out or are cancelled get a critical `reachable` check.
+pub fn reports(hosts: std::vec::Vec<Host>, thresholds: Thresholds) -> std::vec::Vec<Report> {
+    let run = args::cancellation();
+    let executor = Executor::new(hosts).with_limits(args::limits()).with_cancellation(run.token());
+    let order: std::vec::Vec<String> = executor.hosts().iter().map(|host| host.alias.clone()).collect();
+    let mut reports: std::vec::Vec<Report> = executor::block_on(async {
+        executor.each(move |_, host| report(host, &thresholds)).await.map(|outcome| match outcome.result {
+            Ok(report) => report,
+            Err(interrupted) => Report {
+                alias: outcome.alias,
+                metrics: None,
+                checks: vec!(Check { name: String::from("reachable"), value: interrupted.to_string(), level: Level::Crit }),
+            },
+        }).collect().await
+    });
+    reports.sort_by_key(|report| order.iter().position(|alias| *alias == report.alias));
+    reports
+}
+
+/// Parses the output of `HEALTH_CMD`, returning `None` if it doesn't look like the snippet ran.
+pub fn parse(output: &str) -> Option<Metrics> {
+    let mut sections: HashMap<&str, std::vec::Vec<&str>> = HashMap::new();
+    let mut current = None;
+    for line in output.lines() {
+        if let Some(name) = line.strip_prefix("## ") {
+            current = Some(name.trim());
+            sections.insert(name.trim(), vec!());
+        } else if let Some(name) = current {
+            sections.entry(name).or_default().push(line);
+        }
+    }
+    let section = |name: &str| sections.get(name).cloned().unwrap_or_default();
+
+    let cpus = section("cpus").first()?.trim().parse::<f64>().ok()?.max(1.0);
+    let loads: std::vec::Vec<f64> = section("loadavg").first()?.split_whitespace().take(3).filter_map(|v| v.parse().ok()).collect();
+    if loads.len() < 3 {
+        return None;
+    }
+
+    let meminfo: HashMap<&str, f64> = section("meminfo").iter().filter_map(|line| {
+        let mut parts = line.split_whitespace();
+        let key = parts.next()?.trim_end_matches(':');
+        Some((key, parts.next()?.parse().ok()?))
+    }).collect();
+    let pct = |used: f64, total: f64| if total > 0.0 { 100.0 * used / total } else { 0.0 };
+    let mem_total = *meminfo.get("MemTotal").unwrap_or(&0.0);
+    let mem_available = *meminfo.get("MemAvailable").or_else(|| meminfo.get("MemFree")).unwrap_or(&0.0);
+    let swap_total = *meminfo.get("SwapTotal").unwrap_or(&0.0);
+    let swap_free = *meminfo.get("SwapFree").unwrap_or(&0.0);
+
+    Some(Metrics {
+        cpus,
+        load: (loads[0] / cpus, loads[1] / cpus, loads[2] / cpus),
+        disks: parse_df(&section("df")),
+        inodes: parse_df(&section("dfi")),
+        mem_pct: pct(mem_total - mem_available, mem_total),
+        swap_pct: pct(swap_total - swap_free, swap_total),
+        failed_units: section("failed").iter().filter_map(|line| line.split_whitespace().next()).map(String::from).collect(),
+    })
+}
+
+/// Parses `df -P` or `df -Pi` output into per-mount usage, skipping mounts without a percentage.
+fn parse_df(lines: &[&str]) -> std::vec::Vec<MountUsage> {
+    lines.iter().skip(1).filter_map(|line| {
+        let fields: std::vec::Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            return None;
+        }
+        Some(MountUsage {
+            mount: fields[5..].join(" "),
+            used_pct: fields[4].trim_end_matches('%').parse().ok()?,
+        })
+    }).collect()
+}
+
+/// Compares a value against a (warn, crit) pair.
+fn level(value: f64, (warn, crit): (f64, f64)) -> Level {
+    if value >= crit {
+        Level::Crit
+    } else if value >= warn {
+        Level::Warn
+    } else {
+        Level::Ok
+    }
+}
+
+/// Checks every metric against the thresholds.
+pub fn check(metrics: &Metrics, thresholds: &Thresholds) -> std::vec::Vec<Check> {
+    let mut checks = vec!(Check {
+        name: String::from("load"),
+        value: format!("{:.2} {:.2} {:.2} per cpu ({} cpus)", metrics.load.0, metrics.load.1, metrics.load.2, metrics.cpus),
+        level: level(metrics.load.0, thresholds.load),
+    });
+    for disk in metrics.disks.iter() {
+        checks.push(Check {
+            name: format!("disk {}", disk.mount),
+            value: format!("{:.0}% used", disk.used_pct),
+            level: level(disk.used_pct, thresholds.disk),
+        });
+    }
+    for inode in metrics.inodes.iter() {
+        checks.push(Check {
+            name: format!("inodes {}", inode.mount),
+            value: format!("{:.0}% used", inode.used_pct),
+            level: level(inode.used_pct, thresholds.inode),
+        });
+    }
+    checks.push(Check {
+        name: String::from("memory"),
+        value: format!("{:.0}% used", metrics.mem_pct),
+        level: level(metrics.mem_pct, thresholds.mem),
+    });
+    checks.push(Check {
+        name: String::from("swap"),
+        value: format!("{:.0}% used", metrics.swap_pct),
+        level: level(metrics.swap_pct, thresholds.swap),
+    });
+    checks.push(Check {
+        name: String::from("failed units"),
+        value: if metrics.failed_units.is_empty() { String::from("none") } else { metrics.failed_units.join(", ") },
+        level: level(metrics.failed_units.len() as f64, thresholds.failed_units),
+    });
+    checks
+}
+
+/// Colours `text` according to `level`.
+fn paint(text: &str, level: Level) -> String {
+    match level {
+        Level::Ok => style::green(text),
+        Level::Warn => style::yellow(text),
+        Level::Crit => style::red(text),
+    }
+}
+
+/// Short label of a level.
+fn label(level: Level) -> &'static str {
+    match level {
+        Level::Ok => "OK",
+        Level::Warn => "WARN",
+        Level::Crit => "CRIT",
+    }
+}
+
+/// Prints a detailed per-check report of a single host.
+pub fn print_report(report: &Report) {
+    println!("{} {}", style::bold(&report.alias), paint(label(report.level()), report.level()));
+    let width = report.checks.iter().map(|check| check.name.len()).max().unwrap_or(0);
+    for check in report.checks.iter() {
+        println!("  {} {:<width$}  {}", paint(&format!("{:<4}", label(check.level)), check.level), check.name, check.value, width = width);
+    }
+}
+
+/// Prints a one-row-per-host summary table of the whole fleet.
+pub fn print_summary(reports: &[Report]) {
+    let alias_width = reports.iter().map(|report| report.alias.len()).max().unwrap_or(0).max(4);
+    println!("{}", style::bold(&format!("{:<aw$}  {:>8}  {:>5}  {:>5}  {:>5}  {:>5}  {:>6}  STATE",
+                                        "HOST", "LOAD/CPU", "DISK", "INODE", "MEM", "SWAP", "FAILED", aw = alias_width)));
+    for report in reports.iter() {
+        let alias = format!("{:<aw$}", report.alias, aw = alias_width);
+        match report.metrics {
+            Some(ref metrics) => {
+                let worst = |usages: &[MountUsage]| usages.iter().map(|usage| usage.used_pct).fold(0.0, f64::max);
+                println!("{}  {}  {}  {}  {}  {}  {}  {}",
+                         alias,
+                         paint(&format!("{:>8.2}", metrics.load.0), report.level_of("load")),
+                         paint(&format!("{:>4.0}%", worst(&metrics.disks)), report.level_of("disk")),
+                         paint(&format!("{:>4.0}%", worst(&metrics.inodes)), report.level_of("inodes")),
+                         paint(&format!("{:>4.0}%", metrics.mem_pct), report.level_of("memory")),
+                         paint(&format!("{:>4.0}%", metrics.swap_pct), report.level_of("swap")),
+                         paint(&format!("{:>6}", metrics.failed_units.len()), report.level_of("failed units")),
+                         paint(label(report.level()), report.level()));
+            }
+            None => println!("{}  {}", alias, paint("UNREACHABLE", Level::Crit)),
+        }
+    }
+    let count = |level: Level| reports.iter().filter(|report| report.level() == level).count();
+    println!("\n{} ok, {} warning, {} critical", count(Level::Ok), count(Level::Warn), count(Level::Crit));
+}
+
+/// Exits with a non-zero status if any report is critical.
+pub fn exit_on_critical(reports: &[Report]) {
+    if reports.iter().any(|report| report.level() == Level::Crit) {
+        std::process::exit(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "\
+## cpus
+4
+## loadavg
+6.00 2.00 1.00 3/512 4242
+## df
+Filesystem     1024-blocks      Used Available Capacity Mounted on
+/dev/sda1         40000000  38000000   2000000      95% /
+/dev/sdb1        100000000  10000000  90000000      10% /srv/my data
+## dfi
+Filesystem      Inodes  IUsed   IFree IUse% Mounted on
+/dev/sda1      2000000 100000 1900000      5% /
+## meminfo
+MemTotal:        8000000 kB
+MemFree:          500000 kB
+MemAvailable:    2000000 kB
+SwapTotal:       1000000 kB
+SwapFree:        1000000 kB
+## failed
+nginx.service loaded failed failed A high performance web server
+";
+
+    #[test]
+    fn parses_every_section() {
+        let metrics = parse(OUTPUT).unwrap();
+        assert_eq!(metrics.cpus, 4.0);
+        assert_eq!(metrics.load, (1.5, 0.5, 0.25));
+        assert_eq!(metrics.disks.iter().map(|disk| (disk.mount.as_str(), disk.used_pct)).collect::<std::vec::Vec<_>>(), vec!(("/", 95.0), ("/srv/my data", 10.0)));
+        assert_eq!(metrics.inodes.len(), 1);
+        assert_eq!(metrics.mem_pct, 75.0);
+        assert_eq!(metrics.swap_pct, 0.0);
+        assert_eq!(metrics.failed_units, vec!(String::from("nginx.service")));
+    }
+
+    #[test]
+    fn rejects_output_of_a_host_that_did_not_run_the_snippet() {
+        assert!(parse("Host cannot be reached.").is_none());
+        assert!(parse("## cpus\n4\n## loadavg\n").is_none());
+    }
+
+    #[test]
+    fn checks_metrics_against_thresholds() {
+        let report = Report { alias: String::from("web01"), checks: check(&parse(OUTPUT).unwrap(), &Thresholds::default()), metrics: None };
+        let level_of = |name: &str| report.checks.iter().find(|check| check.name == name).map(|check| check.level);
+        assert_eq!(level_of("load"), Some(Level::Warn));
+        assert_eq!(level_of("disk /"), Some(Level::Crit));
+        assert_eq!(level_of("disk /srv/my data"), Some(Level::Ok));
+        assert_eq!(level_of("failed units"), Some(Level::Warn));
+        assert_eq!(report.level(), Level::Crit);
+    }
+}
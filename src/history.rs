@@ -0,0 +1,386 @@
+//! Provides the run history and audit log behind `$rman history`.
+//!
+//! Every remote command, reboot, shutdown and inventory change is appended as one JSON object
+//! per line to `~/.local/share/rman/history.jsonl`. Command output is only kept when
+//! `capture_output = true` is set in the `[history]` section of the rman config. Entries are
+//! hash-chained so tampering can be detected with `$rman audit verify`.
+
+use crate::args;
+use crate::cli::{HistoryArgs, HistoryCommand};
+use crate::audit;
+use crate::executor::HostOutcome;
+use crate::host;
+use crate::ssh_con::CmdResult;
+use crate::style;
+use chrono::{Local, NaiveDate, TimeZone};
+use config::Config;
+use fs2::FileExt;
+use glob::Pattern;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single recorded action.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Run {
+    pub id: String,
+    pub timestamp: i64,                 // Unix time the action was recorded at.
+    pub user: String,                   // Local user running rman.
+    pub action: String,                 // e.g. "host exec", "all exec", "reboot", "host add".
+    pub targets: std::vec::Vec<String>, // Aliases the action was aimed at.
+    pub command: String,                // Remote command, or a description of the inventory change.
+    pub results: std::vec::Vec<HostResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_of: Option<String>,       // Id of the run this entry retries, see `retry`.
+    #[serde(default)]
+    pub prev_hash: String,              // Hash of the previous entry, see `audit`.
+    #[serde(default)]
+    pub hash: String,                   // Hash of this entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,      // ed25519 signature of `hash`, if a signing key exists.
+}
+
+/// Outcome of a run on a single host.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HostResult {
+    pub alias: String,
+    pub status: String,                 // "ok", "failed", "unreachable" or "interrupted".
+    pub exit_status: Option<i32>,
+    pub duration_ms: u64,
+    pub output: Option<String>,         // Only kept if output capture is enabled.
+}
+
+impl HostResult {
+    /// Builds the record of a command's result, keeping the output if `capture` is set.
+    pub fn new(alias: &str, result: &CmdResult, capture: bool) -> HostResult {
+        let status = if result.interrupted {
+            "interrupted"
+        } else if !result.connected {
+            "unreachable"
+        } else if result.exit_status.unwrap_or(0) == 0 {
+            "ok"
+        } else {
+            "failed"
+        };
+        HostResult {
+            alias: alias.to_string(),
+            status: status.to_string(),
+            exit_status: result.exit_status,
+            duration_ms: result.duration.as_millis() as u64,
+            output: if capture { Some(result.output.clone()) } else { None },
+        }
+    }
+
+    /// Records a host the action couldn't be attempted on, e.g. for lack of privileges.
+    pub fn refused(alias: &str, reason: &str) -> HostResult {
+        HostResult {
+            alias: alias.to_string(),
+            status: String::from("failed"),
+            exit_status: None,
+            duration_ms: 0,
+            output: Some(reason.to_string()),
+        }
+    }
+}
+
+/// Whether command output should be stored, from `history.capture_output` in the rman config.
+pub fn capture_output() -> bool {
+    let mut settings = Config::new();
+    if settings.merge(config::File::from(host::config_path()).required(false)).is_err() {
+        return false;
+    }
+    settings.get::<bool>("history.capture_output").unwrap_or(false)
+}
+
+/// Converts command results into `HostResult`s, honouring the output capture setting.
+pub fn results(results: &[HostOutcome<CmdResult>]) -> std::vec::Vec<HostResult> {
+    let capture = capture_output();
+    results.iter().map(|outcome| HostResult::new(&outcome.alias, &outcome.result, capture)).collect()
+}
+
+/// Appends a run to the history, printing a warning if it can't be written.
+pub fn record(action: &str, command: &str, targets: std::vec::Vec<String>, results: std::vec::Vec<HostResult>) {
+    record_retry(action, command, targets, results, None);
+}
+
+/// Appends a run to the history that retries the run `retry_of`, if given.
+/// Nothing is recorded in check mode, as nothing was done.
+pub fn record_retry(action: &str, command: &str, targets: std::vec::Vec<String>, results: std::vec::Vec<HostResult>, retry_of: Option<String>) {
+    if args::check_mode() {
+        return;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let run = Run {
+        id: String::new(),              // Given by `append` under the history lock.
+        timestamp: now.as_secs() as i64,
+        user: local_user(),
+        action: action.to_string(),
+        targets,
+        command: command.to_string(),
+        results,
+        retry_of,
+        prev_hash: String::new(),
+        hash: String::new(),
+        signature: None,
+    };
+    if let Err(err) = append(run) {
+        eprintln!("Unable to write to the history at {}: {}", history_path().display(), err);
+    }
+}
+
+/// Name of the local user.
+fn local_user() -> String {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// Path of the history file.
+pub fn history_path() -> PathBuf {
+    let mut path = match dirs::data_dir() {
+        Some(buf) => buf,
+        _ => panic!("Error getting data directory"),
+    };
+    path.push("rman");
+    path.push("history.jsonl");
+    path
+}
+
+/// Appends one run as a JSON line, chained to the last entry.
+/// The history file stays locked while the chain is read and extended so concurrent runs can't interleave.
+fn append(mut run: Run) -> std::io::Result<()> {
+    let path = history_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+    file.lock_exclusive()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let entries: std::vec::Vec<Run> = contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    run.id = unique_id(now.as_millis() as u64 * 1000 + (std::process::id() as u64 % 1000), &entries);
+    let prev_hash = entries.iter().rev().map(|entry| entry.hash.as_str()).find(|hash| !hash.is_empty()).unwrap_or(audit::GENESIS_HASH);
+    audit::seal(&mut run, prev_hash);
+
+    let line = serde_json::to_string(&run).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+    audit::write_head(entries.len() + 1, &run.hash)
+}
+
+/// The first id from `candidate` on that no entry has yet, in hex.
+fn unique_id(mut candidate: u64, entries: &[Run]) -> String {
+    loop {
+        let id = format!("{:x}", candidate);
+        if !entries.iter().any(|entry| entry.id == id) {
+            return id;
+        }
+        candidate += 1;
+    }
+}
+
+/// Loads every run, oldest first. Lines that can't be parsed are skipped.
+pub fn load() -> std::vec::Vec<Run> {
+    match fs::read_to_string(history_path()) {
+        Ok(contents) => contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect(),
+        Err(_) => vec!(),
+    }
+}
+
+/// Loads a run with the results of all of its retries merged in, the latest result of each host winning.
+/// Returns the run along with the number of retries merged.
+pub fn merged(id: &str) -> Option<(Run, usize)> {
+    let runs = load();
+    let mut run = runs.iter().find(|run| run.id == id)?.clone();
+    let mut retries = 0;
+    for retry in runs.iter().filter(|retry| retry.retry_of.as_deref() == Some(id)) {
+        retries += 1;
+        for result in retry.results.iter() {
+            match run.results.iter_mut().find(|existing| existing.alias == result.alias) {
+                Some(existing) => *existing = result.clone(),
+                None => run.results.push(result.clone()),
+            }
+        }
+    }
+    Some((run, retries))
+}
+
+/// This function handles all `$rman history` commands.
+pub fn base(args: HistoryArgs) {
+    if let Some(HistoryCommand::Show { run_id }) = args.command {
+        show(&run_id);
+        return;
+    }
+    let HistoryArgs { host, since, grep, .. } = args;
+    let since = match since.map(|since| parse_since(&since)).transpose() {
+        Ok(since) => since,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    let host = match host.map(|host| Pattern::new(&host)).transpose() {
+        Ok(host) => host,
+        Err(err) => {
+            println!("Invalid host pattern: {}", err);
+            return;
+        }
+    };
+    let grep = match grep.map(|grep| Regex::new(&grep)).transpose() {
+        Ok(grep) => grep,
+        Err(err) => {
+            println!("Invalid grep pattern: {}", err);
+            return;
+        }
+    };
+
+    for run in load().iter().filter(|run| {
+        since.is_none_or(|since| run.timestamp >= since)
+            && host.as_ref().is_none_or(|host| run.targets.iter().any(|alias| host.matches(alias)))
+            && grep.as_ref().is_none_or(|grep| grep.is_match(&run.command) || grep.is_match(&run.action))
+    }) {
+        println!("{}  {}  {:<8}  {:<10}  {:<24}  {}  {}",
+                 style::bold(&run.id), format_time(run.timestamp), run.user, run.action,
+                 summarize_targets(&run.targets), summarize_results(&run.results), run.command);
+    }
+}
+
+/// Prints a single run with the result of every host, including the results of its retries.
+fn show(id: &str) {
+    let (run, retries) = match merged(id) {
+        Some(merged) => merged,
+        None => {
+            println!("No run with id {}", id);
+            return;
+        }
+    };
+    println!("Run      : {}\nTime     : {}\nUser     : {}\nAction   : {}\nCommand  : {}\nTargets  : {}",
+             run.id, format_time(run.timestamp), run.user, run.action, run.command, run.targets.join(", "));
+    if let Some(ref retry_of) = run.retry_of {
+        println!("Retry of : {}", retry_of);
+    }
+    if retries > 0 {
+        println!("Retries  : {} merged", retries);
+    }
+    println!();
+    for result in run.results.iter() {
+        let status = match result.status.as_str() {
+            "ok" => style::green(&result.status),
+            "failed" => style::red(&result.status),
+            _ => style::yellow(&result.status),
+        };
+        let exit_status = result.exit_status.map(|code| code.to_string()).unwrap_or_else(|| String::from("-"));
+        println!("{} {} (exit {}, {} ms)", style::bold(&result.alias), status, exit_status, result.duration_ms);
+        if let Some(ref output) = result.output {
+            println!("{}", output.trim_end());
+        }
+    }
+}
+
+/// Parses `--since` values such as `30m`, `12h`, `2d`, `1w` or a date like `2026-10-01` into a unix time.
+fn parse_since(since: &str) -> Result<i64, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        return match Local.from_local_datetime(&midnight).earliest() {
+            Some(time) => Ok(time.timestamp()),
+            None => Err(format!("Invalid date '{}'", since)),
+        };
+    }
+    let invalid = || format!("Invalid --since value '{}', expected e.g. 30m, 12h, 2d, 1w or 2026-10-01", since);
+    let unit = since.chars().last().ok_or_else(invalid)?;
+    let amount: i64 = since[..since.len() - unit.len_utf8()].parse().map_err(|_| invalid())?;
+    let unit_seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 60 * 60 * 24,
+        'w' => 60 * 60 * 24 * 7,
+        _ => return Err(invalid()),
+    };
+    amount.checked_mul(unit_seconds).and_then(|seconds| Local::now().timestamp().checked_sub(seconds)).ok_or_else(invalid)
+}
+
+/// Formats a unix time in local time.
+fn format_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Short list of targets, e.g. `web01, web02 +3`.
+fn summarize_targets(targets: &[String]) -> String {
+    match targets.len() {
+        0..=2 => targets.join(", "),
+        n => format!("{}, {} +{}", targets[0], targets[1], n - 2),
+    }
+}
+
+/// Counts of results by status, e.g. `3 ok 1 failed`.
+fn summarize_results(results: &[HostResult]) -> String {
+    if results.is_empty() {
+        return String::from("-");
+    }
+    let count = |status: &str| results.iter().filter(|result| result.status == status).count();
+    let mut summary = vec!(style::green(&format!("{} ok", count("ok"))));
+    if count("failed") > 0 {
+        summary.push(style::red(&format!("{} failed", count("failed"))));
+    }
+    if count("unreachable") > 0 {
+        summary.push(style::yellow(&format!("{} unreachable", count("unreachable"))));
+    }
+    if count("interrupted") > 0 {
+        summary.push(style::yellow(&format!("{} interrupted", count("interrupted"))));
+    }
+    summary.join(" ")
+}
+
+/// A run with the given id, action and command and no results, for tests reading the history.
+#[cfg(test)]
+pub fn test_run(id: &str, action: &str, command: &str) -> Run {
+    Run {
+        id: id.to_string(),
+        timestamp: 0,
+        user: String::from("ops"),
+        action: action.to_string(),
+        targets: vec!(String::from("web01")),
+        command: command.to_string(),
+        results: vec!(),
+        retry_of: None,
+        prev_hash: String::new(),
+        hash: String::new(),
+        signature: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_runs_ids_no_entry_has_yet() {
+        let entries = vec!(test_run("a", "config undo", "inventory"), test_run("b", "host add", "web01"));
+        assert_eq!(unique_id(10, &entries), "c");
+        assert_eq!(unique_id(12, &entries), "c");
+        assert_eq!(unique_id(9, &entries), "9");
+    }
+
+    #[test]
+    fn parses_since_durations_and_dates() {
+        let now = Local::now().timestamp();
+        let since = parse_since("2h").unwrap();
+        assert!((now - 2 * 60 * 60 - since).abs() <= 1);
+        assert!(parse_since("2026-10-01").is_ok());
+        assert!(parse_since("2x").is_err());
+        assert!(parse_since("").is_err());
+    }
+
+    #[test]
+    fn rejects_since_values_that_overflow() {
+        let err = parse_since("99999999999999w").unwrap_err();
+        assert!(err.contains("Invalid --since"), "{}", err);
+        assert!(parse_since(&format!("{}s", i64::MIN)).is_err());
+    }
+}
@@ -1,34 +1,113 @@
 //! Provides the `Host` struct as well as some functions to interact utilize them.
 
-use crate::help;
+use crate::all;
+use crate::cli::HostCommand;
+use crate::args;
+use crate::diff;
+use crate::executor::HostOutcome;
+use crate::facts;
+use crate::guard;
+use crate::health;
+use crate::history;
+use crate::inventory::Inventory;
+use crate::listing;
+use crate::modules;
+use crate::sources;
 use crate::ssh_con;
+use crate::store;
+use crate::style;
+use crate::targets;
+use crate::template;
+use crate::validate;
 extern crate serde_derive;
 extern crate dirs;
-use config::Config;
-use serde_derive::{Serialize, Deserialize};
+use config::{Config, ConfigError};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
-use crate::ssh_con::{execute_remote_command, shutdown, reboot};
+use std::path::{Path, PathBuf};
+use crate::ssh_con::{shutdown, reboot};
 
 /// Struct to store host data in aggregate while in mem.
 /// # Examples
 /// ```
+/// use rman::Host;
+/// use std::collections::BTreeMap;
+///
 /// let host = Host {
 ///     alias: String::from("localhost"),                    // alias field denotes a nickname for the remote machine.
 ///     ip: String::from("127.0.0.1"),                       // ip field denotes the remote machines IP.
 ///     ssh_user: String::from("root"),                      // ssh_user field denotes the ssh user.
 ///     pk_path: String::from("~/.ssh/localhost.pem"),       // pk_path denotes the path to the ssh private key.
-///     description: String::from("An optional description") // description is an optional field to provide a short description of the remote machine.
-/// }
+///     description: String::from("An optional description"), // description is an optional field to provide a short description of the remote machine.
+///     tags: vec!(String::from("web")),                    // tags field groups hosts for filtering, e.g. `host ls --tag web`.
+///     vars: BTreeMap::new(),                              // vars field holds values for `{{vars.name}}` placeholders.
+///     port: 22,                                           // port field denotes the ssh port.
+///     timeout: None,                                      // timeout field optionally limits the seconds a run may take on the host.
+///     become_user: None,                                  // become_user field names the user commands are run as through sudo.
+///     inherited: BTreeMap::new(),                         // inherited field lists the settings taken from `[defaults]` or a group.
+/// };
+/// assert_eq!(host.alias, Host::new("localhost", "127.0.0.1", "root", "~/.ssh/localhost.pem").alias);
 /// ```
+#[derive(Clone)]
 pub struct Host {
     pub alias: String,          // Host alias for reference purposes.
     pub ip: String,             // Remote machine's ip address.
     pub ssh_user: String,       // User to attempt to connect to on remote machine.
     pub pk_path: String,        // Path to the private key for the ssh connection.
     pub description: String,    // Brief optional description of remote machine.
+    pub tags: std::vec::Vec<String>, // Optional tags used to filter hosts.
+    pub vars: BTreeMap<String, String>, // The host's own variables, group variables are merged in by `effective_vars`.
+    pub port: u16,              // Port of the ssh server.
+    pub timeout: Option<u64>,   // Seconds a run may take on the host unless `--host-timeout` is given.
+    pub become_user: Option<String>, // User commands are run as through `sudo`, if any.
+    pub inherited: BTreeMap<String, String>, // Settings the host doesn't set itself, by name, with where their value came from.
+}
+
+impl Host {
+    /// A host without description, tags or variables, using the default port, timeout and become settings.
+    pub fn new(alias: &str, ip: &str, ssh_user: &str, pk_path: &str) -> Host {
+        Host {
+            alias: alias.to_string(),
+            ip: ip.to_string(),
+            ssh_user: ssh_user.to_string(),
+            pk_path: pk_path.to_string(),
+            description: String::new(),
+            tags: vec!(),
+            vars: BTreeMap::new(),
+            port: DEFAULT_PORT,
+            timeout: None,
+            become_user: None,
+            inherited: ["port", "timeout", "become"].iter().map(|name| (name.to_string(), String::from(BUILT_IN))).collect(),
+        }
+    }
+}
+
+/// Printed by commands that find no hosts in the inventory.
+pub const NO_HOSTS: &str = "No hosts yet, add one with `rman host add <alias> <ip>` or run `rman init`.";
+
+/// Port of the ssh server unless `[defaults]`, a group or the host sets another.
+pub const DEFAULT_PORT: u16 = 22;
+
+/// Origin of the settings nothing in the configuration file sets.
+const BUILT_IN: &str = "default";
+
+/// Connection settings of a host, a group (`[groups.<name>]`) or every host (`[defaults]`).
+/// Unset settings are inherited, see `inherit`.
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub ssh_user: Option<String>,
+    pub pk_path: Option<String>,
+    pub port: Option<u16>,
+    pub timeout: Option<u64>,               // Seconds a run may take on a host.
+    pub become_user: Option<Option<String>>, // `Some(None)` turns become off where an outer level turned it on.
+}
+
+/// Settings shared by every host tagged with the group's name, from `[groups.<name>]`.
+#[derive(Clone, Default)]
+pub struct Group {
+    pub vars: BTreeMap<String, String>, // Variables the group's hosts inherit.
+    pub settings: Settings,             // Connection settings the group's hosts inherit.
 }
 
 /// Identical to `Host` except the fields should be given multiple `Host`'s concatenated together.
@@ -38,177 +117,488 @@ pub struct Hosts {
     pub ssh_users: String,       // User to attempt to connect to on remote machine.
     pub pk_paths: String,        // Path to the private key for the ssh connection.
     pub descriptions: String,    // Brief optional description of remote machine.
+    pub tags: String,            // Comma separated tags of each machine.
+    pub ports: String,           // Ports the machines set themselves.
+    pub timeouts: String,        // Timeouts the machines set themselves.
+    pub becomes: String,         // Become settings the machines set themselves.
 }
 
-/// This function handles all `$rman host` cli commands.
-pub fn base(args: std::vec::Vec<String>) {
-    if args.len() < 3 {
-        // If the user does not specify a particular command to execute then print the host help message.
-        help::host();
-    }
-    else {
-        // If the user specifies a command, execute that command
-        let cmd: &str = &args[2];
-        //println!("{}", cmd);
-        match cmd {                             // Run various commands based on user input...
-            "status" => host_status(args),          // host "status"
-            "add" => save_host_runner(args),    // host "add"
-            "del" => rm_host_runner(args),      // host "del"
-            "ls" => list_hosts(),               // host "ls"
-            "exec" => run_host_cmd(args),       // host "exec"
-            "reboot" => host_status(args),      // host "reboot"
-            "shutdown" => shutdown_host(args),  // host "shutdown"
-            _ => help::host()                   // If the user typed something wrong then display the host help message
+/// This function handles all `$rman host` cli commands. Part of the command line rather than the
+/// library, it prints errors and exits.
+#[doc(hidden)]
+pub fn base(command: HostCommand) {
+    match command {                             // Run various commands based on user input...
+        HostCommand::Status { targets } => host_status(&targets),
+        HostCommand::Add { alias, ip, ssh_user, pk_path, description, tags, port } => {
+            let own = Settings { ssh_user, pk_path, port, ..Settings::default() };
+            save_host_runner(&alias, &ip, own, description, tags)
+        }
+        HostCommand::Show { targets } => show_hosts(&targets),
+        HostCommand::Del { targets } => rm_host(&targets),
+        HostCommand::Ls(options) => listing::list_hosts(options),
+        HostCommand::Exec { targets, command } => run_host_cmd(&targets, &command.join(" ")),
+        HostCommand::Reboot { targets } => power_action("reboot", &targets, reboot),
+        HostCommand::Shutdown { targets } => power_action("shutdown", &targets, shutdown),
+        HostCommand::Facts { targets, fresh } => show_facts(&targets, fresh),
+        HostCommand::Tag { targets, tags } => tag_host(&targets, tags),
+        HostCommand::Var { targets, pairs } => var_host(&targets, pairs),
+        HostCommand::Template { targets, template } => template::run(targets_or_exit(&targets), template),
+        HostCommand::Module(module) => {
+            // Apply a module to the target hosts, e.g. `$rman host pkg web-* install nginx`.
+            let (targets, module) = module.split();
+            modules::run_cli(targets_or_exit(&targets), module);
         }
     }
 }
 
-/// Reboot the target host.
-fn reboot_host(args: std::vec::Vec<String>) {
-    if args.len() < 5 {
-        let host = get_host_by_alias(args[3].to_string());
-        reboot(&host);
-    } else {
-        help::host();
+/// Runs `reboot` or `shutdown` on every target host and records the outcome in the history.
+fn power_action(action: &str, spec: &str, run: fn(&Host) -> Result<ssh_con::CmdResult, String>) {
+    let hosts = targets_or_exit(spec);
+    if args::check_mode() {
+        all::preview(&hosts, &[ssh_con::PRIVS_CMD, if action == "reboot" { "shutdown -r" } else { "shutdown" }]);
+        return;
+    }
+    guard::confirm(&format!("run {}", action), &hosts);
+    let capture = history::capture_output();
+    let mut results = vec!();
+    for host in hosts.iter() {
+        match run(host) {
+            Ok(result) => results.push(history::HostResult::new(&host.alias, &result, capture)),
+            Err(err) => {
+                println!("{}: {}", host.alias, err);
+                results.push(history::HostResult::refused(&host.alias, &err));
+            }
+        }
     }
+    history::record(action, action, hosts.into_iter().map(|host| host.alias).collect(), results);
 }
 
-/// Shutdown the target host.
-fn shutdown_host(args: std::vec::Vec<String>) {
-    if args.len() < 5 {
-        let host = get_host_by_alias(args[3].to_string());
-        shutdown(&host);
-    } else {
-        help::host();
+/// Prints a thresholded health report of the target hosts, exiting with a non-zero status if any is critical.
+fn host_status(spec: &str) {
+    let thresholds = health::Thresholds::load();
+    let reports = health::reports(targets_or_exit(spec), thresholds);
+    for report in reports.iter() {
+        health::print_report(report);
     }
+    health::exit_on_critical(&reports);
 }
 
-/// Prints host uptime, disk usage, and various io statistics.
-fn host_status(args: std::vec::Vec<String>) {
-    if args.len() < 5 {
-        let host = get_host_by_alias(args[3].to_string());
-        println!("{}", execute_remote_command(&host, &String::from("uptime")));
-        println!("{}", execute_remote_command(&host, &String::from("df -h /")));
-        println!("{}", execute_remote_command(&host, &String::from("iostat | head -n 4")));
-    } else {
-        help::host();
+/// Prints the facts of the target hosts, gathering them first if `--fresh` is given or nothing is cached.
+fn show_facts(spec: &str, fresh: bool) {
+    for host in targets_or_exit(spec) {
+        println!("{}:", host.alias);
+        facts::print(&facts::get(&host, fresh));
+    }
+}
+
+/// Prints the settings and variables of the target hosts, marking where inherited ones came from.
+fn show_hosts(spec: &str) {
+    let groups = get_groups();
+    for host in targets_or_exit(spec) {
+        let mut rows = vec!(
+            (String::from("ip"), host.ip.clone(), None),
+            (String::from("ssh_user"), host.ssh_user.clone(), host.inherited.get("ssh_user").cloned()),
+            (String::from("pk_path"), host.pk_path.clone(), host.inherited.get("pk_path").cloned()),
+            (String::from("port"), host.port.to_string(), host.inherited.get("port").cloned()),
+            (String::from("timeout"), host.timeout.map(|timeout| format!("{}s", timeout)).unwrap_or_else(|| String::from("-")), host.inherited.get("timeout").cloned()),
+            (String::from("become"), host.become_user.clone().unwrap_or_else(|| String::from("-")), host.inherited.get("become").cloned()),
+            (String::from("description"), host.description.clone(), None),
+            (String::from("tags"), host.tags.join(", "), None),
+        );
+        for (name, value) in effective_vars(&host, &groups) {
+            // The last group in tag order wins, like in `effective_vars`.
+            let origin = match host.vars.contains_key(&name) {
+                true => None,
+                false => host.tags.iter().rev().find(|tag| groups.get(*tag).map(|group| group.vars.contains_key(&name)).unwrap_or(false)).map(|tag| format!("group {}", tag)),
+            };
+            rows.push((format!("vars.{}", name), value, origin));
+        }
+        println!("{}:", style::bold(&host.alias));
+        let width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+        let value_width = rows.iter().map(|(_, value, _)| value.len()).max().unwrap_or(0);
+        for (name, value, origin) in rows {
+            match origin {
+                Some(origin) if origin == BUILT_IN => println!("  {:<width$}  {:<value_width$}  (default)", name, value, width = width, value_width = value_width),
+                Some(origin) => println!("  {:<width$}  {:<value_width$}  (from {})", name, value, origin, width = width, value_width = value_width),
+                None => println!("  {:<width$}  {}", name, value, width = width),
+            }
+        }
     }
 }
 
-/// Run a command on the target host.
-fn run_host_cmd(args: std::vec::Vec<String>) {
-    // Assemble command
-    let mut cmd = String::new();
-    if args.len() < 4 {
-        help::host();
+/// Run a command on the target hosts.
+fn run_host_cmd(spec: &str, cmd: &str) {
+    let hosts = targets_or_exit(spec);
+    if args::check_mode() {
+        all::preview(&hosts, &[cmd]);
+        return;
+    }
+    let commands = all::render_commands(&hosts, cmd);
+    guard::confirm_command("run", cmd, &hosts, &commands);
+    let targets = hosts.iter().map(|host| host.alias.clone()).collect();
+    let results = if hosts.len() == 1 {
+        let result = ssh_con::run_remote_command(&hosts[0], &commands[0]);
+        println!("{}", result.output);
+        vec!(HostOutcome { alias: hosts[0].alias.clone(), result })
     } else {
-        for i in 4..args.len() {
-            cmd.push_str(format!("{} ", args[i]).as_str());
+        all::run_commands(hosts, &commands)
+    };
+    history::record("host exec", cmd, targets, history::results(&results));
+}
+
+/// Replaces the tags of the target hosts with the comma separated list given, or clears them if none is given.
+fn tag_host(spec: &str, tags: Option<String>) {
+    let (mut inventory, targets) = match load_targets(spec) {
+        Some(loaded) => loaded,
+        None => return,
+    };
+    let tags = parse_tags(tags.as_deref().unwrap_or(""));
+    for target in targets.iter() {
+        if let Some(host) = inventory.get_mut(&target.alias) {
+            host.tags = tags.clone();
         }
-        println!("{}", ssh_con::execute_remote_command(&get_host_by_alias((args[3]).parse().unwrap()), &cmd))
+    }
+    match inventory.save() {
+        Ok(_) => history::record("host tag", &format!("tags = {}", tags.join(",")), targets.into_iter().map(|host| host.alias).collect(), vec!()),
+        Err(err) => println!("{}", err),
     }
 }
 
-/// Lists hosts
-fn list_hosts() {
-    for host in get_hosts().iter() {
-        println!("Host Alias : {}\nHost IP : {}\nHost SSHUser : {}\nHost PK Path : {}\nHost Desc. : {}\n", host.alias, host.ip, host.ssh_user, host.pk_path, host.description);
+/// Sets (`key=value`) or removes (`key=`) variables of the target hosts, or prints their variables if none are given.
+fn var_host(spec: &str, pairs: std::vec::Vec<String>) {
+    let (mut inventory, targets) = match load_targets(spec) {
+        Some(loaded) => loaded,
+        None => return,
+    };
+    if pairs.is_empty() {
+        for target in targets.iter() {
+            println!("{}:", target.alias);
+            for (key, value) in inventory.vars(target) {
+                let inherited = if target.vars.contains_key(&key) { "" } else { "\t(group)" };
+                println!("    {} = {}{}", key, value, inherited);
+            }
+        }
+        return;
     }
+    let mut changes = vec!();
+    for pair in pairs.iter() {
+        match pair.find('=') {
+            Some(eq) if eq > 0 => changes.push((pair[..eq].to_string(), pair[eq + 1..].to_string())),
+            _ => {
+                println!("Expected key=value, got '{}'", pair);
+                return;
+            }
+        }
+    }
+    for target in targets.iter() {
+        let host = match inventory.get_mut(&target.alias) {
+            Some(host) => host,
+            None => continue,
+        };
+        for (key, value) in changes.iter() {
+            if value.is_empty() {
+                host.vars.remove(key);
+            } else {
+                host.vars.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    match inventory.save() {
+        Ok(_) => history::record("host var", &pairs.join(" "), targets.into_iter().map(|host| host.alias).collect(), vec!()),
+        Err(err) => println!("{}", err),
+    }
+}
+
+/// Splits a comma separated tag list, dropping empty entries.
+pub fn parse_tags(tags: &str) -> std::vec::Vec<String> {
+    tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(String::from).collect()
 }
 
-/// Parse args and pass to `host::rm_host()`
-fn rm_host_runner(args: std::vec::Vec<String>) {
-    // If the user has specified an alias to be removed then run the rm_host function with that alias. Else, display host help.
-    match args.len() {
-        4 => rm_host(String::from(&args[3])),
-        _ => help::host(),
+/// Removes the target hosts from the configuration file.
+fn rm_host(spec: &str) {
+    let (mut inventory, targets) = match load_targets(spec) {
+        Some(loaded) => loaded,
+        None => return,
+    };
+    for target in targets.iter() {
+        inventory.remove(&target.alias);
+    }
+    match inventory.save() {
+        Ok(_) => {
+            let verb = if args::check_mode() { "Would remove" } else { "Removed" };
+            for target in targets.iter() {
+                println!("{} {}", verb, target.alias);
+            }
+            history::record("host del", spec, targets.into_iter().map(|host| host.alias).collect(), vec!());
+        }
+        Err(err) => println!("{}", err),
     }
 }
 
-/// Removes the target host from the configuration file.
-fn rm_host(rm_alias: String) {
-    let mut hosts = get_hosts();
-    let mut rm_indice:i32 = -1;
-    for i in 0..hosts.len() {
-        if hosts[i].alias == rm_alias {
-            rm_indice = i as i32;
+/// Loads the inventory along with the hosts matching `spec`, printing why if that fails.
+fn load_targets(spec: &str) -> Option<(Inventory, std::vec::Vec<Host>)> {
+    let inventory = match Inventory::load() {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            println!("{}", err);
+            return None;
+        }
+    };
+    match inventory.select(spec) {
+        Ok(targets) => Some((inventory, targets)),
+        Err(err) => {
+            println!("{}", err);
+            None
         }
     }
-    if rm_indice == -1 {
-        println!("Host not found");
-    } else {
-        hosts.remove(rm_indice as usize);
-        println!("{:?}", try_save(hosts));
-    }
-}
-
-/// Parse args and pass to `host::save_host()`
-fn save_host_runner(args: std::vec::Vec<String>) {
-    // Check to make sure the user has specified all of the required fields.
-    if args.len() < 6 {
-        // Display the host help
-        help::base(args);
-    }
-    else {
-        // If optional description is specified...
-        if args.len() > 7 {
-            // Use a description.
-            save_host(Host {
-                alias: String::from(&args[3]),
-                ip: String::from(&args[4]),
-                ssh_user: String::from(&args[5]),
-                pk_path: String::from(&args[6]),
-                description: String::from(&args[7]),
-            });
-        } else {
-            // Else use blank description.
-            save_host(Host {
-                alias: String::from(&args[3]),
-                ip: String::from(&args[4]),
-                ssh_user: String::from(&args[5]),
-                pk_path: String::from(&args[6]),
-                description: String::from(""),
-            });
+}
+
+/// Expands the hosts to add and passes them to `host::save_hosts()`
+/// Bracket ranges in the alias and ip are expanded in pairs, so `web[01:20] web[01:20].example.com` adds twenty hosts.
+/// Settings that aren't given are inherited from the groups named by the tags and from `[defaults]`.
+fn save_host_runner(alias: &str, ip: &str, own: Settings, description: Option<String>, tags: Option<String>) {
+    let tags = parse_tags(tags.as_deref().unwrap_or(""));
+    let (aliases, ips) = match (targets::expand(alias), targets::expand(ip)) {
+        (Ok(aliases), Ok(ips)) => (aliases, ips),
+        (Err(err), _) | (_, Err(err)) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    if aliases.len() != ips.len() && ips.len() != 1 {
+        println!("The alias expands to {} hosts but the ip expands to {}", aliases.len(), ips.len());
+        return;
+    }
+    let (defaults, groups) = (read_defaults(&config_path()), get_groups());
+    let mut to_save = vec!();
+    for (i, alias) in aliases.into_iter().enumerate() {
+        let mut host = Host::new(&alias, ips.get(i).unwrap_or(&ips[0]), "", "");
+        // Use the optional description if one is specified, else a blank one.
+        host.description = description.clone().unwrap_or_default();
+        host.tags = tags.clone();
+        inherit(&mut host, &own, &defaults, &groups);
+        to_save.push(host);
+    }
+    for (name, missing) in [("ssh_user", to_save[0].ssh_user.is_empty()), ("pk_path", to_save[0].pk_path.is_empty())] {
+        if missing {
+            println!("No {} given and none set in [defaults] or the groups of the host", name);
+            return;
+        }
+    }
+    save_hosts(to_save);
+}
+
+/// This function writes `Host`s into the config file
+fn save_hosts(to_save: std::vec::Vec<Host>) {
+    let mut inventory = match Inventory::load() {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+
+    // Hosts that already exist are skipped rather than saved again.
+    let mut added = vec!();
+    for host in to_save {
+        let line = format!("{} {} {} {}", host.alias, host.ip, host.ssh_user, host.pk_path);
+        match inventory.add(host) {
+            Ok(_) => added.push(line),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    // Save the host configuration
+    match inventory.save() {
+        Ok(_) => {
+            let aliases = added.iter().filter_map(|line| line.split(' ').next()).map(String::from).collect();
+            history::record("host add", &added.join("; "), aliases, vec!());
         }
+        Err(err) => println!("{}", err),
     }
 }
 
-/// This function writes a `Host` into the config file
-fn save_host(to_save: Host) {
-    // Load config into mem in the form of Vec<Host>
-    let mut configuration = get_hosts();
+/// Writes the hosts into the configuration file at `path`, which has to still read `expected`.
+/// Only the host lists are rewritten, other sections such as `[thresholds]` are kept as they are.
+/// With `--check` the difference to the current file is printed instead.
+pub(crate) fn write_hosts(path: &Path, configuration: &[Host], expected: &str) -> std::io::Result<()> {
+    // Keep everything but the host lists from the existing file.
+    write_inventory(path, read_document(path), configuration, expected)
+}
 
-    // Check to see if the host already exists before saving it again.
-    let mut exists: bool = false;
-    for host in configuration.iter() {
-        if to_save.alias == host.alias {
-            exists = true;
+/// Writes the hosts into `document` and the result into the configuration file at `path`, see
+/// `store::save`. Only prints the difference to the current file with `--check`.
+pub(crate) fn write_inventory(path: &Path, mut document: toml::value::Table, configuration: &[Host], expected: &str) -> std::io::Result<()> {
+    // Write bundled host values into the file...
+    let hosts = bundle_hosts(configuration.to_vec());
+    document.insert(String::from("alias"), toml::Value::String(hosts.aliases));
+    document.insert(String::from("ip"), toml::Value::String(hosts.ips));
+    document.insert(String::from("ssh_user"), toml::Value::String(hosts.ssh_users));
+    document.insert(String::from("pk_path"), toml::Value::String(hosts.pk_paths));
+    document.insert(String::from("description"), toml::Value::String(hosts.descriptions));
+    document.insert(String::from("tags"), toml::Value::String(hosts.tags));
+    // Lists of settings no host sets itself are left out.
+    for (key, values) in [("port", hosts.ports), ("timeout", hosts.timeouts), ("become", hosts.becomes)] {
+        if values.chars().all(|c| c == '|') {
+            document.remove(key);
+        } else {
+            document.insert(String::from(key), toml::Value::String(values));
         }
     }
-    if !exists {
-        configuration.push(to_save);
+    // Host variables live in `[vars.<alias>]` tables, which are dropped along with their host.
+    let vars: toml::value::Table = configuration.iter()
+        .filter(|host| !host.vars.is_empty())
+        .map(|host| (host.alias.clone(), toml::Value::Table(host.vars.iter().map(|(key, value)| (key.clone(), toml::Value::String(value.clone()))).collect())))
+        .collect();
+    if vars.is_empty() {
+        document.remove("vars");
     } else {
-        println!("Host already in configuration file!");
+        document.insert(String::from("vars"), toml::Value::Table(vars));
+    }
+    // Serialized as a value, which puts plain keys before tables such as `[defaults]`.
+    let contents = match toml::to_string(&toml::Value::Table(document)) {
+        Ok(contents) => contents,
+        Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+    };
+    // In check mode the change is only shown.
+    if args::check_mode() {
+        let current = std::fs::read_to_string(path).unwrap_or_default();
+        let name = path.display().to_string();
+        match diff::unified(&current, &contents, &name, &name).as_str() {
+            "" => println!("Check mode, the inventory would not change"),
+            diff => println!("Check mode, the inventory would change:\n{}", diff),
+        }
+        return Ok(());
     }
+    store::save(path, &contents, expected)
+}
 
-    // Save the host configuration
-    try_save(configuration);
+/// Reads a configuration file as a TOML table, which is empty if it is missing or invalid.
+fn read_document(path: &Path) -> toml::value::Table {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents.parse::<toml::Value>().ok().and_then(|value| value.as_table().cloned()).unwrap_or_default(),
+        Err(_) => toml::value::Table::new(),
+    }
 }
 
-/// Attempt to save the configuration file.
-fn try_save(configuration: std::vec::Vec<Host>) -> std::io::Result<()>{
-    let mut path = match dirs::home_dir() {
-        Some(buf) => buf,
-        _ => panic!("Error getting home directory"),
+/// Converts a TOML table of variables into strings, so numbers and booleans can be written unquoted.
+fn to_vars(table: Option<&toml::Value>) -> BTreeMap<String, String> {
+    let table = match table.and_then(toml::Value::as_table) {
+        Some(table) => table,
+        None => return BTreeMap::new(),
     };
-    path.push(Path::new(".config/rman/rman.toml"));
-    // Open the config file.
-    let mut save_file = File::create(path)?;
-    // Write bundled host values into the file...
-    let hosts = bundle_hosts(configuration);
-    save_file.write_all(format!("alias = '{}'\nip = '{}'\nssh_user = '{}'\npk_path = '{}'\ndescription = '{}'", hosts.aliases, hosts.ips, hosts.ssh_users, hosts.pk_paths, hosts.descriptions).into_bytes().as_ref())?;
-    save_file.sync_data()?;
-    Ok(())
+    table.iter().map(|(key, value)| {
+        let value = match value {
+            toml::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        (key.clone(), value)
+    }).collect()
+}
+
+/// Loads the `[groups.<name>]` sections of the configuration file.
+/// # Examples
+/// ```no_run
+/// use rman::host::{get_groups, Group};
+/// use std::collections::BTreeMap;
+///
+/// let groups: BTreeMap<String, Group> = get_groups();
+/// ```
+pub fn get_groups() -> BTreeMap<String, Group> {
+    read_groups(&config_path())
+}
+
+/// Loads the `[groups.<name>]` sections of the configuration file at `path`.
+pub(crate) fn read_groups(path: &Path) -> BTreeMap<String, Group> {
+    let document = read_document(path);
+    let groups = match document.get("groups").and_then(toml::Value::as_table) {
+        Some(groups) => groups,
+        None => return BTreeMap::new(),
+    };
+    groups.iter().map(|(name, group)| (name.clone(), Group { vars: to_vars(group.get("vars")), settings: to_settings(Some(group)) })).collect()
+}
+
+/// Loads the `[defaults]` section of the configuration file at `path`.
+pub(crate) fn read_defaults(path: &Path) -> Settings {
+    to_settings(read_document(path).get("defaults"))
+}
+
+/// Reads the connection settings of a `[defaults]` or `[groups.<name>]` table.
+fn to_settings(table: Option<&toml::Value>) -> Settings {
+    let table = match table.and_then(toml::Value::as_table) {
+        Some(table) => table,
+        None => return Settings::default(),
+    };
+    let text = |key: &str| table.get(key).and_then(toml::Value::as_str).map(String::from);
+    let number = |key: &str| table.get(key).and_then(toml::Value::as_integer);
+    Settings {
+        ssh_user: text("ssh_user"),
+        pk_path: text("pk_path"),
+        port: number("port").and_then(|port| u16::try_from(port).ok()),
+        timeout: number("timeout").and_then(|timeout| u64::try_from(timeout).ok()),
+        become_user: table.get("become").and_then(|value| match value {
+            toml::Value::String(user) => parse_become(user),
+            other => parse_become(&other.to_string()),
+        }),
+    }
+}
+
+/// Parses a become setting: `true` becomes root, `false` turns become off, anything else names
+/// the user to become. Empty means unset.
+pub(crate) fn parse_become(value: &str) -> Option<Option<String>> {
+    match value.trim() {
+        "" => None,
+        "false" => Some(None),
+        "true" => Some(Some(String::from("root"))),
+        user => Some(Some(user.to_string())),
+    }
+}
+
+/// Completes the settings a host doesn't set itself from its groups, the later tags taking
+/// precedence like for variables, and then from `[defaults]`. Inherited settings are recorded
+/// in `Host::inherited`, so they aren't written back as the host's own.
+pub fn inherit(host: &mut Host, own: &Settings, defaults: &Settings, groups: &BTreeMap<String, Group>) {
+    // Levels from the most to the least specific, the host itself first.
+    let mut levels: std::vec::Vec<(String, &Settings)> = vec!((String::new(), own));
+    levels.extend(host.tags.iter().rev().filter_map(|tag| groups.get(tag).map(|group| (format!("group {}", tag), &group.settings))));
+    levels.push((String::from("[defaults]"), defaults));
+
+    let mut inherited = BTreeMap::new();
+    host.ssh_user = setting(&levels, "ssh_user", |settings| settings.ssh_user.clone(), &mut inherited).unwrap_or_default();
+    host.pk_path = setting(&levels, "pk_path", |settings| settings.pk_path.clone(), &mut inherited).unwrap_or_default();
+    host.port = setting(&levels, "port", |settings| settings.port, &mut inherited).unwrap_or(DEFAULT_PORT);
+    host.timeout = setting(&levels, "timeout", |settings| settings.timeout, &mut inherited);
+    host.become_user = setting(&levels, "become", |settings| settings.become_user.clone(), &mut inherited).flatten();
+    host.inherited = inherited;
+}
+
+/// The value of the first level setting it, recording where it came from unless that is the host itself.
+fn setting<T, F: Fn(&Settings) -> Option<T>>(levels: &[(String, &Settings)], name: &str, get: F, inherited: &mut BTreeMap<String, String>) -> Option<T> {
+    for (i, (origin, settings)) in levels.iter().enumerate() {
+        if let Some(value) = get(settings) {
+            if i > 0 {
+                inherited.insert(name.to_string(), origin.clone());
+            }
+            return Some(value);
+        }
+    }
+    inherited.insert(name.to_string(), String::from(BUILT_IN));
+    None
+}
+
+/// Variables of a host: those of its groups, in the order of its tags, overridden by its own.
+pub fn effective_vars(host: &Host, groups: &BTreeMap<String, Group>) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    for group in host.tags.iter().filter_map(|tag| groups.get(tag)) {
+        vars.extend(group.vars.clone());
+    }
+    vars.extend(host.vars.clone());
+    vars
+}
+
+/// Path of the rman configuration file in use, see `context` for how it is chosen.
+pub fn config_path() -> PathBuf {
+    crate::context::config_path()
 }
 
 /// Bundles hosts together into one `Hosts`
@@ -218,105 +608,160 @@ fn bundle_hosts(to_hosts: std::vec::Vec<Host>) -> Hosts {
     let mut ssh_users = String::from("");
     let mut pk_paths = String::from("");
     let mut descriptions = String::from("");
+    let mut tags = String::from("");
+    let mut ports = String::from("");
+    let mut timeouts = String::from("");
+    let mut becomes = String::from("");
 
     // Bundle all host fields into one string using the pipe character as the delimiter.
+    // Inherited settings are left empty, so they follow `[defaults]` and the groups.
     for host in to_hosts.iter() {
         aliases.push_str(format!("{}{}",host.clone().alias.as_str(), "|").as_str());
         ips.push_str(format!("{}{}",host.clone().ip.as_str(), "|").as_str());
-        ssh_users.push_str(format!("{}{}",host.clone().ssh_user.as_str(), "|").as_str());
-        pk_paths.push_str(format!("{}{}",host.clone().pk_path.as_str(), "|").as_str());
+        ssh_users.push_str(format!("{}{}", own_value(host, "ssh_user", host.ssh_user.clone()), "|").as_str());
+        pk_paths.push_str(format!("{}{}", own_value(host, "pk_path", host.pk_path.clone()), "|").as_str());
         descriptions.push_str(format!("{}{}",host.clone().description.as_str(), "|").as_str());
+        tags.push_str(format!("{}{}", host.tags.join(","), "|").as_str());
+        ports.push_str(format!("{}{}", own_value(host, "port", host.port.to_string()), "|").as_str());
+        timeouts.push_str(format!("{}{}", own_value(host, "timeout", host.timeout.map(|timeout| timeout.to_string()).unwrap_or_default()), "|").as_str());
+        becomes.push_str(format!("{}{}", own_value(host, "become", host.become_user.clone().unwrap_or_else(|| String::from("false"))), "|").as_str());
     }
 
-    // Remove trailing pipe from fields
-    aliases = aliases[0..aliases.len() - 1].parse().unwrap();
-    ips = ips[0..ips.len() - 1].parse().unwrap();
-    ssh_users = ssh_users[0..ssh_users.len() - 1].parse().unwrap();
-    pk_paths = pk_paths[0..pk_paths.len() - 1].parse().unwrap();
-    descriptions = descriptions[0..descriptions.len() - 1].parse().unwrap();
+    // Remove trailing pipe from fields, which are left empty if there are no hosts.
+    for field in [&mut aliases, &mut ips, &mut ssh_users, &mut pk_paths, &mut descriptions, &mut tags, &mut ports, &mut timeouts, &mut becomes] {
+        field.pop();
+    }
 
     Hosts{
         aliases,
         ips,
         ssh_users,
         pk_paths,
-        descriptions
+        descriptions,
+        tags,
+        ports,
+        timeouts,
+        becomes
     }
 }
 
-/// Loads hosts from the config file and into a `Vec<Host>`
-/// # Examples
-/// let hosts_as_vec std::vec::Vec<Host> = get_hosts();
-pub fn get_hosts() -> std::vec::Vec<Host> {
-    match try_get_hosts() {
-        Ok(result) => result,
-        Err(err) => create_cfg()
+/// The value of a setting as the host's own, empty if the host inherits it.
+fn own_value(host: &Host, name: &str, value: String) -> String {
+    if host.inherited.contains_key(name) {
+        String::new()
+    } else {
+        value
     }
 }
 
-/// Attempts to get hosts from the configuration file.
-fn try_get_hosts() -> Result<std::vec::Vec<Host>, Box<dyn Error>> {
+/// Loads hosts from the config file and into a `Vec<Host>`, followed by those of its dynamic
+/// sources, see `sources`. Empty if there is no configuration file yet, an error if the file
+/// can't be read.
+/// # Examples
+/// ```no_run
+/// use rman::host::{get_hosts, Host};
+///
+/// let hosts_as_vec: std::vec::Vec<Host> = get_hosts()?;
+/// # Ok::<(), String>(())
+/// ```
+pub fn get_hosts() -> Result<std::vec::Vec<Host>, String> {
+    let path = config_path();
+    let mut result = read_hosts(&path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+    sources::merge(&path, &mut result, false);
+    Ok(result)
+}
+
+/// Attempts to get hosts from the configuration file at `path`, failing if it has errors, see `validate`.
+/// A missing file or one without hosts is an empty inventory.
+pub(crate) fn read_hosts(path: &Path) -> Result<std::vec::Vec<Host>, Box<dyn Error>> {
+    validate::errors(path)?;
+    parse_hosts(path)
+}
+
+/// Reads the hosts of the configuration file at `path` without validating it first.
+pub(crate) fn parse_hosts(path: &Path) -> Result<std::vec::Vec<Host>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(vec!());
+    }
     let mut settings = Config::new();
-    let mut path = match dirs::home_dir() {
-        Some(buf) => buf,
-        _ => panic!("Error"),
-    };
-    path.push(Path::new(".config/rman/rman.toml"));
-    settings.merge(config::File::from(path));
+    settings.merge(config::File::from(path))?;
 
-    let aliases: std::vec::Vec<String> = to_string_vec(settings.get::<String>("alias")?.split("|").collect());
+    let aliases = match settings.get::<String>("alias") {
+        Ok(aliases) => aliases,
+        Err(ConfigError::NotFound(_)) => return Ok(vec!()),
+        Err(err) => return Err(err.into()),
+    };
+    if aliases.is_empty() {
+        return Ok(vec!());
+    }
+    let aliases: std::vec::Vec<String> = to_string_vec(aliases.split("|").collect());
     let ips: std::vec::Vec<String> = to_string_vec(settings.get::<String>("ip")?.split("|").collect());
-    let users: std::vec::Vec<String> = to_string_vec(settings.get::<String>("ssh_user")?.split("|").collect());
-    let pkpaths: std::vec::Vec<String> = to_string_vec(settings.get::<String>("pk_path")?.split("|").collect());
-    let descs: std::vec::Vec<String> = to_string_vec(settings.get::<String>("description")?.split("|").collect());
+    // Users, keys and descriptions may all be left to `[defaults]` and the groups.
+    let users: std::vec::Vec<String> = to_string_vec(settings.get::<String>("ssh_user").unwrap_or_default().split("|").collect());
+    let pkpaths: std::vec::Vec<String> = to_string_vec(settings.get::<String>("pk_path").unwrap_or_default().split("|").collect());
+    let descs: std::vec::Vec<String> = to_string_vec(settings.get::<String>("description").unwrap_or_default().split("|").collect());
+    // Tags were added later, so older configuration files may not have them.
+    let tags: std::vec::Vec<String> = to_string_vec(settings.get::<String>("tags").unwrap_or_default().split("|").collect());
+    // Variables are read with toml, as `Config` lowercases keys such as the aliases.
+    let document = read_document(path);
+    let vars = document.get("vars");
+
+    // Ports, timeouts and become settings are optional, empty values are inherited.
+    let ports: std::vec::Vec<String> = to_string_vec(settings.get::<String>("port").unwrap_or_default().split("|").collect());
+    let timeouts: std::vec::Vec<String> = to_string_vec(settings.get::<String>("timeout").unwrap_or_default().split("|").collect());
+    let becomes: std::vec::Vec<String> = to_string_vec(settings.get::<String>("become").unwrap_or_default().split("|").collect());
+    let defaults = read_defaults(path);
+    let groups = read_groups(path);
 
     let mut r_hosts: std::vec::Vec<Host> = vec!();
-    for i in 0..aliases.len() {
-        r_hosts.push(Host {
-            alias: String::from(aliases[i].clone()),
-            ip: String::from(ips[i].clone()),
-            ssh_user: String::from(users[i].clone()),
-            pk_path: String::from(pkpaths[i].clone()),
-            description: String::from(descs[i].clone()),
-        });
+    for (i, alias) in aliases.iter().enumerate() {
+        // Values are looked up leniently, `validate` reports lists of the wrong length.
+        let own = Settings {
+            ssh_user: users.get(i).cloned().filter(|user| !user.is_empty()),
+            pk_path: pkpaths.get(i).cloned().filter(|path| !path.is_empty()),
+            port: ports.get(i).and_then(|port| port.parse().ok()),
+            timeout: timeouts.get(i).and_then(|timeout| timeout.parse().ok()),
+            become_user: becomes.get(i).and_then(|value| parse_become(value)),
+        };
+        let mut host = Host::new(alias, ips.get(i).map(String::as_str).unwrap_or(""), "", "");
+        host.description = descs.get(i).cloned().unwrap_or_default();
+        host.tags = parse_tags(tags.get(i).map(String::as_str).unwrap_or(""));
+        host.vars = to_vars(vars.and_then(|vars| vars.get(alias)));
+        inherit(&mut host, &own, &defaults, &groups);
+        r_hosts.push(host);
     }
+    // Older versions wrote a placeholder host into new configuration files, which is dropped
+    // here and so disappears with the next save.
+    r_hosts.retain(|host| !(host.alias == "filler" && host.ip == "filler"));
 
     Ok(r_hosts)
 }
 
-/// Gets a singular host by alias
+/// Resolves a target specification such as `web-*` or `web[01:12]` against the inventory.
+/// Fails if the inventory can't be read or the specification doesn't match any host.
 /// # Examples
-/// let localhost: Host = get_host_by_alias(String::from("localhost"));
-fn get_host_by_alias(alias: String) -> Host {
-    let hosts = get_hosts();
-    match hosts.into_iter().find(|host| host.alias == alias) {
-        Some(host) => host,
-        None => panic!("host not found.")
+/// ```no_run
+/// use rman::host::{resolve_targets, Host};
+///
+/// let web_hosts: Vec<Host> = resolve_targets("web-*")?;
+/// # Ok::<(), String>(())
+/// ```
+pub fn resolve_targets(spec: &str) -> Result<std::vec::Vec<Host>, String> {
+    targets::resolve(spec, &get_hosts()?)
+}
+
+/// Resolves the target hosts of a command, printing the error and exiting if that fails.
+fn targets_or_exit(spec: &str) -> std::vec::Vec<Host> {
+    match resolve_targets(spec) {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
     }
 }
 
 /// Converts a vec<str> to a vec<String>
 fn to_string_vec(as_an_str: std::vec::Vec<&str>) -> std::vec::Vec<String>  {
-    as_an_str.into_iter().map(|elem| String::from(elem)).collect()
-}
-
-/// Creates a configuration file with filler host to prevent confy errors.
-fn create_cfg() -> std::vec::Vec<Host> {
-    match try_save(vec!(Host {
-        alias: "filler".to_string(),
-        ip: "filler".to_string(),
-        ssh_user: "filler".to_string(),
-        pk_path: "filler".to_string(),
-        description: "filler".to_string()
-    })) {
-        Ok(_) => println!("New configuration file created."),
-        Err(_) => panic!("Unable to create a configuration file.")
-    }
-    vec!(Host {
-        alias: "filler".to_string(),
-        ip: "filler".to_string(),
-        ssh_user: "filler".to_string(),
-        pk_path: "filler".to_string(),
-        description: "filler".to_string()
-    })
-}
\ No newline at end of file
+    as_an_str.into_iter().map(String::from).collect()
+}
@@ -0,0 +1,108 @@
+//! Provides `$rman init`, which creates the configuration file on first use.
+//!
+//! The ssh user and key most hosts share are written to `[defaults]`, so hosts can then be added
+//! with just an alias and an address. On a terminal the wizard asks for them, suggesting the
+//! current user and the first key found in `~/.ssh`, and for a first few hosts. Otherwise only
+//! the values given as flags are used, which still leaves a valid, empty inventory.
+
+use crate::args;
+use crate::history;
+use crate::host::{self, Host, Settings};
+use crate::validate;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+
+/// Keys suggested as the default, in order of preference.
+const KEYS: [&str; 3] = [".ssh/id_ed25519", ".ssh/id_ecdsa", ".ssh/id_rsa"];
+
+/// This function handles `$rman init`.
+pub fn base(ssh_user: Option<String>, pk_path: Option<String>) {
+    let path = host::config_path();
+    if path.exists() {
+        println!("{} already exists, add hosts with `rman host add <alias> <ip>`", path.display());
+        return;
+    }
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        println!("Creating {}, press enter to accept the suggestions in brackets.", path.display());
+    }
+    let ssh_user = ssh_user.or_else(|| ask(interactive, "ssh user most hosts share", std::env::var("USER").ok()));
+    let pk_path = pk_path.or_else(|| ask(interactive, "Private key most hosts share", default_key()));
+
+    let mut defaults = toml::value::Table::new();
+    for (key, value) in [("ssh_user", &ssh_user), ("pk_path", &pk_path)] {
+        if let Some(value) = value {
+            defaults.insert(String::from(key), toml::Value::String(value.clone()));
+        }
+    }
+    let settings = Settings { ssh_user, pk_path, ..Settings::default() };
+
+    // Hosts are only asked for on a terminal, `rman host add` takes them later as well.
+    let mut hosts: std::vec::Vec<Host> = vec!();
+    while let Some(alias) = ask(interactive, "Alias of a host to add, empty to finish", None) {
+        if hosts.iter().any(|host| host.alias == alias) {
+            println!("Host {} was already added", alias);
+            continue;
+        }
+        let ip = match ask(true, &format!("IP address or host name of {}", alias), None) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let mut host = Host::new(&alias, &ip, "", "");
+        host::inherit(&mut host, &Settings::default(), &settings, &BTreeMap::new());
+        if let Err(err) = validate::check_host(&host) {
+            println!("{}", err);
+            continue;
+        }
+        hosts.push(host);
+    }
+
+    let mut document = toml::value::Table::new();
+    if !defaults.is_empty() {
+        document.insert(String::from("defaults"), toml::Value::Table(defaults));
+    }
+    if !args::check_mode() {
+        if let Some(dir) = path.parent() {
+            if let Err(err) = std::fs::create_dir_all(dir) {
+                println!("Unable to create {}: {}", dir.display(), err);
+                return;
+            }
+        }
+    }
+    // Expecting an empty file fails if another rman created it meanwhile.
+    if let Err(err) = host::write_inventory(&path, document, &hosts, "") {
+        println!("Unable to save {}: {}", path.display(), err);
+        return;
+    }
+    if !args::check_mode() {
+        history::record("init", &path.display().to_string(), hosts.iter().map(|host| host.alias.clone()).collect(), vec!());
+        println!("Created {} with {} hosts, add more with `rman host add <alias> <ip>`", path.display(), hosts.len());
+    }
+}
+
+/// Asks a question on the terminal, returning the answer or the suggestion if it was left empty.
+/// Without a terminal the suggestion isn't used, nothing is guessed.
+fn ask(interactive: bool, question: &str, suggestion: Option<String>) -> Option<String> {
+    if !interactive {
+        return None;
+    }
+    match suggestion {
+        Some(ref suggestion) => print!("{} [{}]: ", question, suggestion),
+        None => print!("{}: ", question),
+    }
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return None;
+    }
+    match answer.trim() {
+        "" => suggestion,
+        answer => Some(answer.to_string()),
+    }
+}
+
+/// The first of the usual private keys that exists.
+fn default_key() -> Option<String> {
+    let home = dirs::home_dir()?;
+    KEYS.iter().map(|key| home.join(key)).find(|key| key.exists()).map(|key| key.display().to_string())
+}
@@ -0,0 +1,220 @@
+//! Provides `Inventory`, the hosts and groups of an rman configuration file.
+//!
+//! Changes made through an `Inventory` are kept in memory until `save` writes them back. Other
+//! sections of the file, such as `[thresholds]` or `[groups]`, are preserved.
+//!
+//! Hosts of the file's dynamic sources, see `sources`, are loaded along with it and read-only:
+//! `all_hosts`, `select` and `filter` see them like `rman all exec` does, while `hosts`, `get`,
+//! the methods changing hosts and `save` only deal with the hosts of the file itself.
+
+use crate::args;
+use crate::filter;
+use crate::host::{self, Group, Host};
+use crate::sources;
+use crate::targets;
+use crate::validate;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The hosts of one configuration file.
+/// # Examples
+/// ```
+/// use rman::{Host, Inventory};
+///
+/// let path = std::env::temp_dir().join("rman-inventory-example.toml");
+/// let mut inventory = Inventory::load_from(&path).unwrap();
+/// inventory.add(Host::new("web01", "10.0.0.5", "root", "~/.ssh/web.pem")).unwrap();
+/// inventory.add(Host::new("web02", "10.0.0.6", "root", "~/.ssh/web.pem")).unwrap();
+/// inventory.save().unwrap();
+///
+/// let reloaded = Inventory::load_from(&path).unwrap();
+/// assert_eq!(reloaded.select("web*").unwrap().len(), 2);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct Inventory {
+    path: PathBuf,                          // The configuration file the hosts were loaded from.
+    hosts: std::vec::Vec<Host>,             // Hosts in the order of the file.
+    dynamic: std::vec::Vec<Host>,           // Hosts of the dynamic sources, never saved.
+    groups: BTreeMap<String, Group>,        // `[groups.<name>]` sections, by name.
+    loaded: String,                         // Contents of the file when it was read, empty if it was missing.
+}
+
+impl Inventory {
+    /// Loads the inventory of the configuration file in use: `$RMAN_CONFIG`, that of the current
+    /// context or `~/.config/rman/rman.toml`.
+    pub fn load() -> Result<Inventory, String> {
+        Inventory::load_from(host::config_path())
+    }
+
+    /// Loads the inventory of a configuration file. A missing file is an empty inventory.
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Inventory, String> {
+        let path = path.as_ref().to_path_buf();
+        let loaded = std::fs::read_to_string(&path).unwrap_or_default();
+        let hosts = host::read_hosts(&path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+        let groups = host::read_groups(&path);
+        let mut merged = hosts.clone();
+        sources::merge(&path, &mut merged, false);
+        let dynamic = merged.split_off(hosts.len());
+        Ok(Inventory { path, hosts, dynamic, groups, loaded })
+    }
+
+    /// The configuration file of the inventory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every host of the configuration file, in its order.
+    pub fn hosts(&self) -> &[Host] {
+        &self.hosts
+    }
+
+    /// Every host of the configuration file followed by those of its dynamic sources, whose
+    /// aliases the file doesn't use.
+    pub fn all_hosts(&self) -> std::vec::Vec<Host> {
+        let dynamic = self.dynamic.iter().filter(|host| self.get(&host.alias).is_none());
+        self.hosts.iter().chain(dynamic).cloned().collect()
+    }
+
+    /// The groups hosts inherit variables from, by name.
+    pub fn groups(&self) -> &BTreeMap<String, Group> {
+        &self.groups
+    }
+
+    /// The host of the configuration file with the given alias.
+    pub fn get(&self, alias: &str) -> Option<&Host> {
+        self.hosts.iter().find(|host| host.alias == alias)
+    }
+
+    /// The host of the configuration file with the given alias, to change it before saving.
+    pub fn get_mut(&mut self, alias: &str) -> Option<&mut Host> {
+        self.hosts.iter_mut().find(|host| host.alias == alias)
+    }
+
+    /// Hosts matching a target specification of comma separated aliases, globs (`web-*`),
+    /// regexes (`~^db\d+$`) or ranges (`web[01:12]`), dynamic hosts included. Fails if a part
+    /// matches no host.
+    pub fn select(&self, spec: &str) -> Result<std::vec::Vec<Host>, String> {
+        targets::resolve(spec, &self.all_hosts())
+    }
+
+    /// Hosts whose facts match a filter expression such as `os=ubuntu && kernel<5.15`,
+    /// gathering the facts first if `fresh` is set or nothing is cached. Dynamic hosts are included.
+    pub fn filter(&self, expr: &str, fresh: bool) -> Result<std::vec::Vec<Host>, String> {
+        filter::select(self.all_hosts(), expr, fresh)
+    }
+
+    /// Variables of a host: those of its groups, overridden by its own.
+    pub fn vars(&self, host: &Host) -> BTreeMap<String, String> {
+        host::effective_vars(host, &self.groups)
+    }
+
+    /// Adds a host, failing if its alias is already taken or one of its values is invalid.
+    pub fn add(&mut self, host: Host) -> Result<(), String> {
+        if self.get(&host.alias).is_some() {
+            return Err(format!("Host {} already in configuration file!", host.alias));
+        }
+        validate::check_host(&host)?;
+        self.hosts.push(host);
+        Ok(())
+    }
+
+    /// Removes the host with the given alias, returning it if it was there.
+    pub fn remove(&mut self, alias: &str) -> Option<Host> {
+        let position = self.hosts.iter().position(|host| host.alias == alias)?;
+        Some(self.hosts.remove(position))
+    }
+
+    /// Writes the hosts back into the configuration file, keeping a backup of the previous version.
+    /// Fails without writing anything if a host is invalid, see `validate::check_host`, or if the
+    /// file was changed by someone else since it was loaded.
+    pub fn save(&mut self) -> Result<(), String> {
+        // Hosts changed through `get_mut` are checked as well, nothing is written if one is invalid.
+        for host in self.hosts.iter() {
+            validate::check_host(host)?;
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| format!("Unable to create {}: {}", parent.display(), err))?;
+        }
+        host::write_hosts(&self.path, &self.hosts, &self.loaded).map_err(|err| format!("Unable to save {}: {}", self.path.display(), err))?;
+        if !args::check_mode() {
+            self.loaded = std::fs::read_to_string(&self.path).unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_keeps_nested_tables_after_the_host_lists() {
+        let path = std::env::temp_dir().join(format!("rman-inventory-roundtrip-{}.toml", std::process::id()));
+        std::fs::write(&path, "\
+alias = \"web01\"
+ip = \"10.0.0.5\"
+ssh_user = \"root\"
+pk_path = \"/root/.ssh/web.pem\"
+description = \"\"
+tags = \"web\"
+
+[groups.web]
+port = 2222
+
+[groups.web.vars]
+env = \"prod\"
+
+[history]
+keep = 50
+
+[guard]
+deny = [\"rm -rf /\"]
+").unwrap();
+        let mut inventory = Inventory::load_from(&path).unwrap();
+        let mut web02 = Host::new("web02", "10.0.0.6", "root", "/root/.ssh/web.pem");
+        web02.tags = vec!(String::from("web"));
+        inventory.add(web02).unwrap();
+        inventory.save().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let document = contents.parse::<toml::Value>().unwrap();
+        assert_eq!(document["alias"].as_str(), Some("web01|web02"));
+        assert_eq!(document["groups"]["web"]["port"].as_integer(), Some(2222));
+        assert_eq!(document["groups"]["web"]["vars"]["env"].as_str(), Some("prod"));
+        assert_eq!(document["history"]["keep"].as_integer(), Some(50));
+        assert_eq!(document["guard"]["deny"][0].as_str(), Some("rm -rf /"));
+
+        let reloaded = Inventory::load_from(&path).unwrap();
+        assert_eq!(reloaded.hosts().len(), 2);
+        assert_eq!(reloaded.vars(reloaded.get("web02").unwrap()).get("env").map(String::as_str), Some("prod"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn selects_dynamic_hosts_without_taking_them_into_the_file() {
+        let dir = std::env::temp_dir().join(format!("rman-inventory-sources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cmdb.json"), r#"{"hosts": {"web01": {"ip": "10.0.9.9"}, "db01": {"ip": "10.0.1.7"}}}"#).unwrap();
+        let path = dir.join("rman.toml");
+        std::fs::write(&path, "\
+alias = \"web01\"
+ip = \"10.0.0.5\"
+ssh_user = \"root\"
+pk_path = \"/root/.ssh/web.pem\"
+description = \"\"
+tags = \"\"
+
+[sources.cmdb]
+file = \"cmdb.json\"
+").unwrap();
+        let inventory = Inventory::load_from(&path).unwrap();
+        let aliases = |hosts: &[Host]| hosts.iter().map(|host| host.alias.clone()).collect::<std::vec::Vec<_>>();
+        assert_eq!(aliases(inventory.hosts()), vec!("web01"));
+        assert_eq!(aliases(&inventory.all_hosts()), vec!("web01", "db01"));
+        // The file's own host wins over the source's.
+        assert_eq!(inventory.select("web01").unwrap()[0].ip, "10.0.0.5");
+        assert_eq!(aliases(&inventory.select("db*").unwrap()), vec!("db01"));
+        assert!(inventory.get("db01").is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
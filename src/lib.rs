@@ -0,0 +1,66 @@
+//! rman as a library: load and change inventories with [`Inventory`] and run commands or upload
+//! files over their hosts with [`Executor`]. The `rman` command line is a thin layer over it.
+//!
+//! # Examples
+//! ```no_run
+//! use rman::{Executor, Inventory};
+//!
+//! let inventory = Inventory::load()?;
+//! let hosts = inventory.filter("os=ubuntu", false)?;
+//! for outcome in Executor::new(hosts).run("apt-get -s upgrade | grep -c ^Inst") {
+//!     println!("{}: {} pending upgrades", outcome.alias, outcome.result.output.trim());
+//! }
+//! # Ok::<(), String>(())
+//! ```
+
+pub mod context;
+pub mod executor;
+pub mod facts;
+pub mod filter;
+pub mod host;
+pub mod inventory;
+pub mod render;
+pub mod ssh_con;
+pub mod targets;
+pub mod validate;
+
+// The commands of the `rman` binary, public only so it can reach them.
+#[doc(hidden)]
+pub mod all;
+#[doc(hidden)]
+pub mod args;
+#[doc(hidden)]
+pub mod audit;
+#[doc(hidden)]
+pub mod cli;
+#[doc(hidden)]
+pub mod drift;
+#[doc(hidden)]
+pub mod history;
+#[doc(hidden)]
+pub mod init;
+#[doc(hidden)]
+pub mod probe;
+#[doc(hidden)]
+pub mod retry;
+#[doc(hidden)]
+pub mod runbook;
+#[doc(hidden)]
+pub mod store;
+#[doc(hidden)]
+pub mod verify;
+
+mod diff;
+mod guard;
+mod health;
+mod listing;
+mod modules;
+mod sources;
+mod style;
+mod table;
+mod template;
+
+pub use executor::{Executor, HostOutcome};
+pub use host::Host;
+pub use inventory::Inventory;
+pub use ssh_con::CmdResult;
@@ -0,0 +1,246 @@
+//! Provides the tabular `$rman host ls` listing.
+
+use crate::cli::ListArgs;
+use crate::facts;
+use crate::filter;
+use crate::host::{get_hosts, Host};
+use crate::probe::{self, Probe, State};
+use crate::style;
+use crate::table::{Cell, Table};
+use glob::Pattern;
+use std::cmp::Ordering;
+
+/// Columns shown when none are selected.
+const DEFAULT_COLUMNS: &str = "alias,ip,user,tags,status,rtt,desc";
+/// Columns shown by `--wide`.
+const WIDE_COLUMNS: &str = "alias,ip,user,key,tags,status,rtt,os,kernel,desc";
+/// Columns that aren't facts.
+const BUILT_IN_COLUMNS: [&str; 9] = ["alias", "ip", "user", "key", "desc", "description", "tags", "status", "rtt"];
+
+/// A host together with everything that may be displayed about it.
+struct Row {
+    host: Host,
+    probe: Option<Probe>,
+    facts: facts::Facts,
+}
+
+/// Lists hosts as a table, see `$rman host ls --help` for the supported flags.
+pub fn list_hosts(args: ListArgs) {
+    let ListArgs { columns, wide, no_status, alias: alias_glob, tag: tags, where_expr: expr, sort, probe: options } = args;
+    let columns = match parse_columns(columns, wide, no_status) {
+        Ok(columns) => columns,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let sort = match sort.map(|sort| parse_sort(&sort)).transpose() {
+        Ok(sort) => sort,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Filter the inventory before probing so only listed hosts are contacted.
+    let mut hosts = match get_hosts() {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if hosts.is_empty() {
+        println!("{}", crate::host::NO_HOSTS);
+        return;
+    }
+    if let Some(alias_glob) = alias_glob {
+        match Pattern::new(&alias_glob) {
+            Ok(pattern) => hosts.retain(|host| pattern.matches(&host.alias)),
+            Err(err) => {
+                println!("Invalid alias pattern '{}': {}", alias_glob, err);
+                return;
+            }
+        }
+    }
+    if let Some(tags) = tags {
+        let wanted = crate::host::parse_tags(&tags);
+        hosts.retain(|host| wanted.iter().all(|tag| host.tags.contains(tag)));
+    }
+    if let Some(expr) = expr {
+        // Only cached facts are used here, `host ls` should never have to log into every host.
+        let expr = match filter::parse(&expr) {
+            Ok(expr) => expr,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+        hosts.retain(|host| expr.eval(&cached_facts(host)));
+    }
+
+    let probes = if columns.iter().any(|column| column == "status" || column == "rtt") {
+        probe::probe_all(&hosts, options.level, options.timeout()).into_iter().map(Some).collect()
+    } else {
+        hosts.iter().map(|_| None).collect::<std::vec::Vec<_>>()
+    };
+    let mut rows: std::vec::Vec<Row> = hosts.into_iter().zip(probes)
+        .map(|(host, probe)| Row { facts: cached_facts(&host), host, probe })
+        .collect();
+
+    if let Some((column, descending)) = sort {
+        sort_rows(&mut rows, &column, descending);
+    }
+
+    let table = Table {
+        headers: columns.iter().map(|column| column.to_uppercase()).collect(),
+        rows: rows.iter().map(|row| columns.iter().map(|column| cell(row, column)).collect()).collect(),
+    };
+    table.print(wide);
+}
+
+/// The columns to show, the defaults if none are given. Fails on names that are neither built
+/// in nor facts, listing the valid ones.
+fn parse_columns(columns: Option<std::vec::Vec<String>>, wide: bool, no_status: bool) -> Result<std::vec::Vec<String>, String> {
+    let default_columns = if wide { WIDE_COLUMNS } else { DEFAULT_COLUMNS };
+    let columns: std::vec::Vec<String> = columns
+        .unwrap_or_else(|| default_columns.split(',').map(String::from).collect())
+        .iter()
+        .map(|column| column.trim().to_lowercase())
+        .filter(|column| !column.is_empty() && !(no_status && (column == "status" || column == "rtt")))
+        .collect();
+    for column in columns.iter() {
+        check_column(column)?;
+    }
+    Ok(columns)
+}
+
+/// The column and direction of a `--sort` value such as `-rtt`.
+fn parse_sort(sort: &str) -> Result<(String, bool), String> {
+    let (column, descending) = match sort.strip_prefix('-') {
+        Some(column) => (column.trim().to_lowercase(), true),
+        None => (sort.trim().to_lowercase(), false),
+    };
+    check_column(&column)?;
+    Ok((column, descending))
+}
+
+/// Fails if `column` is neither built in nor a fact.
+fn check_column(column: &str) -> Result<(), String> {
+    if BUILT_IN_COLUMNS.contains(&column) || facts::NAMES.contains(&column) {
+        return Ok(());
+    }
+    let valid: std::vec::Vec<&str> = BUILT_IN_COLUMNS.iter().chain(facts::NAMES.iter()).copied().collect();
+    Err(format!("Unknown column '{}', expected one of {}", column, valid.join(", ")))
+}
+
+/// Sorts rows by a column. Unreachable hosts stay last when sorting by round-trip time either way.
+fn sort_rows(rows: &mut [Row], column: &str, descending: bool) {
+    rows.sort_by(|a, b| {
+        if column == "rtt" {
+            let rtt = |row: &Row| row.probe.as_ref().and_then(|probe| probe.rtt);
+            if let (Some(a), Some(b)) = (rtt(a), rtt(b)) {
+                return if descending { b.cmp(&a) } else { a.cmp(&b) };
+            }
+            return rtt(b).is_some().cmp(&rtt(a).is_some());
+        }
+        let ordering = compare(a, b, column);
+        if descending { ordering.reverse() } else { ordering }
+    });
+}
+
+/// Cached facts of a host, or only its inventory fields if nothing has been gathered yet.
+fn cached_facts(host: &Host) -> facts::Facts {
+    facts::cached(host).unwrap_or_else(|| facts::with_host_fields(host, facts::Facts::new()))
+}
+
+/// Plain text value of a column.
+fn value(row: &Row, column: &str) -> String {
+    match column {
+        "alias" => row.host.alias.clone(),
+        "ip" => row.host.ip.clone(),
+        "user" => row.host.ssh_user.clone(),
+        "key" => row.host.pk_path.clone(),
+        "desc" | "description" => row.host.description.clone(),
+        "tags" => row.host.tags.join(","),
+        "status" => row.probe.as_ref().map(|probe| probe.state.label().to_string()).unwrap_or_default(),
+        "rtt" => row.probe.as_ref().map(Probe::rtt_label).unwrap_or_default(),
+        // Anything else is looked up in the cached facts.
+        _ => row.facts.get(column).cloned().unwrap_or_default(),
+    }
+}
+
+/// Builds the table cell of a column, colouring the status.
+fn cell(row: &Row, column: &str) -> Cell {
+    let text = value(row, column);
+    match (column, row.probe.as_ref().map(|probe| probe.state)) {
+        ("status", Some(State::Up)) => Cell::painted(&text, style::green),
+        ("status", Some(State::AuthFailed)) => Cell::painted(&text, style::yellow),
+        ("status", Some(State::Down)) => Cell::painted(&text, style::red),
+        _ => Cell::plain(&text),
+    }
+}
+
+/// Orders two rows by a column, numerically if both values are numbers.
+fn compare(a: &Row, b: &Row, column: &str) -> Ordering {
+    let (a, b) = (value(a, column), value(b, column));
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(&b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn row(alias: &str, rtt: Option<u64>, cpus: &str) -> Row {
+        let probe = Probe {
+            alias: alias.to_string(),
+            state: if rtt.is_some() { State::Up } else { State::Down },
+            rtt: rtt.map(Duration::from_millis),
+            detail: String::new(),
+        };
+        let mut facts = facts::Facts::new();
+        facts.insert(String::from("cpus"), cpus.to_string());
+        Row { host: Host::new(alias, "10.0.0.1", "root", "/root/.ssh/key"), probe: Some(probe), facts }
+    }
+
+    fn aliases(rows: &[Row]) -> std::vec::Vec<&str> {
+        rows.iter().map(|row| row.host.alias.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_columns_and_drops_status_on_request() {
+        assert_eq!(parse_columns(None, false, false).unwrap(), DEFAULT_COLUMNS.split(',').collect::<std::vec::Vec<_>>());
+        assert_eq!(parse_columns(Some(vec!(String::from(" Alias"), String::from("os"), String::from("rtt"))), false, true).unwrap(), vec!("alias", "os"));
+        assert!(parse_columns(None, true, false).unwrap().contains(&String::from("kernel")));
+    }
+
+    #[test]
+    fn rejects_unknown_columns_listing_the_valid_ones() {
+        let err = parse_columns(Some(vec!(String::from("alias"), String::from("ipaddr"))), false, false).unwrap_err();
+        assert!(err.contains("Unknown column 'ipaddr'") && err.contains("alias, ip") && err.contains("kernel"), "{}", err);
+        assert!(parse_sort("-nope").is_err());
+        assert_eq!(parse_sort("-RTT").unwrap(), (String::from("rtt"), true));
+    }
+
+    #[test]
+    fn sorts_unreachable_hosts_last_either_way() {
+        let mut rows = vec!(row("down", None, "1"), row("slow", Some(80), "1"), row("fast", Some(3), "1"));
+        sort_rows(&mut rows, "rtt", false);
+        assert_eq!(aliases(&rows), vec!("fast", "slow", "down"));
+        sort_rows(&mut rows, "rtt", true);
+        assert_eq!(aliases(&rows), vec!("slow", "fast", "down"));
+    }
+
+    #[test]
+    fn sorts_numbers_numerically_and_text_alphabetically() {
+        let mut rows = vec!(row("web10", None, "16"), row("web02", None, "4"), row("db01", None, "32"));
+        sort_rows(&mut rows, "cpus", false);
+        assert_eq!(aliases(&rows), vec!("web02", "web10", "db01"));
+        sort_rows(&mut rows, "alias", true);
+        assert_eq!(aliases(&rows), vec!("web10", "web02", "db01"));
+    }
+}
@@ -1,38 +1,120 @@
 //! Provides CLI entry into rman.
 
-mod help;
-mod host;
-mod all;
-mod ssh_con;
-
 // Imports
-extern crate config;
-use std::env;
+use clap::{CommandFactory, Parser};
+use clap_complete::env::{Bash, CompleteEnv, EnvCompleter, Fish, Zsh};
+use rman::cli::{self, Cli, Command, Shell};
+use rman::{all, args, audit, context, drift, history, host, init, probe, render, retry, runbook, store, verify};
+use std::io;
 
 /// Main method handles arguments supplied via CLI
 fn main() {
-    // Collect arguments.
-    let args: Vec<String> = env::args().collect();
-    //println!("{:?}", args);
-    // Handle user interaction via CLI...
-    if args.len() > 1 {
-        let cmd: &str = &args[1];
-        match cmd {
-            "status" | "s" => show_status(),    // Execute the status command.
-            "host" | "h" => host::base(args),   // Execute a host command.
-            "all" | "a" => all::base(args),     // Execute an all command.
-            "help" => help::base(args),         // Display rman commands.
-            _ => help::base(vec!(String::new())),
+    // Answer shell completion requests made through the script of `rman completions`.
+    CompleteEnv::with_factory(Cli::command).complete();
+    let cli = Cli::parse();
+    handle_interrupts();
+    args::set_check_mode(cli.check);
+    args::set_confirmed(cli.yes_i_am_sure);
+    args::set_limits(cli.limits());
+    if let Some(path) = cli.config.clone() {
+        args::set_config(path);
+    }
+    if let Some(name) = cli.context.clone() {
+        args::set_context(name);
+    }
+    // Contexts can be managed even while the selected one is unknown.
+    if !matches!(cli.command, Command::Context(_)) {
+        if let Err(err) = context::check_selected() {
+            println!("{}", err);
+            std::process::exit(1);
         }
     }
-    else {
-        // Show base help message when supplied no args.
-        help::base(args);
+    match cli.command {
+        Command::Init { ssh_user, pk_path } => init::base(ssh_user, pk_path),  // Create the configuration file.
+        Command::Status(probe) => show_status(probe),           // Execute the status command.
+        Command::Host(command) => host::base(command),          // Execute a host command.
+        Command::All(all) => all::base(all),                    // Execute an all command.
+        Command::Run { file } => runbook::base(&file),          // Execute a runbook.
+        Command::Render { targets, command } => exit_on_error(render::base(&targets, &command.join(" "))),  // Preview templated commands.
+        Command::Drift(drift) => drift::base(drift),            // Compare files across hosts.
+        Command::Verify { file, junit, wide } => verify::base(&file, junit, wide),  // Evaluate compliance checks.
+        Command::Retry { run_id, failed, unreachable } => retry::base(run_id, failed, unreachable),  // Re-run failed hosts of a run.
+        Command::History(history) => history::base(history),    // Review past runs.
+        Command::Audit(command) => audit::base(command),        // Verify the history.
+        Command::Config(command) => store::base(command),       // Review and undo configuration changes.
+        Command::Context(command) => context::base(command),    // Switch inventories.
+        Command::Completions { shell } => print_completions(shell),
+        Command::Man { out } => print_man(out),
+    }
+}
+
+/// Handles Ctrl-C on a thread of its own. The first one cancels the hosts of a run that haven't
+/// finished, letting the running ones finish and the run be recorded. Another one, or one while
+/// no hosts are worked on, exits.
+fn handle_interrupts() {
+    std::thread::spawn(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("unable to start the async runtime");
+        runtime.block_on(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if !args::interrupt() {
+                    std::process::exit(130);
+                }
+                println!("Cancelling, hosts already running will finish. Press Ctrl-C again to exit.");
+            }
+        });
+    });
+}
+
+/// Prints the error of a command and exits with a non-zero status, if it failed.
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(err) = result {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Prints the script registering dynamic completions, which call back into rman for aliases and tags.
+fn print_completions(shell: Shell) {
+    let completer: &dyn EnvCompleter = match shell {
+        Shell::Bash => &Bash,
+        Shell::Zsh => &Zsh,
+        Shell::Fish => &Fish,
+    };
+    if let Err(err) = completer.write_registration("COMPLETE", "rman", "rman", "rman", &mut io::stdout()) {
+        println!("Unable to write the completion script: {}", err);
+    }
+}
+
+/// Prints the man page, or writes one page per command into `out`.
+fn print_man(out: Option<std::path::PathBuf>) {
+    let written = match out {
+        Some(dir) => clap_mangen::generate_to(Cli::command(), &dir).map(|_| println!("Man pages written to {}", dir.display())),
+        None => clap_mangen::Man::new(Cli::command()).render(&mut io::stdout()),
+    };
+    if let Err(err) = written {
+        println!("Unable to write the man page: {}", err);
     }
 }
 
-fn show_status() {
-    let hosts = host::get_hosts();
+/// Probes every host and prints whether it is up along with its round-trip time.
+fn show_status(options: cli::ProbeArgs) {
+    println!("Inventory: {}", context::describe());
+    let hosts = match host::get_hosts() {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if hosts.is_empty() {
+        println!("{}", host::NO_HOSTS);
+        return;
+    }
+    let probes = probe::probe_all(&hosts, options.level, options.timeout());
+    let width = probes.iter().map(|probe| probe.alias.len()).max().unwrap_or(0);
+    for probe in probes.iter() {
+        println!("{:<width$}  {:<11}  {:>8}  {}", probe.alias, probe.state.label(), probe.rtt_label(), probe.detail, width = width);
+    }
     println!("Number of registered hosts: {}", hosts.len());
-    println!("Number of reachable hosts: {}", all::check_up_hosts());
-}
\ No newline at end of file
+    println!("Number of reachable hosts: {}", probes.iter().filter(|probe| probe.state == probe::State::Up).count());
+}
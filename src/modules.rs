@@ -0,0 +1,450 @@
+//! Provides idempotent modules that check a host's current state before changing it.
+//!
+//! Every module is turned into one or more checks made of a `test` command, which succeeds when
+//! the host is already in the desired state, and an `action` command that gets it there. Both
+//! run in a single remote shell, so a module costs one round trip per host and reports `ok`,
+//! `changed` or `failed`.
+//!
+//! With `--check` only the tests run, so every host reports whether it would change.
+//!
+//! Modules can be used from the command line, e.g. `$rman all pkg install nginx`, or from
+//! runbook steps:
+//!
+//! ```toml
+//! [[step]]
+//! name = "nginx"
+//! module = { type = "package", name = "nginx", state = "present" }
+//! ```
+
+use crate::args;
+use crate::cli::{FileState, ModuleCommand, PkgAction, PresenceState, ServiceAction};
+use crate::history::{self, HostResult};
+use crate::host::Host;
+use crate::runbook::{Outcome, Status};
+use crate::ssh_con::{run_remote_command, shell_quote};
+use crate::style;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Desired state of whatever a module manages.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Present,
+    Absent,
+    Directory,
+    Started,
+    Stopped,
+    Restarted,
+}
+
+/// A typed module and its parameters.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Module {
+    Package {
+        name: String,
+        #[serde(default = "present")]
+        state: State,
+    },
+    File {
+        path: String,
+        content: Option<String>,
+        mode: Option<String>,           // Octal, e.g. "644".
+        #[serde(default = "present")]
+        state: State,
+    },
+    Service {
+        name: String,
+        state: Option<State>,
+        enabled: Option<bool>,
+    },
+    User {
+        name: String,
+        #[serde(default)]
+        groups: std::vec::Vec<String>,
+        #[serde(default = "present")]
+        state: State,
+    },
+    Group {
+        name: String,
+        #[serde(default = "present")]
+        state: State,
+    },
+    LineInFile {
+        path: String,
+        line: String,
+        regexp: Option<String>,         // Line to replace, the line is appended if nothing matches.
+        #[serde(default = "present")]
+        state: State,
+    },
+}
+
+fn present() -> State {
+    State::Present
+}
+
+/// A single idempotent operation.
+pub struct Check {
+    pub test: String,                   // Succeeds if nothing needs to be done.
+    pub action: String,                 // Brings the host into the desired state.
+}
+
+/// Shell functions detecting the package manager, shared by every package check. They are
+/// prefixed so they can't shadow commands such as `install`.
+const PACKAGE_PRELUDE: &str = "if command -v apt-get >/dev/null 2>&1; then \
+rman_pkg_installed() { dpkg-query -W -f='${Status}' \"$1\" 2>/dev/null | grep -q 'ok installed'; }; \
+rman_pkg_install() { DEBIAN_FRONTEND=noninteractive apt-get install -y \"$1\"; }; \
+rman_pkg_remove() { DEBIAN_FRONTEND=noninteractive apt-get remove -y \"$1\"; }; \
+elif command -v dnf >/dev/null 2>&1; then \
+rman_pkg_installed() { rpm -q \"$1\" >/dev/null 2>&1; }; rman_pkg_install() { dnf install -y \"$1\"; }; rman_pkg_remove() { dnf remove -y \"$1\"; }; \
+elif command -v pacman >/dev/null 2>&1; then \
+rman_pkg_installed() { pacman -Q \"$1\" >/dev/null 2>&1; }; rman_pkg_install() { pacman -S --noconfirm \"$1\"; }; rman_pkg_remove() { pacman -R --noconfirm \"$1\"; }; \
+elif command -v apk >/dev/null 2>&1; then \
+rman_pkg_installed() { apk info -e \"$1\" >/dev/null 2>&1; }; rman_pkg_install() { apk add \"$1\"; }; rman_pkg_remove() { apk del \"$1\"; }; \
+else echo 'no supported package manager (apt, dnf, pacman, apk) found' >&2; exit 2; fi; ";
+
+impl Module {
+    /// Short description used in output and the history.
+    pub fn describe(&self) -> String {
+        match self {
+            Module::Package { name, state } => format!("package {} {:?}", name, state).to_lowercase(),
+            Module::File { path, state, .. } => format!("file {} {:?}", path, state).to_lowercase(),
+            Module::Service { name, state, enabled } => format!("service {} {:?} enabled={:?}", name, state, enabled).to_lowercase(),
+            Module::User { name, state, .. } => format!("user {} {:?}", name, state).to_lowercase(),
+            Module::Group { name, state } => format!("group {} {:?}", name, state).to_lowercase(),
+            Module::LineInFile { path, line, state, .. } => format!("line-in-file {} {:?} {:?}", path, line, state).to_lowercase(),
+        }
+    }
+
+    /// Shell code every check of the module needs, such as package manager detection.
+    fn prelude(&self) -> &'static str {
+        match self {
+            Module::Package { .. } => PACKAGE_PRELUDE,
+            _ => "",
+        }
+    }
+
+    /// Turns the module into checks, or explains why its parameters make no sense.
+    pub fn checks(&self) -> Result<std::vec::Vec<Check>, String> {
+        let check = |test: String, action: String| Check { test, action };
+        let unsupported = |state: &State| Err(format!("state {:?} is not supported by {}", state, self.describe()));
+        Ok(match self {
+            Module::Package { name, state } => {
+                let name = shell_quote(name);
+                match state {
+                    State::Present => vec!(check(format!("rman_pkg_installed {}", name), format!("rman_pkg_install {}", name))),
+                    State::Absent => vec!(check(format!("! rman_pkg_installed {}", name), format!("rman_pkg_remove {}", name))),
+                    _ => return unsupported(state),
+                }
+            }
+            Module::File { path, content, mode, state } => {
+                let quoted = shell_quote(path);
+                match state {
+                    State::Absent => vec!(check(format!("[ ! -e {} ]", quoted), format!("rm -rf -- {}", quoted))),
+                    State::Directory => {
+                        let mut checks = vec!(check(format!("[ -d {} ]", quoted), format!("mkdir -p -- {}", quoted)));
+                        if let Some(mode) = mode {
+                            checks.push(mode_check(&quoted, mode)?);
+                        }
+                        checks
+                    }
+                    State::Present => {
+                        let mut checks = vec!();
+                        match content {
+                            Some(content) => {
+                                let hash = hex::encode(Sha256::digest(content.as_bytes()));
+                                checks.push(check(
+                                    format!("[ \"$(sha256sum < {} 2>/dev/null | cut -d' ' -f1)\" = {} ]", quoted, hash),
+                                    format!("printf '%s' {} > {}", shell_quote(content), quoted)));
+                            }
+                            None => checks.push(check(format!("[ -f {} ]", quoted), format!("touch -- {}", quoted))),
+                        }
+                        if let Some(mode) = mode {
+                            checks.push(mode_check(&quoted, mode)?);
+                        }
+                        checks
+                    }
+                    _ => return unsupported(state),
+                }
+            }
+            Module::Service { name, state, enabled } => {
+                let name = shell_quote(name);
+                let mut checks = vec!();
+                match state {
+                    Some(State::Started) => checks.push(check(format!("systemctl is-active --quiet {}", name), format!("systemctl start {}", name))),
+                    Some(State::Stopped) => checks.push(check(format!("! systemctl is-active --quiet {}", name), format!("systemctl stop {}", name))),
+                    Some(State::Restarted) => checks.push(check(String::from("false"), format!("systemctl restart {}", name))),
+                    Some(state) => return unsupported(state),
+                    None => (),
+                }
+                match enabled {
+                    Some(true) => checks.push(check(format!("systemctl is-enabled --quiet {}", name), format!("systemctl enable {}", name))),
+                    Some(false) => checks.push(check(format!("! systemctl is-enabled --quiet {}", name), format!("systemctl disable {}", name))),
+                    None => (),
+                }
+                if checks.is_empty() {
+                    return Err(String::from("service needs a state, enabled, or both"));
+                }
+                checks
+            }
+            Module::User { name, groups, state } => {
+                let quoted = shell_quote(name);
+                match state {
+                    State::Present => {
+                        let mut checks = vec!(check(format!("id -u {} >/dev/null 2>&1", quoted), format!("useradd -m {}", quoted)));
+                        for group in groups {
+                            let group = shell_quote(group);
+                            checks.push(check(
+                                format!("id -nG {} | tr ' ' '\\n' | grep -qxF {}", quoted, group),
+                                format!("usermod -aG {} {}", group, quoted)));
+                        }
+                        checks
+                    }
+                    State::Absent => vec!(check(format!("! id -u {} >/dev/null 2>&1", quoted), format!("userdel {}", quoted))),
+                    _ => return unsupported(state),
+                }
+            }
+            Module::Group { name, state } => {
+                let name = shell_quote(name);
+                match state {
+                    State::Present => vec!(check(format!("getent group {} >/dev/null", name), format!("groupadd {}", name))),
+                    State::Absent => vec!(check(format!("! getent group {} >/dev/null", name), format!("groupdel {}", name))),
+                    _ => return unsupported(state),
+                }
+            }
+            Module::LineInFile { path, line, regexp, state } => {
+                let path = shell_quote(path);
+                let line = shell_quote(line);
+                // The file is rewritten through `cat >` so its owner and mode are kept.
+                let rewrite = |filter: &str| format!("{} {} > {path}.rman-tmp; cat {path}.rman-tmp > {path} && rm -f {path}.rman-tmp", filter, path, path = path);
+                match (state, regexp) {
+                    (State::Present, None) => vec!(check(
+                        format!("grep -qxF -- {} {}", line, path),
+                        format!("printf '%s\\n' {} >> {}", line, path))),
+                    (State::Present, Some(regexp)) => vec!(check(
+                        format!("grep -qxF -- {} {}", line, path),
+                        format!("RMAN_RE={} RMAN_LINE={} {}", shell_quote(regexp), line, rewrite(
+                            "awk '!done && $0 ~ ENVIRON[\"RMAN_RE\"] { print ENVIRON[\"RMAN_LINE\"]; done = 1; next } { print } END { if (!done) print ENVIRON[\"RMAN_LINE\"] }'")))),
+                    (State::Absent, None) => vec!(check(
+                        format!("! grep -qxF -- {} {}", line, path),
+                        rewrite(&format!("grep -vxF -- {}", line)))),
+                    (State::Absent, Some(regexp)) => vec!(check(
+                        format!("! grep -qE -- {} {}", shell_quote(regexp), path),
+                        rewrite(&format!("grep -vE -- {}", shell_quote(regexp))))),
+                    (state, _) => return unsupported(state),
+                }
+            }
+        })
+    }
+
+    /// The remote script applying every check, or with `test_only` only telling which of them
+    /// would change something. Each check prints a marker so one round trip tells which of them
+    /// changed something.
+    pub fn script(&self, test_only: bool) -> Result<String, String> {
+        let mut script = String::from(self.prelude());
+        for check in self.checks()?.iter() {
+            if test_only {
+                script.push_str(&format!("if {}; then echo __rman_ok; else echo __rman_changed; fi; ", check.test));
+            } else {
+                script.push_str(&format!("if {}; then echo __rman_ok; else {{ {}; }} && echo __rman_changed || exit 1; fi; ", check.test, check.action));
+            }
+        }
+        Ok(script)
+    }
+
+    /// Applies the module to a host. In check mode only the tests run and the outcome tells
+    /// whether the module would change something.
+    pub fn apply(&self, host: &Host) -> Outcome {
+        let script = match self.script(args::check_mode()) {
+            Ok(script) => script,
+            Err(err) => return Outcome { status: Status::Failed, rc: None, output: err },
+        };
+        let result = run_remote_command(host, &script);
+        let output: String = result.output.lines().filter(|line| !line.starts_with("__rman_")).collect::<std::vec::Vec<_>>().join("\n");
+        let status = if !result.connected || result.exit_status.unwrap_or(0) != 0 {
+            Status::Failed
+        } else if result.output.lines().any(|line| line == "__rman_changed") {
+            Status::Changed
+        } else {
+            Status::Ok
+        };
+        Outcome { status, rc: result.exit_status, output }
+    }
+}
+
+/// Check that a path has the given octal mode.
+fn mode_check(quoted_path: &str, mode: &str) -> Result<Check, String> {
+    let mode = u32::from_str_radix(mode, 8).map_err(|_| format!("invalid mode '{}'", mode))?;
+    Ok(Check {
+        test: format!("[ \"$(stat -c %a {})\" = {:o} ]", quoted_path, mode),
+        action: format!("chmod {:o} {}", mode, quoted_path),
+    })
+}
+
+impl From<ModuleCommand> for Module {
+    /// Converts a module given on the command line.
+    fn from(command: ModuleCommand) -> Module {
+        let presence = |state: PresenceState| match state {
+            PresenceState::Present => State::Present,
+            PresenceState::Absent => State::Absent,
+        };
+        match command {
+            ModuleCommand::Pkg(args) => Module::Package {
+                name: args.name,
+                state: match args.action {
+                    PkgAction::Install => State::Present,
+                    PkgAction::Remove => State::Absent,
+                },
+            },
+            ModuleCommand::File(args) => Module::File {
+                path: args.path,
+                content: args.content,
+                mode: args.mode,
+                state: match args.state {
+                    FileState::Present => State::Present,
+                    FileState::Absent => State::Absent,
+                    FileState::Directory => State::Directory,
+                },
+            },
+            ModuleCommand::Service(args) => Module::Service {
+                name: args.name,
+                state: args.action.map(|action| match action {
+                    ServiceAction::Start => State::Started,
+                    ServiceAction::Stop => State::Stopped,
+                    ServiceAction::Restart => State::Restarted,
+                }),
+                enabled: if args.enable { Some(true) } else if args.disable { Some(false) } else { None },
+            },
+            ModuleCommand::User(args) => Module::User { name: args.name, groups: args.groups, state: presence(args.state) },
+            ModuleCommand::Group(args) => Module::Group { name: args.name, state: presence(args.state) },
+            ModuleCommand::Line(args) => Module::LineInFile {
+                path: args.path,
+                line: args.line,
+                regexp: args.regexp,
+                state: if args.absent { State::Absent } else { State::Present },
+            },
+        }
+    }
+}
+
+/// Applies a module given on the command line to every host, printing and recording the outcome.
+/// Exits with a non-zero status if any host failed.
+pub fn run_cli(hosts: std::vec::Vec<Host>, command: ModuleCommand) {
+    let module = Module::from(command);
+    if let Err(err) = module.checks() {
+        println!("{}", err);
+        std::process::exit(1);
+    }
+    let capture = history::capture_output();
+    let mut results = vec!();
+    let mut failed = false;
+    for host in hosts.iter() {
+        let start = std::time::Instant::now();
+        let outcome = module.apply(host);
+        let (label, status) = match outcome.status {
+            Status::Changed if args::check_mode() => (style::yellow("would change"), "ok"),
+            Status::Changed => (style::yellow("changed"), "ok"),
+            Status::Failed => (style::red("failed"), "failed"),
+            _ => (style::green("ok"), "ok"),
+        };
+        println!("{}: {}", label, host.alias);
+        if outcome.status == Status::Failed {
+            failed = true;
+            println!("    {}", outcome.output.trim().replace('\n', "\n    "));
+        }
+        results.push(HostResult {
+            alias: host.alias.clone(),
+            status: status.to_string(),
+            exit_status: outcome.rc,
+            duration_ms: start.elapsed().as_millis() as u64,
+            output: if capture { Some(outcome.output) } else { None },
+        });
+    }
+    history::record("module", &module.describe(), hosts.into_iter().map(|host| host.alias).collect(), results);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(toml: &str) -> Module {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// The test and action of every check.
+    fn checks(toml: &str) -> std::vec::Vec<(String, String)> {
+        module(toml).checks().unwrap().into_iter().map(|check| (check.test, check.action)).collect()
+    }
+
+    fn pair(test: &str, action: &str) -> (String, String) {
+        (test.to_string(), action.to_string())
+    }
+
+    #[test]
+    fn packages_use_the_detected_package_manager() {
+        assert_eq!(checks("type = \"package\"\nname = \"nginx\""), vec!(pair("rman_pkg_installed 'nginx'", "rman_pkg_install 'nginx'")));
+        assert_eq!(checks("type = \"package\"\nname = \"nginx\"\nstate = \"absent\""), vec!(pair("! rman_pkg_installed 'nginx'", "rman_pkg_remove 'nginx'")));
+        let script = module("type = \"package\"\nname = \"nginx\"").script(false).unwrap();
+        assert!(script.starts_with(PACKAGE_PRELUDE));
+        // The helpers never shadow commands of the host such as install(1).
+        assert!(!PACKAGE_PRELUDE.contains(" install()") && !PACKAGE_PRELUDE.contains(" installed()"));
+    }
+
+    #[test]
+    fn files_check_content_and_mode() {
+        let content_hash = hex::encode(Sha256::digest(b"hi"));
+        assert_eq!(checks("type = \"file\"\npath = \"/etc/motd\"\ncontent = \"hi\"\nmode = \"600\""), vec!(
+            pair(&format!("[ \"$(sha256sum < '/etc/motd' 2>/dev/null | cut -d' ' -f1)\" = {} ]", content_hash), "printf '%s' 'hi' > '/etc/motd'"),
+            pair("[ \"$(stat -c %a '/etc/motd')\" = 600 ]", "chmod 600 '/etc/motd'"),
+        ));
+        assert_eq!(checks("type = \"file\"\npath = \"/srv/app\"\nstate = \"directory\""), vec!(pair("[ -d '/srv/app' ]", "mkdir -p -- '/srv/app'")));
+        assert_eq!(checks("type = \"file\"\npath = \"/tmp/x\"\nstate = \"absent\""), vec!(pair("[ ! -e '/tmp/x' ]", "rm -rf -- '/tmp/x'")));
+        assert!(module("type = \"file\"\npath = \"/tmp/x\"\nmode = \"rw\"").checks().is_err());
+        assert!(module("type = \"file\"\npath = \"/tmp/x\"\nstate = \"started\"").checks().is_err());
+    }
+
+    #[test]
+    fn services_need_a_state_or_enabled() {
+        assert_eq!(checks("type = \"service\"\nname = \"nginx\"\nstate = \"started\"\nenabled = true"), vec!(
+            pair("systemctl is-active --quiet 'nginx'", "systemctl start 'nginx'"),
+            pair("systemctl is-enabled --quiet 'nginx'", "systemctl enable 'nginx'"),
+        ));
+        assert_eq!(checks("type = \"service\"\nname = \"nginx\"\nstate = \"restarted\""), vec!(pair("false", "systemctl restart 'nginx'")));
+        assert!(module("type = \"service\"\nname = \"nginx\"").checks().is_err());
+    }
+
+    #[test]
+    fn users_and_groups_are_created_and_removed() {
+        assert_eq!(checks("type = \"user\"\nname = \"deploy\"\ngroups = [\"docker\"]"), vec!(
+            pair("id -u 'deploy' >/dev/null 2>&1", "useradd -m 'deploy'"),
+            pair("id -nG 'deploy' | tr ' ' '\\n' | grep -qxF 'docker'", "usermod -aG 'docker' 'deploy'"),
+        ));
+        assert_eq!(checks("type = \"user\"\nname = \"deploy\"\nstate = \"absent\""), vec!(pair("! id -u 'deploy' >/dev/null 2>&1", "userdel 'deploy'")));
+        assert_eq!(checks("type = \"group\"\nname = \"docker\""), vec!(pair("getent group 'docker' >/dev/null", "groupadd 'docker'")));
+        assert_eq!(checks("type = \"group\"\nname = \"docker\"\nstate = \"absent\""), vec!(pair("! getent group 'docker' >/dev/null", "groupdel 'docker'")));
+    }
+
+    #[test]
+    fn lines_are_appended_replaced_and_removed() {
+        assert_eq!(checks("type = \"line_in_file\"\npath = \"/etc/hosts\"\nline = \"10.0.0.2 db\""), vec!(
+            pair("grep -qxF -- '10.0.0.2 db' '/etc/hosts'", "printf '%s\\n' '10.0.0.2 db' >> '/etc/hosts'"),
+        ));
+        let replace = checks("type = \"line_in_file\"\npath = \"/etc/hosts\"\nline = \"10.0.0.2 db\"\nregexp = \" db$\"");
+        assert!(replace[0].1.starts_with("RMAN_RE=' db$' RMAN_LINE='10.0.0.2 db' awk "));
+        assert!(replace[0].1.ends_with("> '/etc/hosts'.rman-tmp; cat '/etc/hosts'.rman-tmp > '/etc/hosts' && rm -f '/etc/hosts'.rman-tmp"));
+        let absent = checks("type = \"line_in_file\"\npath = \"/etc/hosts\"\nline = \"x\"\nregexp = \"^x\"\nstate = \"absent\"");
+        assert_eq!(absent[0].0, "! grep -qE -- '^x' '/etc/hosts'");
+        assert!(absent[0].1.starts_with("grep -vE -- '^x' '/etc/hosts' > "));
+    }
+
+    #[test]
+    fn check_mode_scripts_only_run_the_tests() {
+        let group = module("type = \"group\"\nname = \"docker\"");
+        assert_eq!(group.script(true).unwrap(), "if getent group 'docker' >/dev/null; then echo __rman_ok; else echo __rman_changed; fi; ");
+        assert_eq!(group.script(false).unwrap(), "if getent group 'docker' >/dev/null; then echo __rman_ok; else { groupadd 'docker'; } && echo __rman_changed || exit 1; fi; ");
+    }
+}
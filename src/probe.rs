@@ -0,0 +1,207 @@
+//! Provides fast, concurrent reachability probing of hosts.
+//!
+//! A probe can stop at one of three levels:
+//! * `Tcp` only opens a TCP connection to the host's ssh port,
+//! * `Banner` additionally waits for the server's `SSH-` identification line,
+//! * `Auth` performs a full ssh handshake and key authentication.
+//!
+//! The round-trip time reported is the time it took to open the TCP connection.
+
+use crate::args;
+use crate::executor;
+use crate::host::Host;
+use crate::ssh_con::{check_auth, ConnectError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Timeout in milliseconds used when none is given on the command line.
+pub const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+/// How far a probe goes before declaring a host up.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Level {
+    Tcp,
+    Banner,
+    Auth,
+}
+
+/// Outcome of a probe.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Up,
+    Down,
+    AuthFailed,
+}
+
+/// Result of probing a single host.
+pub struct Probe {
+    pub alias: String,
+    pub state: State,
+    pub rtt: Option<Duration>,  // TCP connect time, if the host could be reached.
+    pub detail: String,         // Server banner or error message.
+}
+
+impl State {
+    pub fn label(self) -> &'static str {
+        match self {
+            State::Up => "up",
+            State::Down => "down",
+            State::AuthFailed => "auth-failed",
+        }
+    }
+}
+
+impl Probe {
+    /// Round-trip time formatted for display, `-` if unknown.
+    pub fn rtt_label(&self) -> String {
+        match self.rtt {
+            Some(rtt) => format!("{} ms", rtt.as_millis()),
+            None => String::from("-"),
+        }
+    }
+}
+
+/// Probes up to `--concurrency` hosts at a time, returning results in the same order as `hosts`.
+pub fn probe_all(hosts: &[Host], level: Level, timeout: Duration) -> std::vec::Vec<Probe> {
+    executor::block_on(probe_hosts(hosts, level, timeout, args::limits().concurrency))
+}
+
+/// Probes up to `concurrency` hosts at a time on the current runtime, returning results in the
+/// same order as `hosts`. TCP and banner probes don't occupy a thread while they wait, auth probes
+/// occupy one each, see `probe`.
+pub async fn probe_hosts(hosts: &[Host], level: Level, timeout: Duration, concurrency: usize) -> std::vec::Vec<Probe> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let tasks: std::vec::Vec<_> = hosts.iter().cloned().map(|host| {
+        let permits = permits.clone();
+        tokio::spawn(async move {
+            let permit = permits.acquire_owned().await.expect("the semaphore is never closed");
+            probe_holding(&host, level, timeout, Some(permit)).await
+        })
+    }).collect();
+    let mut probes = vec!();
+    for task in tasks {
+        probes.push(task.await.expect("probe task panicked"));
+    }
+    probes
+}
+
+/// Probes a single host up to `level`. The ssh handshake of an auth probe runs on a thread of the
+/// blocking pool that libssh can't be stopped on, so a handshake that timed out keeps the thread
+/// until libssh gives up.
+pub async fn probe(host: &Host, level: Level, timeout: Duration) -> Probe {
+    probe_holding(host, level, timeout, None).await
+}
+
+/// Probes a single host like `probe`, holding `permit` until it is done, that of a handshake that
+/// timed out included.
+async fn probe_holding(host: &Host, level: Level, timeout: Duration, permit: Option<OwnedSemaphorePermit>) -> Probe {
+    let down = |detail: String| Probe { alias: host.alias.clone(), state: State::Down, rtt: None, detail };
+
+    // Open the TCP connection, trying every address the host name resolves to.
+    let addrs = match tokio::net::lookup_host((host.ip.as_str(), host.port)).await {
+        Ok(addrs) => addrs,
+        Err(err) => return down(err.to_string()),
+    };
+    let mut last_err = String::from("no addresses found");
+    let mut connected = None;
+    for addr in addrs {
+        let start = Instant::now();
+        match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                connected = Some((stream, start.elapsed()));
+                break;
+            }
+            Ok(Err(err)) => last_err = err.to_string(),
+            Err(_) => last_err = String::from("connection timed out"),
+        }
+    }
+    let (stream, rtt) = match connected {
+        Some(connected) => connected,
+        None => return down(last_err),
+    };
+    let up = |detail: String| Probe { alias: host.alias.clone(), state: State::Up, rtt: Some(rtt), detail };
+    if level == Level::Tcp {
+        return up(String::new());
+    }
+
+    // Wait for the server identification line.
+    let mut banner = String::new();
+    match tokio::time::timeout(timeout, BufReader::new(stream).read_line(&mut banner)).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(err)) => return down(format!("no ssh banner: {}", err)),
+        Err(_) => return down(String::from("no ssh banner: timed out")),
+    }
+    let banner = banner.trim().to_string();
+    if !banner.starts_with("SSH-") {
+        return down(format!("unexpected banner: {}", banner));
+    }
+    if level == Level::Banner {
+        return up(banner);
+    }
+
+    // Run the full handshake on the blocking pool since libssh offers no timeout of its own.
+    let auth_host = host.clone();
+    let handshake = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        check_auth(&auth_host)
+    });
+    match tokio::time::timeout(timeout, handshake).await {
+        Ok(Ok(Ok(()))) => up(banner),
+        Ok(Ok(Err(ConnectError::AuthFailed(err)))) => Probe { alias: host.alias.clone(), state: State::AuthFailed, rtt: Some(rtt), detail: err },
+        Ok(Ok(Err(ConnectError::Unreachable(err)))) => down(err),
+        Ok(Err(err)) => down(err.to_string()),
+        Err(_) => down(String::from("ssh handshake timed out")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    fn local_host(port: u16) -> Host {
+        let mut host = Host::new("local", "127.0.0.1", "root", "/root/.ssh/key");
+        host.port = port;
+        host
+    }
+
+    #[test]
+    fn reports_a_listening_port_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = local_host(listener.local_addr().unwrap().port());
+        let probe = executor::block_on(probe(&host, Level::Tcp, Duration::from_secs(2)));
+        assert_eq!(probe.state, State::Up);
+        assert!(probe.rtt.is_some());
+    }
+
+    #[test]
+    fn reports_a_refused_port_down() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let probe = executor::block_on(probe(&local_host(port), Level::Tcp, Duration::from_secs(2)));
+        assert_eq!(probe.state, State::Down);
+        assert_eq!(probe.rtt, None);
+        assert_eq!(probe.rtt_label(), "-");
+    }
+
+    #[test]
+    fn checks_the_ssh_banner() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = local_host(listener.local_addr().unwrap().port());
+        let server = std::thread::spawn(move || {
+            for banner in ["SSH-2.0-OpenSSH_9.6\r\n", "HTTP/1.1 400 Bad Request\r\n"].iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.write_all(banner.as_bytes()).unwrap();
+            }
+        });
+        let first = executor::block_on(probe_hosts(std::slice::from_ref(&host), Level::Banner, Duration::from_secs(2), 1));
+        let second = executor::block_on(probe_hosts(std::slice::from_ref(&host), Level::Banner, Duration::from_secs(2), 1));
+        server.join().unwrap();
+        assert_eq!((first[0].state, first[0].detail.as_str()), (State::Up, "SSH-2.0-OpenSSH_9.6"));
+        assert_eq!(second[0].state, State::Down);
+        assert!(second[0].detail.starts_with("unexpected banner"), "{}", second[0].detail);
+    }
+}
@@ -0,0 +1,184 @@
+//! Provides `{{placeholder}}` rendering of commands and templates with per-host variables.
+//!
+//! A template may use the host's fields (`{{alias}}`, `{{ip}}`, `{{ssh_user}}`, `{{description}}`,
+//! `{{tags}}`), its variables (`{{vars.role}}`, group variables first, then the host's own) and its
+//! cached facts (`{{facts.os}}`, or just `{{os}}`). Rendering fails on undefined names rather than
+//! sending a half-rendered command.
+//!
+//! Only `{{name}}`, with dotted names and an optional `| filter`, is a placeholder. Anything else
+//! between braces, like the Go templates of `docker inspect -f '{{.State.Status}}'`, is passed
+//! through as it is, and `{{{{` puts a literal `{{` in front of what would be a placeholder, as in
+//! `{{{{end}}`.
+//!
+//! In commands every value is inserted as a single-quoted shell word, so a variable can never
+//! inject shell syntax. `{{vars.opts | raw}}` inserts a trusted value as it is.
+
+use crate::facts::{self, Facts};
+use crate::host::{self, Group, Host};
+use crate::ssh_con::shell_quote;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// How values are inserted into the rendered text.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Escape {
+    Shell,                              // Values become single-quoted shell words.
+    None,                               // Values are inserted as they are, for file templates.
+}
+
+/// Renders templates for hosts of the inventory, holding the group variables they inherit.
+pub struct Renderer {
+    groups: BTreeMap<String, Group>,
+}
+
+impl Renderer {
+    /// Loads the group variables of the inventory.
+    pub fn load() -> Renderer {
+        Renderer { groups: host::get_groups() }
+    }
+
+    /// Every name a template can use for this host.
+    pub fn variables(&self, host: &Host) -> Facts {
+        let mut variables = Facts::new();
+        if let Some(cached) = facts::cached(host) {
+            for (key, value) in cached {
+                variables.insert(format!("facts.{}", key), value.clone());
+                variables.insert(key, value);
+            }
+        }
+        for (key, value) in host::effective_vars(host, &self.groups) {
+            variables.insert(format!("vars.{}", key), value);
+        }
+        facts::with_host_fields(host, variables)
+    }
+
+    /// Renders `template` for one host.
+    pub fn render(&self, host: &Host, template: &str, escape: Escape) -> Result<String, String> {
+        if !template.contains("{{") {
+            return Ok(template.to_string());
+        }
+        render(template, &self.variables(host), escape).map_err(|err| format!("{}: {}", host.alias, err))
+    }
+
+    /// Renders a command for every host, failing if any of them can't be rendered.
+    pub fn commands(&self, hosts: &[Host], template: &str) -> Result<std::vec::Vec<String>, String> {
+        hosts.iter().map(|host| self.render(host, template, Escape::Shell)).collect()
+    }
+}
+
+/// Replaces every `{{name}}` or `{{name | raw}}` in `template` with its value from `variables`.
+/// Braces that don't hold a placeholder are kept, `{{{{` stands for a literal `{{`.
+pub fn render(template: &str, variables: &Facts, escape: Escape) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("{{{{") {
+            rendered.push_str("{{");
+            rest = escaped;
+            continue;
+        }
+        let captures = match placeholder().captures(rest) {
+            Some(captures) => captures,
+            None => {
+                rendered.push_str("{{");
+                rest = &rest[2..];
+                continue;
+            }
+        };
+        let name = &captures[1];
+        let filters: std::vec::Vec<&str> = captures[2].split('|').map(str::trim).filter(|filter| !filter.is_empty()).collect();
+        let raw = match filters.as_slice() {
+            [] => false,
+            ["raw"] => true,
+            [filter] => return Err(format!("unknown filter '{}'", filter)),
+            _ => return Err(format!("only one filter is allowed in '{}'", &captures[0])),
+        };
+        let value = variables.get(name).ok_or_else(|| format!("undefined variable '{}'", name))?;
+        if raw || escape == Escape::None {
+            rendered.push_str(value);
+        } else {
+            rendered.push_str(&shell_quote(value));
+        }
+        rest = &rest[captures[0].len()..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Matches a placeholder at the start of the text: a dotted name and any filters.
+fn placeholder() -> &'static Regex {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| {
+        Regex::new(r"^\{\{\s*([A-Za-z_][\w-]*(?:\.[A-Za-z_][\w-]*)*)\s*((?:\|\s*[A-Za-z_]\w*\s*)*)\}\}").expect("the placeholder pattern is valid")
+    })
+}
+
+/// This function handles `$rman render`, printing the command as each target host would receive it.
+/// Fails with the reasons if it can't be rendered for some host.
+pub fn base(targets: &str, template: &str) -> Result<(), String> {
+    let hosts = host::resolve_targets(targets)?;
+    let renderer = Renderer::load();
+    let mut errors = vec!();
+    for host in hosts.iter() {
+        match renderer.render(host, template, Escape::Shell) {
+            Ok(command) => println!("{}: {}", host.alias, command),
+            Err(err) => errors.push(err),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join("\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Facts {
+        let mut variables = Facts::new();
+        variables.insert(String::from("alias"), String::from("web01"));
+        variables.insert(String::from("vars.motd"), String::from("it's up; rm -rf /"));
+        variables
+    }
+
+    #[test]
+    fn quotes_values_in_commands() {
+        let rendered = render("echo {{vars.motd}} on {{alias}}", &variables(), Escape::Shell).unwrap();
+        assert_eq!(rendered, "echo 'it'\\''s up; rm -rf /' on 'web01'");
+    }
+
+    #[test]
+    fn inserts_raw_values_and_file_templates_as_they_are() {
+        assert_eq!(render("{{ vars.motd | raw }}", &variables(), Escape::Shell).unwrap(), "it's up; rm -rf /");
+        assert_eq!(render("host={{alias}}\n", &variables(), Escape::None).unwrap(), "host=web01\n");
+        assert_eq!(render("no placeholders", &variables(), Escape::Shell).unwrap(), "no placeholders");
+    }
+
+    #[test]
+    fn rejects_undefined_names_and_unknown_filters() {
+        assert!(render("{{vars.role}}", &variables(), Escape::Shell).unwrap_err().contains("undefined variable 'vars.role'"));
+        assert!(render("{{alias | upper}}", &variables(), Escape::Shell).unwrap_err().contains("unknown filter 'upper'"));
+        assert!(render("{{alias | raw | raw}}", &variables(), Escape::Shell).is_err());
+    }
+
+    #[test]
+    fn passes_go_templates_and_other_braces_through() {
+        let command = "docker inspect -f '{{.State.Status}}' {{alias}}";
+        assert_eq!(render(command, &variables(), Escape::Shell).unwrap(), "docker inspect -f '{{.State.Status}}' 'web01'");
+        let command = "kubectl get pods -o go-template='{{range .items}}{{.metadata.name}} {{ json . }}{{\"\\n\"}}'";
+        assert_eq!(render(command, &variables(), Escape::Shell).unwrap(), command);
+        assert_eq!(render("echo {{alias", &variables(), Escape::None).unwrap(), "echo {{alias");
+    }
+
+    #[test]
+    fn escapes_braces_that_would_be_a_placeholder() {
+        let command = "kubectl get pods -o go-template='{{range .items}}{{.metadata.name}}{{{{end}}' # {{alias}}";
+        let rendered = render(command, &variables(), Escape::None).unwrap();
+        assert_eq!(rendered, "kubectl get pods -o go-template='{{range .items}}{{.metadata.name}}{{end}}' # web01");
+        assert_eq!(render("{{{{{{alias}}}}", &variables(), Escape::None).unwrap(), "{{web01}}");
+    }
+}
@@ -0,0 +1,127 @@
+//! Provides `$rman retry`, which re-runs a recorded command on the hosts where it failed.
+//!
+//! Retries are appended to the history as their own entries pointing back at the original run
+//! (`retry_of`), which keeps the audit log append-only. `$rman history show` merges them into
+//! the original run, and retrying the same run again only targets hosts that still failed.
+
+use crate::all;
+use crate::args;
+use crate::guard;
+use crate::history::{self, Run};
+use crate::host::get_hosts;
+
+/// Actions that can be retried, other entries don't carry a plain remote command.
+const RETRYABLE: [&str; 2] = ["host exec", "all exec"];
+
+/// This function handles all `$rman retry` commands.
+pub fn base(run_id: Option<String>, failed: bool, unreachable: bool) {
+    // Without a filter both failed and unreachable hosts are retried. Interrupted hosts never are,
+    // the command may have run on them.
+    let (failed, unreachable) = if failed || unreachable { (failed, unreachable) } else { (true, true) };
+
+    let id = match run_id {
+        Some(id) => original_id(&id),
+        None => match last_retryable() {
+            Some(id) => id,
+            None => {
+                println!("No run to retry");
+                return;
+            }
+        },
+    };
+    let (run, _) = match history::merged(&id) {
+        Some(merged) => merged,
+        None => {
+            println!("No run with id {}", id);
+            return;
+        }
+    };
+    if !RETRYABLE.contains(&run.action.as_str()) {
+        println!("Run {} is a {}, only {} runs can be retried", run.id, run.action, RETRYABLE.join(" and "));
+        return;
+    }
+
+    let aliases: std::vec::Vec<String> = run.results.iter()
+        .filter(|result| (failed && result.status == "failed") || (unreachable && result.status == "unreachable"))
+        .map(|result| result.alias.clone())
+        .collect();
+    if aliases.is_empty() {
+        println!("Nothing to retry, every selected host of run {} succeeded", run.id);
+        return;
+    }
+    let inventory = match get_hosts() {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let hosts: std::vec::Vec<_> = inventory.into_iter().filter(|host| aliases.contains(&host.alias)).collect();
+    for alias in aliases.iter().filter(|alias| !hosts.iter().any(|host| &host.alias == *alias)) {
+        println!("Skipping {}, it is no longer in the inventory", alias);
+    }
+    if hosts.is_empty() {
+        return;
+    }
+
+    if args::check_mode() {
+        all::preview(&hosts, &[&run.command]);
+        return;
+    }
+    let commands = all::render_commands(&hosts, &run.command);
+    guard::confirm_command("retry", &run.command, &hosts, &commands);
+    println!("Retrying \"{}\" from run {} on {} hosts", run.command, run.id, hosts.len());
+    let targets = hosts.iter().map(|host| host.alias.clone()).collect();
+    let results = all::run_commands(hosts, &commands);
+    history::record_retry("retry", &run.command, targets, history::results(&results), Some(run.id.clone()));
+}
+
+/// Follows `retry_of` back to the original run, so retrying a retry extends the original.
+fn original_id(id: &str) -> String {
+    follow_retries(&history::load(), id)
+}
+
+/// Follows `retry_of` links from `id` among `runs`, stopping where they form a cycle, e.g. in a
+/// hand-edited history.
+fn follow_retries(runs: &[Run], id: &str) -> String {
+    let mut visited = vec!(id.to_string());
+    let mut id = id.to_string();
+    while let Some(retry_of) = runs.iter().find(|run| run.id == id).and_then(|run| run.retry_of.clone()) {
+        if visited.contains(&retry_of) {
+            break;
+        }
+        visited.push(retry_of.clone());
+        id = retry_of;
+    }
+    id
+}
+
+/// Id of the most recent run that can be retried.
+fn last_retryable() -> Option<String> {
+    history::load().into_iter().rev()
+        .find(|run| RETRYABLE.contains(&run.action.as_str()))
+        .map(|run| run.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(id: &str, retry_of: Option<&str>) -> Run {
+        Run { retry_of: retry_of.map(String::from), ..history::test_run(id, "retry", "uptime") }
+    }
+
+    #[test]
+    fn follows_retries_back_to_the_original_run() {
+        let runs = vec!(run("a", None), run("b", Some("a")), run("c", Some("b")));
+        assert_eq!(follow_retries(&runs, "c"), "a");
+        assert_eq!(follow_retries(&runs, "unknown"), "unknown");
+    }
+
+    #[test]
+    fn stops_at_cycles() {
+        let runs = vec!(run("a", Some("c")), run("b", Some("a")), run("c", Some("b")));
+        assert_eq!(follow_retries(&runs, "c"), "a");
+        assert_eq!(follow_retries(&[run("a", Some("a"))], "a"), "a");
+    }
+}
@@ -0,0 +1,494 @@
+//! Provides `$rman run`, which executes a runbook of ordered steps from a TOML file.
+//!
+//! ```toml
+//! targets = "web-*"                   # hosts the runbook applies to, defaults to every host
+//!
+//! [[step]]
+//! name = "kernel version"
+//! command = "uname -r"
+//! register = "kernel"                 # kernel.stdout, kernel.rc, kernel.changed and kernel.failed become variables
+//!
+//! [[step]]
+//! name = "nginx config"
+//! push = { src = "nginx.conf", dest = "/etc/nginx/nginx.conf", mode = "644" }
+//! when = "os=ubuntu"                  # a `--where` expression over facts and registered variables
+//! notify = ["reload nginx"]
+//!
+//! [[step]]
+//! name = "nginx running"
+//! module = { type = "service", name = "nginx", state = "started", enabled = true }
+//!
+//! [[step]]
+//! name = "reboot old kernels"
+//! reboot = true
+//! wait = 300                          # seconds to wait for the host to come back
+//! when = "kernel.stdout<5.15"
+//! ignore_errors = true
+//!
+//! [[handler]]
+//! name = "reload nginx"
+//! command = "systemctl reload nginx"
+//! ```
+//!
+//! Steps run in order, each on all of its hosts before the next one starts. A host whose step
+//! fails is left out of the remaining steps unless the step sets `ignore_errors`. Handlers run once
+//! at the end, on the hosts where a step notifying them reported a change.
+//!
+//! With `--check` commands are skipped, while pushes, modules and reboots report whether they
+//! would change something without touching the hosts.
+
+use crate::args;
+use crate::facts::{self, Facts};
+use crate::filter::{self, Expr};
+use crate::guard::{self, Guard};
+use crate::history::{self, HostResult};
+use crate::host::{get_hosts, Host};
+use crate::modules::Module;
+use crate::ssh_con::{check_privs, push_file, run_remote_command, shell_quote};
+use crate::style;
+use crate::targets;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// A runbook file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Runbook {
+    pub targets: Option<String>,
+    #[serde(rename = "where")]
+    pub where_expr: Option<String>,
+    #[serde(default, rename = "step")]
+    pub steps: std::vec::Vec<Step>,
+    #[serde(default, rename = "handler")]
+    pub handlers: std::vec::Vec<Step>,
+}
+
+/// A single step or handler. Exactly one of `command`, `push`, `module` and `reboot` has to be given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub name: String,
+    pub command: Option<String>,
+    pub push: Option<Push>,
+    pub module: Option<Module>,
+    #[serde(default)]
+    pub reboot: bool,
+    #[serde(default = "default_wait")]
+    pub wait: u64,                      // Seconds to wait for a rebooted host to come back.
+    pub targets: Option<String>,        // Narrows the runbook's hosts for this step.
+    #[serde(rename = "where")]
+    pub where_expr: Option<String>,     // Narrows the hosts by facts, like `--where`.
+    pub when: Option<String>,           // Skips hosts where this expression is false.
+    pub changed_when: Option<String>,   // Decides whether a command changed something, from `rc` and `stdout`.
+    #[serde(skip)]
+    changed_expr: Option<Expr>,         // `changed_when` as parsed by `load`.
+    pub register: Option<String>,
+    #[serde(default)]
+    pub ignore_errors: bool,
+    #[serde(default)]
+    pub notify: std::vec::Vec<String>,
+}
+
+/// A local file to upload.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Push {
+    pub src: String,                    // Relative to the runbook's directory.
+    pub dest: String,
+    pub mode: Option<String>,           // Octal, defaults to 644.
+}
+
+fn default_wait() -> u64 {
+    300
+}
+
+/// Outcome of a step on one host.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    Changed,
+    Skipped,
+    Failed,
+}
+
+/// Result of a step on one host.
+pub struct Outcome {
+    pub status: Status,
+    pub rc: Option<i32>,
+    pub output: String,
+}
+
+/// Progress of a host through the runbook.
+#[derive(Default)]
+struct HostState {
+    vars: Facts,                        // Registered variables.
+    facts: Option<Facts>,               // Loaded on the first `when` that needs them.
+    notified: std::vec::Vec<String>,
+    failed: bool,
+    counts: [usize; 4],                 // ok, changed, skipped, failed
+}
+
+impl Outcome {
+    fn failed(output: String) -> Outcome {
+        Outcome { status: Status::Failed, rc: None, output }
+    }
+}
+
+impl Status {
+    fn label(self) -> String {
+        match self {
+            Status::Ok => style::green("ok"),
+            Status::Changed => style::yellow("changed"),
+            Status::Skipped => String::from("skipped"),
+            Status::Failed => style::red("failed"),
+        }
+    }
+}
+
+/// This function handles all `$rman run` commands.
+pub fn base(path: &Path) {
+    let runbook = match load(path) {
+        Ok(runbook) => runbook,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if !execute(&runbook, path) {
+        std::process::exit(1);
+    }
+}
+
+/// Reads and checks a runbook file.
+pub fn load(path: &Path) -> Result<Runbook, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+    let mut runbook: Runbook = toml::from_str(&contents).map_err(|err| format!("Invalid runbook {}: {}", path.display(), err))?;
+    // Expressions are checked up front, so a typo fails the runbook before anything runs.
+    if let Some(ref spec) = runbook.targets {
+        targets::check(spec).map_err(|err| format!("Invalid targets: {}", err))?;
+    }
+    if let Some(ref expr) = runbook.where_expr {
+        filter::parse(expr).map_err(|err| format!("Invalid where: {}", err))?;
+    }
+    for step in runbook.steps.iter().chain(runbook.handlers.iter()) {
+        let actions = step.command.is_some() as u8 + step.push.is_some() as u8 + step.module.is_some() as u8 + step.reboot as u8;
+        if actions != 1 {
+            return Err(format!("Step '{}' needs exactly one of command, push, module or reboot", step.name));
+        }
+        if let Some(ref module) = step.module {
+            module.checks().map_err(|err| format!("Step '{}': {}", step.name, err))?;
+        }
+        if let Some(ref spec) = step.targets {
+            targets::check(spec).map_err(|err| format!("Step '{}' has invalid targets: {}", step.name, err))?;
+        }
+        for (key, expr) in [("where", &step.where_expr), ("when", &step.when)] {
+            if let Some(expr) = expr {
+                filter::parse(expr).map_err(|err| format!("Step '{}' has an invalid {}: {}", step.name, key, err))?;
+            }
+        }
+        for handler in step.notify.iter() {
+            if !runbook.handlers.iter().any(|h| &h.name == handler) {
+                return Err(format!("Step '{}' notifies unknown handler '{}'", step.name, handler));
+            }
+        }
+    }
+    // Kept parsed, a command that ran must not fail over its `changed_when`.
+    for step in runbook.steps.iter_mut().chain(runbook.handlers.iter_mut()) {
+        if let Some(ref expr) = step.changed_when {
+            step.changed_expr = Some(filter::parse(expr).map_err(|err| format!("Step '{}' has an invalid changed_when: {}", step.name, err))?);
+        }
+    }
+    Ok(runbook)
+}
+
+/// Runs every step and the notified handlers, returning whether all hosts succeeded.
+pub fn execute(runbook: &Runbook, path: &Path) -> bool {
+    let inventory = match get_hosts() {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            println!("{}", err);
+            return false;
+        }
+    };
+    let mut hosts = match targets::resolve(runbook.targets.as_deref().unwrap_or("*"), &inventory) {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            return false;
+        }
+    };
+    if let Some(ref expr) = runbook.where_expr {
+        hosts = match filter::select(hosts, expr, false) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                println!("{}", err);
+                return false;
+            }
+        };
+    }
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    if args::check_mode() {
+        println!("Check mode, commands are skipped and nothing is changed");
+    }
+    // Steps may narrow the hosts further, a destructive step is confirmed for all of them.
+    let guard = Guard::load();
+    let destructive: std::vec::Vec<&str> = runbook.steps.iter().chain(runbook.handlers.iter())
+        .filter(|step| step.reboot || step.command.as_deref().map(|command| guard.is_destructive(command)).unwrap_or(false))
+        .map(|step| step.name.as_str())
+        .collect();
+    if !destructive.is_empty() {
+        guard::confirm(&format!("run the steps {} of {}", destructive.join(", "), path.display()), &hosts);
+    }
+    let start = Instant::now();
+    let mut states: HashMap<String, HostState> = hosts.iter().map(|host| (host.alias.clone(), HostState::default())).collect();
+
+    for step in runbook.steps.iter() {
+        let active: std::vec::Vec<Host> = hosts.iter().filter(|host| !states[&host.alias].failed).cloned().collect();
+        run_step(step, &active, &inventory, base_dir, &mut states);
+    }
+    for handler in runbook.handlers.iter() {
+        let notified: std::vec::Vec<Host> = hosts.iter()
+            .filter(|host| !states[&host.alias].failed && states[&host.alias].notified.contains(&handler.name))
+            .cloned()
+            .collect();
+        if !notified.is_empty() {
+            run_step(handler, &notified, &inventory, base_dir, &mut states);
+        }
+    }
+
+    // Recap and record the run.
+    println!("\n{}", style::bold("RECAP"));
+    let mut results = vec!();
+    for host in hosts.iter() {
+        let state = &states[&host.alias];
+        println!("{:<20} ok={} changed={} skipped={} failed={}", host.alias, state.counts[0], state.counts[1], state.counts[2], state.counts[3]);
+        results.push(HostResult {
+            alias: host.alias.clone(),
+            status: String::from(if state.failed { "failed" } else { "ok" }),
+            exit_status: None,
+            duration_ms: start.elapsed().as_millis() as u64,
+            output: None,
+        });
+    }
+    history::record("run", &path.display().to_string(), hosts.iter().map(|host| host.alias.clone()).collect(), results);
+    !states.values().any(|state| state.failed)
+}
+
+/// Runs one step on the hosts it applies to.
+fn run_step(step: &Step, active: &[Host], inventory: &[Host], base_dir: &Path, states: &mut HashMap<String, HostState>) {
+    println!("\n{} [{}]", style::bold("STEP"), step.name);
+    let mut hosts = active.to_vec();
+    // Hosts the step can't be resolved for fail it rather than silently skip it.
+    if let Some(ref spec) = step.targets {
+        match targets::resolve(spec, inventory) {
+            Ok(selected) => hosts.retain(|host| selected.iter().any(|s| s.alias == host.alias)),
+            Err(err) => return fail_step(step, &hosts, states, &err),
+        }
+    }
+    if let Some(ref expr) = step.where_expr {
+        hosts = match filter::select(hosts.clone(), expr, false) {
+            Ok(hosts) => hosts,
+            Err(err) => return fail_step(step, &hosts, states, &err),
+        };
+    }
+    let when = match step.when.as_ref().map(|when| filter::parse(when)).transpose() {
+        Ok(when) => when,
+        Err(err) => return fail_step(step, &hosts, states, &err),
+    };
+
+    for host in hosts.iter() {
+        let state = states.get_mut(&host.alias).expect("every targeted host has a state");
+        let outcome = match when {
+            Some(ref when) if !when.eval(&variables(host, state)) => Outcome { status: Status::Skipped, rc: None, output: String::new() },
+            _ => perform(step, host, base_dir),
+        };
+
+        if let Some(ref name) = step.register {
+            state.vars.insert(format!("{}.stdout", name), outcome.output.trim().to_string());
+            state.vars.insert(format!("{}.rc", name), outcome.rc.map(|rc| rc.to_string()).unwrap_or_default());
+            state.vars.insert(format!("{}.changed", name), (outcome.status == Status::Changed).to_string());
+            state.vars.insert(format!("{}.failed", name), (outcome.status == Status::Failed).to_string());
+        }
+        match outcome.status {
+            Status::Ok => state.counts[0] += 1,
+            Status::Changed => {
+                state.counts[1] += 1;
+                state.notified.extend(step.notify.iter().cloned());
+            }
+            Status::Skipped => state.counts[2] += 1,
+            Status::Failed => {
+                state.counts[3] += 1;
+                state.failed |= !step.ignore_errors;
+            }
+        }
+
+        let ignored = if outcome.status == Status::Failed && step.ignore_errors { " (ignored)" } else { "" };
+        println!("  {}: {}{}", outcome.status.label(), host.alias, ignored);
+        let explain = outcome.status == Status::Failed || (args::check_mode() && outcome.status != Status::Ok);
+        if explain && !outcome.output.trim().is_empty() {
+            println!("    {}", outcome.output.trim().replace('\n', "\n    "));
+        }
+    }
+}
+
+/// Marks a step failed on every host, e.g. when its targets can't be resolved.
+fn fail_step(step: &Step, hosts: &[Host], states: &mut HashMap<String, HostState>, reason: &str) {
+    println!("  {}", reason);
+    for host in hosts.iter() {
+        let state = states.get_mut(&host.alias).expect("every targeted host has a state");
+        state.counts[3] += 1;
+        state.failed |= !step.ignore_errors;
+        let ignored = if step.ignore_errors { " (ignored)" } else { "" };
+        println!("  {}: {}{}", Status::Failed.label(), host.alias, ignored);
+    }
+}
+
+/// Facts of a host together with its registered variables, used to evaluate `when`.
+fn variables(host: &Host, state: &mut HostState) -> Facts {
+    let facts = state.facts.get_or_insert_with(|| facts::get(host, false));
+    let mut variables = facts.clone();
+    variables.extend(state.vars.iter().map(|(key, value)| (key.clone(), value.clone())));
+    variables
+}
+
+/// Performs the action of a step on a host.
+/// In check mode commands are skipped, as their effect can't be known without running them.
+fn perform(step: &Step, host: &Host, base_dir: &Path) -> Outcome {
+    if let Some(ref command) = step.command {
+        if args::check_mode() {
+            return Outcome { status: Status::Skipped, rc: None, output: format!("would run: {}", command) };
+        }
+        run_command(command, step.changed_expr.as_ref(), host)
+    } else if let Some(ref push) = step.push {
+        push_step(push, host, base_dir)
+    } else if let Some(ref module) = step.module {
+        module.apply(host)
+    } else if args::check_mode() {
+        Outcome { status: Status::Changed, rc: None, output: String::from("would reboot") }
+    } else {
+        reboot_and_wait(host, Duration::from_secs(step.wait))
+    }
+}
+
+/// Runs a command, which counts as a change unless `changed_when` says otherwise.
+pub fn run_command(command: &str, changed_when: Option<&Expr>, host: &Host) -> Outcome {
+    let result = run_remote_command(host, command);
+    if !result.connected || result.exit_status.unwrap_or(0) != 0 {
+        return Outcome { status: Status::Failed, rc: result.exit_status, output: result.output };
+    }
+    let changed = match changed_when {
+        Some(expr) => {
+            let mut facts = Facts::new();
+            facts.insert(String::from("rc"), result.exit_status.unwrap_or(0).to_string());
+            facts.insert(String::from("stdout"), result.output.trim().to_string());
+            expr.eval(&facts)
+        }
+        None => true,
+    };
+    Outcome { status: if changed { Status::Changed } else { Status::Ok }, rc: result.exit_status, output: result.output }
+}
+
+/// Uploads a file unless the remote copy already has the same content and mode.
+fn push_step(push: &Push, host: &Host, base_dir: &Path) -> Outcome {
+    let src = base_dir.join(&push.src);
+    let contents = match std::fs::read(&src) {
+        Ok(contents) => contents,
+        Err(err) => return Outcome::failed(format!("Unable to read {}: {}", src.display(), err)),
+    };
+    let mode = push.mode.as_deref().unwrap_or("644");
+    let mode_bits = match usize::from_str_radix(mode, 8) {
+        Ok(bits) => bits,
+        Err(_) => return Outcome::failed(format!("Invalid mode '{}'", mode)),
+    };
+    push_contents(host, &contents, &push.dest, mode_bits)
+}
+
+/// Uploads `contents` to `dest` if the remote file differs in content or mode.
+pub fn push_contents(host: &Host, contents: &[u8], dest: &str, mode: usize) -> Outcome {
+    let expected = format!("{} {:o}", hex::encode(Sha256::digest(contents)), mode);
+    let check = format!("printf '%s %s' \"$(sha256sum -- {dest} 2>/dev/null | cut -d' ' -f1)\" \"$(stat -c %a -- {dest} 2>/dev/null)\"", dest = shell_quote(dest));
+    let current = run_remote_command(host, &check);
+    if !current.connected {
+        return Outcome::failed(current.output);
+    }
+    if current.output.trim() == expected {
+        return Outcome { status: Status::Ok, rc: Some(0), output: String::new() };
+    }
+    if args::check_mode() {
+        return Outcome { status: Status::Changed, rc: None, output: format!("would upload {}", dest) };
+    }
+    match push_file(host, contents, dest, mode) {
+        // scp only applies the mode to new files, so set it explicitly.
+        Ok(()) => {
+            let chmod = run_remote_command(host, &format!("chmod {:o} -- {}", mode, shell_quote(dest)));
+            if chmod.exit_status.unwrap_or(0) != 0 {
+                return Outcome::failed(chmod.output);
+            }
+            Outcome { status: Status::Changed, rc: Some(0), output: String::new() }
+        }
+        Err(err) => Outcome::failed(err),
+    }
+}
+
+/// Reboots a host and waits until it is back with a new boot id.
+fn reboot_and_wait(host: &Host, wait: Duration) -> Outcome {
+    let boot_id_cmd = "cat /proc/sys/kernel/random/boot_id";
+    let before = run_remote_command(host, boot_id_cmd);
+    if !before.connected {
+        return Outcome::failed(before.output);
+    }
+    if !check_privs(host) {
+        return Outcome::failed(String::from("User lacks privileges to execute this command."));
+    }
+    // The connection usually drops while the command runs, so its result is meaningless.
+    run_remote_command(host, "shutdown -r now");
+    let start = Instant::now();
+    while start.elapsed() < wait {
+        std::thread::sleep(Duration::from_secs(5));
+        let after = run_remote_command(host, boot_id_cmd);
+        if after.connected && after.output.trim() != before.output.trim() {
+            return Outcome { status: Status::Changed, rc: Some(0), output: String::new() };
+        }
+    }
+    Outcome::failed(format!("Host did not come back within {} seconds", wait.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_str(name: &str, contents: &str) -> Result<Runbook, String> {
+        let path = std::env::temp_dir().join(format!("rman-runbook-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let runbook = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        runbook
+    }
+
+    #[test]
+    fn loads_steps_and_handlers() {
+        let runbook = load_str("valid", "targets = \"web[01:02]\"\n[[step]]\nname = \"a\"\ncommand = \"true\"\nwhen = \"os=ubuntu\"\nnotify = [\"h\"]\n[[handler]]\nname = \"h\"\nreboot = true\n").unwrap();
+        assert_eq!((runbook.steps.len(), runbook.handlers.len()), (1, 1));
+        let runbook = load_str("changed", "[[step]]\nname = \"a\"\ncommand = \"true\"\nchanged_when = \"rc=0\"\n").unwrap();
+        assert!(runbook.steps[0].changed_expr.is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_expressions_before_running() {
+        assert!(load_str("targets", "targets = \"~(\"\n").is_err());
+        assert!(load_str("where", "where = \"os=\"\n").is_err());
+        assert!(load_str("step-targets", "[[step]]\nname = \"a\"\ncommand = \"true\"\ntargets = \"web[3:1]\"\n").is_err());
+        assert!(load_str("step-when", "[[step]]\nname = \"a\"\ncommand = \"true\"\nwhen = \"(os=ubuntu\"\n").is_err());
+        let err = load_str("changed-when", "[[step]]\nname = \"a\"\ncommand = \"true\"\nchanged_when = \"stdout~=\"\n").err().unwrap();
+        assert!(err.contains("invalid changed_when"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_steps_without_exactly_one_action_and_unknown_handlers() {
+        assert!(load_str("actions", "[[step]]\nname = \"a\"\ncommand = \"true\"\nreboot = true\n").is_err());
+        assert!(load_str("handler", "[[step]]\nname = \"a\"\ncommand = \"true\"\nnotify = [\"nope\"]\n").is_err());
+    }
+}
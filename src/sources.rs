@@ -0,0 +1,429 @@
+//! Provides dynamic inventory sources, whose hosts are merged with those of the configuration file.
+//!
+//! A source either runs a local executable or reads a JSON file, e.g. a CMDB export or Terraform
+//! output. The output of executables is cached for `ttl` seconds:
+//!
+//! ```toml
+//! [sources.cmdb]
+//! command = ["/usr/local/bin/cmdb-export", "--env", "prod"]
+//! ttl = 300               # Seconds the output is reused for, 0 runs the command every time.
+//! timeout = 30            # Seconds the command may take.
+//!
+//! [sources.terraform]
+//! file = "terraform/rman.json"    # Relative paths start at the configuration file's directory.
+//! ```
+//!
+//! Both print or contain a JSON object with optional `hosts`, `groups` and `vars`:
+//!
+//! ```json
+//! {
+//!   "hosts": {
+//!     "web01": {"ip": "10.0.0.5", "tags": ["web"], "vars": {"role": "frontend"}},
+//!     "db01": {"ip": "10.0.1.7", "ssh_user": "postgres", "port": 2222}
+//!   },
+//!   "groups": {
+//!     "db": {"hosts": ["db01"], "pk_path": "~/.ssh/db", "vars": {"backup": true}}
+//!   },
+//!   "vars": {"dc": "fra1"}
+//! }
+//! ```
+//!
+//! Hosts take `ip` and optionally `ssh_user`, `pk_path`, `port`, `timeout`, `become`,
+//! `description`, `tags` and `vars`; other fields are ignored. Groups take the same settings and
+//! `vars`, and add their name to the tags of the hosts they list. Settings a host doesn't set are
+//! inherited like for hosts of the configuration file. A setting of a source's group takes
+//! precedence over the same setting of a `[groups.<name>]` section of the same name, whose other
+//! settings and variables still apply. Variables of the source and of its groups are merged into
+//! those of each host. Hosts of the configuration file win over dynamic hosts with the same alias,
+//! and earlier sources over later ones.
+//!
+//! Problems with sources are reported on stderr, so they don't mix with a command's output.
+//! Sources aren't run while the shell asks for completions.
+
+use crate::context;
+use crate::host::{self, Group, Host, Settings};
+use crate::store;
+use crate::validate;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Seconds the output of a command is reused for unless the source sets `ttl`.
+pub const DEFAULT_TTL: u64 = 300;
+
+/// Seconds a command may take unless the source sets `timeout`.
+pub const DEFAULT_TIMEOUT: u64 = 60;
+
+/// Set while the shell asks rman for completions, see `rman completions`.
+const COMPLETING: &str = "COMPLETE";
+
+/// A `[sources.<name>]` section of the configuration file.
+/// Unknown fields are left to `rman config check` to report.
+#[derive(Deserialize)]
+pub struct Source {
+    pub command: Option<std::vec::Vec<String>>,     // Executable and arguments printing the inventory.
+    pub file: Option<PathBuf>,                      // JSON file holding the inventory.
+    pub ttl: Option<u64>,                           // Seconds the output of `command` is cached for.
+    pub timeout: Option<u64>,                       // Seconds `command` may take.
+}
+
+/// The inventory a source returns.
+#[derive(Deserialize)]
+struct Output {
+    #[serde(default)]
+    hosts: BTreeMap<String, SourceHost>,
+    #[serde(default)]
+    groups: BTreeMap<String, SourceGroup>,
+    #[serde(default)]
+    vars: BTreeMap<String, serde_json::Value>,      // Variables of every host of the source.
+}
+
+/// A host returned by a source.
+#[derive(Deserialize)]
+struct SourceHost {
+    ip: String,
+    #[serde(flatten)]
+    settings: SourceSettings,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    tags: std::vec::Vec<String>,
+    #[serde(default)]
+    vars: BTreeMap<String, serde_json::Value>,
+}
+
+/// A group returned by a source.
+#[derive(Deserialize)]
+struct SourceGroup {
+    #[serde(default)]
+    hosts: std::vec::Vec<String>,                   // Aliases of the hosts tagged with the group.
+    #[serde(flatten)]
+    settings: SourceSettings,
+    #[serde(default)]
+    vars: BTreeMap<String, serde_json::Value>,
+}
+
+/// Connection settings of a host or group returned by a source.
+#[derive(Deserialize)]
+struct SourceSettings {
+    ssh_user: Option<String>,
+    pk_path: Option<String>,
+    port: Option<u16>,
+    timeout: Option<u64>,
+    #[serde(rename = "become")]
+    become_user: Option<serde_json::Value>,         // `true`, `false` or a user name.
+}
+
+impl SourceSettings {
+    fn to_settings(&self) -> Settings {
+        Settings {
+            ssh_user: self.ssh_user.clone(),
+            pk_path: self.pk_path.clone(),
+            port: self.port,
+            timeout: self.timeout,
+            become_user: self.become_user.as_ref().and_then(|value| host::parse_become(&to_string(value))),
+        }
+    }
+
+    /// These settings where they are given, those of `base` otherwise.
+    fn over(&self, base: &Settings) -> Settings {
+        let own = self.to_settings();
+        Settings {
+            ssh_user: own.ssh_user.or_else(|| base.ssh_user.clone()),
+            pk_path: own.pk_path.or_else(|| base.pk_path.clone()),
+            port: own.port.or(base.port),
+            timeout: own.timeout.or(base.timeout),
+            become_user: own.become_user.or_else(|| base.become_user.clone()),
+        }
+    }
+}
+
+/// Reads the `[sources.<name>]` sections of the configuration file at `path`.
+fn read_sources(path: &Path) -> Result<BTreeMap<String, Source>, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(BTreeMap::new()),
+    };
+    let document = contents.parse::<toml::Value>().map_err(|err| err.to_string())?;
+    match document.get("sources") {
+        Some(sources) => sources.clone().try_into().map_err(|err: toml::de::Error| format!("invalid [sources]: {}", err)),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Adds the hosts of the sources of the configuration file at `path` to `hosts`, skipping
+/// aliases that are already taken. Sources that fail are reported and skipped.
+pub fn merge(path: &Path, hosts: &mut std::vec::Vec<Host>, refresh: bool) {
+    // Completions have to be quick and quiet.
+    if std::env::var_os(COMPLETING).is_some_and(|value| !value.is_empty()) {
+        return;
+    }
+    let sources = match read_sources(path) {
+        Ok(sources) => sources,
+        Err(err) => {
+            eprintln!("Ignoring the dynamic inventory of {}: {}", path.display(), err);
+            return;
+        }
+    };
+    for (name, source) in sources.iter() {
+        match load(path, name, source, refresh) {
+            Ok(found) => {
+                for host in found {
+                    if !hosts.iter().any(|other| other.alias == host.alias) {
+                        hosts.push(host);
+                    }
+                }
+            }
+            Err(err) => eprintln!("Ignoring source {}: {}", name, err),
+        }
+    }
+}
+
+/// The hosts of one source.
+fn load(path: &Path, name: &str, source: &Source, refresh: bool) -> Result<std::vec::Vec<Host>, String> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let contents = match (&source.command, &source.file) {
+        (Some(command), None) => cached_output(path, name, source, command, refresh)?,
+        (None, Some(file)) => {
+            let file = dir.join(file);
+            std::fs::read_to_string(&file).map_err(|err| format!("unable to read {}: {}", file.display(), err))?
+        }
+        _ => return Err(String::from("set either `command` or `file`")),
+    };
+    let output: Output = serde_json::from_str(&contents).map_err(|err| format!("invalid output: {}", err))?;
+    Ok(to_hosts(path, name, output))
+}
+
+/// Builds the hosts of a source's output, skipping invalid ones.
+fn to_hosts(path: &Path, name: &str, output: Output) -> std::vec::Vec<Host> {
+    let defaults = host::read_defaults(path);
+    let mut groups = host::read_groups(path);
+    for (group, source_group) in output.groups.iter() {
+        let merged: &mut Group = groups.entry(group.clone()).or_default();
+        merged.settings = source_group.settings.over(&merged.settings);
+    }
+    let source_groups = &output.groups;
+    let mut hosts = vec!();
+    for (alias, source_host) in output.hosts {
+        let mut host = Host::new(&alias, &source_host.ip, "", "");
+        host.description = source_host.description;
+        host.tags = source_host.tags;
+        for (group, source_group) in source_groups.iter() {
+            if source_group.hosts.contains(&alias) && !host.tags.contains(group) {
+                host.tags.push(group.clone());
+            }
+        }
+        // Variables of the source, then of its groups in tag order, then the host's own.
+        let mut vars = to_vars(&output.vars);
+        for group in host.tags.iter().filter_map(|tag| source_groups.get(tag)) {
+            vars.extend(to_vars(&group.vars));
+        }
+        vars.extend(to_vars(&source_host.vars));
+        host.vars = vars;
+        host::inherit(&mut host, &source_host.settings.to_settings(), &defaults, &groups);
+        match validate::check_host(&host) {
+            Ok(()) => hosts.push(host),
+            Err(err) => eprintln!("Ignoring a host of source {}: {}", name, err),
+        }
+    }
+    hosts
+}
+
+/// Converts JSON variables into strings, keeping strings unquoted.
+fn to_vars(vars: &BTreeMap<String, serde_json::Value>) -> BTreeMap<String, String> {
+    vars.iter().map(|(key, value)| (key.clone(), to_string(value))).collect()
+}
+
+fn to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Path of the cached output of a source, kept per configuration file.
+fn cache_path(path: &Path, name: &str) -> PathBuf {
+    let mut cache = match dirs::cache_dir() {
+        Some(buf) => buf,
+        None => context::config_dir(),
+    };
+    cache.push("rman");
+    cache.push("sources");
+    cache.push(store::path_key(path));
+    cache.push(format!("{}.json", name));
+    cache
+}
+
+/// The output of a source's command, from the cache while it is younger than the TTL and than the
+/// configuration file. If the command fails, an outdated cache is used rather than no hosts.
+fn cached_output(path: &Path, name: &str, source: &Source, command: &[String], refresh: bool) -> Result<String, String> {
+    let cache = cache_path(path, name);
+    let ttl = Duration::from_secs(source.ttl.unwrap_or(DEFAULT_TTL));
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let cached_at = modified(&cache);
+    if let Some(cached_at) = cached_at {
+        let age = SystemTime::now().duration_since(cached_at).unwrap_or_default();
+        let changed = modified(path).is_some_and(|changed| changed > cached_at);
+        if !refresh && age < ttl && !changed {
+            if let Ok(contents) = std::fs::read_to_string(&cache) {
+                return Ok(contents);
+            }
+        }
+    }
+    let timeout = Duration::from_secs(source.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    match run(path, command, timeout) {
+        Ok(output) => {
+            // Output that isn't valid JSON isn't cached, the next load runs the command again.
+            if ttl > Duration::ZERO && serde_json::from_str::<serde_json::Value>(&output).is_ok() {
+                let written = cache.parent().map(std::fs::create_dir_all).unwrap_or(Ok(())).and_then(|_| std::fs::write(&cache, &output));
+                if let Err(err) = written {
+                    eprintln!("Unable to cache the output of source {}: {}", name, err);
+                }
+            }
+            Ok(output)
+        }
+        Err(err) => match std::fs::read_to_string(&cache) {
+            Ok(contents) => {
+                let age = cached_at.and_then(|cached_at| SystemTime::now().duration_since(cached_at).ok()).unwrap_or_default();
+                eprintln!("Source {} failed, using its output from {}s ago: {}", name, age.as_secs(), err);
+                Ok(contents)
+            }
+            Err(_) => Err(err),
+        },
+    }
+}
+
+/// Runs a command in the directory of the configuration file, returning what it printed. It is
+/// killed if it takes longer than `timeout`.
+fn run(path: &Path, command: &[String], timeout: Duration) -> Result<String, String> {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Err(String::from("`command` is empty")),
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .current_dir(path.parent().filter(|dir| dir.is_dir()).unwrap_or_else(|| Path::new(".")))
+        .env("RMAN_CONFIG", path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("unable to run {}: {}", program, err))?;
+    // Read on another thread, so a command printing more than a pipe holds can't block the wait.
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} took longer than {}s", program, timeout.as_secs()));
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(20)),
+            Err(err) => return Err(format!("unable to wait for {}: {}", program, err)),
+        }
+    };
+    let output = reader.join().map_err(|_| format!("unable to read the output of {}", program))?
+        .map_err(|err| format!("unable to read the output of {}: {}", program, err))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", program, status));
+    }
+    Ok(output)
+}
+
+/// This function handles `$rman config refresh`, running every source again and listing the hosts it returned.
+pub fn refresh(path: &Path) {
+    let sources = match read_sources(path) {
+        Ok(sources) => sources,
+        Err(err) => {
+            println!("Unable to read the sources of {}: {}", path.display(), err);
+            return;
+        }
+    };
+    if sources.is_empty() {
+        println!("{} has no [sources.<name>] sections", path.display());
+        return;
+    }
+    for (name, source) in sources.iter() {
+        match load(path, name, source, true) {
+            Ok(hosts) => {
+                let aliases: std::vec::Vec<&str> = hosts.iter().map(|host| host.alias.as_str()).collect();
+                println!("{}: {} hosts ({})", name, hosts.len(), aliases.join(", "));
+            }
+            Err(err) => println!("{}: {}", name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a configuration file and a JSON source next to it in a directory of their own.
+    fn inventory(name: &str, config: &str, source: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rman-sources-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("inventory.json"), source).unwrap();
+        let path = dir.join("rman.toml");
+        std::fs::write(&path, config).unwrap();
+        path
+    }
+
+    const SOURCE: &str = r#"{
+        "hosts": {
+            "web01": {"ip": "10.0.0.5", "tags": ["web"], "vars": {"role": "frontend"}},
+            "db01": {"ip": "10.0.1.7", "ssh_user": "postgres"},
+            "bad": {"ip": "not an address"}
+        },
+        "groups": {"db": {"hosts": ["db01"], "port": 5432, "vars": {"backup": true}}},
+        "vars": {"dc": "fra1", "role": "none"}
+    }"#;
+
+    #[test]
+    fn merges_source_groups_over_static_groups_setting_by_setting() {
+        let path = inventory("groups", "[defaults]\nssh_user = \"admin\"\n\n[groups.db]\npk_path = \"/keys/db\"\nport = 2200\n\n[sources.cmdb]\nfile = \"inventory.json\"\n", SOURCE);
+        let output: Output = serde_json::from_str(SOURCE).unwrap();
+        let hosts = to_hosts(&path, "cmdb", output);
+        assert_eq!(hosts.iter().map(|host| host.alias.as_str()).collect::<std::vec::Vec<_>>(), vec!("db01", "web01"));
+
+        let db = &hosts[0];
+        assert_eq!(db.tags, vec!(String::from("db")));
+        assert_eq!((db.ssh_user.as_str(), db.pk_path.as_str(), db.port), ("postgres", "/keys/db", 5432));
+        assert_eq!(db.vars.get("backup").map(String::as_str), Some("true"));
+
+        let web = &hosts[1];
+        assert_eq!(web.ssh_user, "admin");
+        assert_eq!(web.vars.get("role").map(String::as_str), Some("frontend"));
+        assert_eq!(web.vars.get("dc").map(String::as_str), Some("fra1"));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn static_hosts_win_over_dynamic_hosts_with_the_same_alias() {
+        let path = inventory("merge", "[sources.cmdb]\nfile = \"inventory.json\"\n\n[sources.missing]\nfile = \"missing.json\"\n", SOURCE);
+        let mut hosts = vec!(Host::new("web01", "192.168.0.1", "root", "/keys/web"));
+        merge(&path, &mut hosts, false);
+        assert_eq!(hosts.iter().map(|host| host.alias.as_str()).collect::<std::vec::Vec<_>>(), vec!("web01", "db01"));
+        assert_eq!(hosts[0].ip, "192.168.0.1");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn runs_commands_with_a_timeout() {
+        let path = inventory("run", "", "{}");
+        let echo = vec!(String::from("sh"), String::from("-c"), String::from("cat inventory.json"));
+        assert_eq!(run(&path, &echo, Duration::from_secs(10)).unwrap(), "{}");
+        let slow = vec!(String::from("sleep"), String::from("5"));
+        assert!(run(&path, &slow, Duration::from_millis(100)).unwrap_err().contains("took longer"));
+        let failing = vec!(String::from("false"));
+        assert!(run(&path, &failing, Duration::from_secs(10)).is_err());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -4,93 +4,194 @@ use crate::host;
 extern crate ssh;
 use ssh::*;
 use std::path::Path;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
-pub fn execute_remote_command(host: &host::Host, remote_cmd: &String) -> String {
-    // Connect to the remote machine
-    let mut session=Session::new().unwrap();
-    session.set_host(host.ip.as_str()).unwrap();
-    session.set_username(host.ssh_user.as_str()).unwrap();
-    session.set_identity(Path::new(host.pk_path.as_str())).unwrap();
-    let mut connected = false;
-    let mut count: u8 = 0;
-    while !connected || count > 2 {
-        match session.connect() {
-            Ok(_) => connected = true,
-            Err(_) => count += 1 // Increment connection timeout counter...
+/// Outcome of running a command on a remote machine.
+pub struct CmdResult {
+    pub output: String,             // Standard output, or an error message if the command couldn't run.
+    pub exit_status: Option<i32>,   // Exit status reported by the remote machine, if any.
+    pub duration: Duration,         // Wall time including connecting and authenticating.
+    pub connected: bool,            // Whether the host could be reached and the key was accepted.
+    pub interrupted: bool,          // Whether rman gave up on the command before it finished, it may have run.
+}
+
+/// Runs a command on the remote machine and returns its output.
+pub fn execute_remote_command(host: &host::Host, remote_cmd: &str) -> String {
+    run_remote_command(host, remote_cmd).output
+}
+
+/// Runs a command on the remote machine and returns its output, exit status and duration.
+pub fn run_remote_command(host: &host::Host, remote_cmd: &str) -> CmdResult {
+    let start = Instant::now();
+    let mut session = match open_session(host) {
+        Ok(session) => session,
+        Err(ConnectError::Unreachable(_)) => return CmdResult { output: String::from("Host cannot be reached."), exit_status: None, duration: start.elapsed(), connected: false, interrupted: false },
+        Err(ConnectError::AuthFailed(_)) => return CmdResult { output: String::from("Failed to open key."), exit_status: None, duration: start.elapsed(), connected: false, interrupted: false },
+    };
+    // Run the command as the become user, if the host has one.
+    let remote_cmd = match host.become_user {
+        Some(ref user) => format!("sudo -n -u {} -- sh -c {}", shell_quote(user), shell_quote(remote_cmd)),
+        None => remote_cmd.to_string(),
+    };
+    // Execute command on the remote machine...
+    let mut s=session.channel_new().unwrap();
+    s.open_session().unwrap();
+    s.request_exec(remote_cmd.as_ref()).unwrap();
+    s.send_eof().unwrap();
+    let mut buf=Vec::new();
+    while buf.is_empty() {
+        if s.stdout().read_to_end(&mut buf).is_ok() {
+            break;
         }
+        // Attempt to read to buffer again...
     }
-    if connected {
-        // Check to make sure the key can be used...
-        let mut key_open = false;
-        let mut key_count = 0;
-        while !key_open {
-            if key_count == 3 {
-                break
-            }
-            match session.userauth_publickey_auto(None) {
-                Ok(_) => key_open = true,
-                Err(_) => println!("Password incorrect. Remaining tries: {}", 2 - key_count)
+    CmdResult {
+        output: String::from_utf8_lossy(&buf).into_owned(),
+        exit_status: s.get_exit_status(),
+        duration: start.elapsed(),
+        connected: true,
+        interrupted: false,
+    }
+}
+
+/// Uploads `contents` to `dest` on the remote machine with the given file mode. For hosts with a
+/// become user the file is uploaded into a private directory made with `mktemp -d` and put in
+/// place by that user with `install`, so it ends up owned by them. The upload is never readable by
+/// anyone else: become users other than root are only let in with an ACL, which needs `setfacl`.
+pub fn push_file(host: &host::Host, contents: &[u8], dest: &str, mode: usize) -> Result<(), String> {
+    let user = match host.become_user {
+        Some(ref user) => user,
+        None => return upload(host, contents, dest, mode),
+    };
+    // The temporary directory belongs to the ssh user, who makes, shares and removes it.
+    let mut ssh_user = host.clone();
+    ssh_user.become_user = None;
+    let mktemp = run_remote_command(&ssh_user, "mktemp -d");
+    let dir = mktemp.output.trim().to_string();
+    if mktemp.exit_status != Some(0) || dir.is_empty() {
+        return Err(format!("Unable to create a temporary directory: {}", mktemp.output.trim()));
+    }
+    let temp = format!("{}/upload", dir);
+    let installed = upload(host, contents, &temp, 0o600).and_then(|_| {
+        if user != "root" {
+            let share = run_remote_command(&ssh_user, &format!("setfacl -m u:{user}:x {} && setfacl -m u:{user}:r {}", shell_quote(&dir), shell_quote(&temp), user = shell_quote(user)));
+            if share.exit_status != Some(0) {
+                return Err(format!("Unable to let {} read the upload, is setfacl installed? {}", user, share.output.trim()));
             }
-            key_count += 1;
         }
-        if !key_open {
-            return String::from("Failed to open key.");
+        let install = run_remote_command(host, &format!("install -m {:o} {} {}", mode, shell_quote(&temp), shell_quote(dest)));
+        match install.exit_status {
+            Some(0) => Ok(()),
+            _ => Err(format!("Unable to install {} as {}: {}", dest, user, install.output.trim())),
         }
-        // Execute command on the remote machine...
-        let mut s=session.channel_new().unwrap();
-        s.open_session().unwrap();
-        s.request_exec(remote_cmd.as_str().as_ref()).unwrap();
-        s.send_eof().unwrap();
-        let mut buf=Vec::new();
-        while buf.is_empty() {
-            match s.stdout().read_to_end(&mut buf) {
-                Ok(_) => break,
-                Err(_) => () // Attempt to read to buffer again...
-            }
-        }
-        String::from(std::str::from_utf8(&buf).unwrap())
-    } else {
-        String::from("Host cannot be reached.")
-    }
+    });
+    run_remote_command(&ssh_user, &format!("rm -rf {}", shell_quote(&dir)));
+    installed
+}
+
+/// Uploads `contents` to `dest` as the ssh user with scp.
+fn upload(host: &host::Host, contents: &[u8], dest: &str, mode: usize) -> Result<(), String> {
+    let dest = Path::new(dest);
+    let (dir, name) = match (dest.parent(), dest.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Err(format!("Invalid destination {}", dest.display())),
+    };
+    let mut session = open_session(host).map_err(|err| match err {
+        ConnectError::Unreachable(err) | ConnectError::AuthFailed(err) => err,
+    })?;
+    let mut scp = session.scp_new(WRITE, dir).map_err(|err| err.to_string())?;
+    scp.init().map_err(|err| err.to_string())?;
+    scp.push_file(name, contents.len(), mode).map_err(|err| err.to_string())?;
+    // libssh writes the whole buffer at once and reports success rather than a byte count.
+    scp.write(contents).map_err(|err| err.to_string())?;
+    scp.close();
+    Ok(())
 }
 
-pub fn check_host(host: &host::Host) -> bool {
-    // Attempt to connect to the remote host...
+/// Connects to the remote machine and authenticates, retrying a few times like an interactive ssh would.
+fn open_session(host: &host::Host) -> Result<Session, ConnectError> {
+    // Connect to the remote machine
     let mut session=Session::new().unwrap();
     session.set_host(host.ip.as_str()).unwrap();
+    session.set_port(host.port as usize).unwrap();
     session.set_username(host.ssh_user.as_str()).unwrap();
     session.set_identity(Path::new(host.pk_path.as_str())).unwrap();
     let mut connected = false;
     let mut count: u8 = 0;
-    while !connected || count > 2 {
+    while !connected && count < 3 {
         match session.connect() {
             Ok(_) => connected = true,
             Err(_) => count += 1 // Increment connection timeout counter...
         }
     }
-    std::mem::drop(session);
-    connected
+    if !connected {
+        return Err(ConnectError::Unreachable(String::from("Host cannot be reached.")));
+    }
+    // Check to make sure the key can be used...
+    let mut key_count = 0;
+    while key_count < 3 {
+        match session.userauth_publickey_auto(None) {
+            Ok(_) => return Ok(session),
+            Err(_) => println!("Password incorrect. Remaining tries: {}", 2 - key_count)
+        }
+        key_count += 1;
+    }
+    Err(ConnectError::AuthFailed(String::from("Failed to open key.")))
 }
 
-/// Checks if the ssh user has sudo privileges
+/// Reasons a connection attempt can fail.
+#[derive(Debug)]
+pub enum ConnectError {
+    Unreachable(String),    // The ssh connection itself could not be established.
+    AuthFailed(String),     // The host answered but rejected the key.
+}
+
+/// Connects to the remote host and authenticates with the host's key, without running anything.
+pub fn check_auth(host: &host::Host) -> Result<(), ConnectError> {
+    let mut session = Session::new().map_err(|_| ConnectError::Unreachable(String::from("unable to create ssh session")))?;
+    session.set_host(host.ip.as_str()).map_err(|err| ConnectError::Unreachable(err.to_string()))?;
+    session.set_port(host.port as usize).map_err(|err| ConnectError::Unreachable(err.to_string()))?;
+    session.set_username(host.ssh_user.as_str()).map_err(|err| ConnectError::Unreachable(err.to_string()))?;
+    session.set_identity(Path::new(host.pk_path.as_str())).map_err(|err| ConnectError::AuthFailed(err.to_string()))?;
+    session.connect().map_err(|err| ConnectError::Unreachable(err.to_string()))?;
+    session.userauth_publickey_auto(None).map_err(|err| ConnectError::AuthFailed(err.to_string()))?;
+    Ok(())
+}
+
+/// Quotes a value for use as a single word in a remote shell command.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Command whose output tells whether commands run as root or by a member of the sudo group.
+pub const PRIVS_CMD: &str = "id -u && groups";
+
+/// Checks if commands run on the host have root or sudo privileges. For hosts with a become user
+/// the check runs through `sudo -n` like every command, so it fails if sudo isn't allowed or
+/// asks for a password.
 pub fn check_privs(host: &host::Host) -> bool {
-    let groups = execute_remote_command(host, &String::from("groups"));
-    groups.contains("sudo")
+    let result = run_remote_command(host, PRIVS_CMD);
+    if result.exit_status != Some(0) {
+        return false;
+    }
+    let mut lines = result.output.lines();
+    let root = lines.next().map(str::trim) == Some("0");
+    root || lines.next().map(|groups| groups.split_whitespace().any(|group| group == "sudo")).unwrap_or(false)
 }
 /// Reboot the target host
-pub fn reboot(host: &host::Host) {
+pub fn reboot(host: &host::Host) -> Result<CmdResult, String> {
     if check_privs(host) {
-        execute_remote_command(host, &String::from("shutdown -r"));
+        Ok(run_remote_command(host, "shutdown -r"))
     } else {
-        println!("User lacks privileges to execute this command.");
+        Err(String::from("User lacks privileges to execute this command."))
     }
 }
 /// Shutdown the target host
-pub fn shutdown(host: &host::Host) {
+pub fn shutdown(host: &host::Host) -> Result<CmdResult, String> {
     if check_privs(host) {
-        execute_remote_command(host, &String::from("shutdown"));
+        Ok(run_remote_command(host, "shutdown"))
     } else {
-        println!("User lacks privileges to execute this command.")
+        Err(String::from("User lacks privileges to execute this command."))
     }
-}
\ No newline at end of file
+}
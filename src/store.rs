@@ -0,0 +1,352 @@
+//! Provides crash and concurrency safe writes of configuration files, with backups to undo them.
+//!
+//! Writers hold an advisory lock on `<file>.lock` next to the file, write the new contents to a
+//! temporary file and rename it over the old one, so a crash leaves either the old or the new
+//! version. A write fails instead of silently dropping changes if another rman changed the file
+//! after it was read. The replaced version of an inventory is kept as a timestamped backup in
+//! `<config dir>/backups/`, the newest `[backups] keep` (10 by default) of them per inventory.
+//! Backups get the permissions of the inventory, and a counter if two share a timestamp.
+
+use crate::args;
+use crate::cli::ConfigCommand;
+use crate::context;
+use crate::diff;
+use crate::history;
+use crate::host;
+use crate::sources;
+use crate::validate;
+use chrono::{Local, NaiveDateTime, TimeZone, Utc};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Backups kept per inventory unless `[backups] keep` says otherwise.
+pub const DEFAULT_KEEP: usize = 10;
+
+/// Format of the timestamp backups are named after, in UTC.
+const STAMP: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// Suffix of backups replaced by `rman config undo`, which undo doesn't restore again.
+const UNDONE: &str = ".undone";
+
+/// A previous version of an inventory.
+pub struct Backup {
+    pub path: PathBuf,                  // The backup file.
+    pub saved: NaiveDateTime,           // When it was replaced, in UTC.
+    pub undone: bool,                   // Whether it was replaced by `rman config undo`.
+}
+
+/// Replaces the inventory at `path` with `contents`, backing up the current version.
+/// `expected` is the contents the change was based on, the write fails if the file differs from it.
+pub fn save(path: &Path, contents: &str, expected: &str) -> io::Result<()> {
+    save_backed_up(path, &backup_dir(path), contents, expected)
+}
+
+/// Like `save`, keeping the backups in `dir`.
+fn save_backed_up(path: &Path, dir: &Path, contents: &str, expected: &str) -> io::Result<()> {
+    let _lock = lock(path)?;
+    let current = fs::read_to_string(path).unwrap_or_default();
+    if current != expected {
+        return Err(io::Error::other("the file was changed by another rman since it was read, nothing was saved. Run the command again"));
+    }
+    if current == contents {
+        return Ok(());
+    }
+    if !current.is_empty() {
+        backup(path, dir, &current, "")?;
+    }
+    replace(path, contents)
+}
+
+/// Replaces the file at `path` with `contents` under the lock, without a backup.
+pub fn write(path: &Path, contents: &str) -> io::Result<()> {
+    let _lock = lock(path)?;
+    replace(path, contents)
+}
+
+/// Takes the advisory lock of `path`, released when the returned file is dropped.
+fn lock(path: &Path) -> io::Result<File> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(path.with_file_name(name))?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+/// Writes `contents` to a temporary file in the same directory and renames it over `path`.
+fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".tmp-{}", std::process::id()));
+    let temp = path.with_file_name(name);
+    let written = (|| {
+        let mut file = File::create(&temp)?;
+        // Keep the permissions of the file being replaced, it may be readable by its owner only.
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written?;
+    // Persist the rename itself, directories can't be opened for syncing everywhere.
+    if let Some(dir) = path.parent().and_then(|dir| File::open(dir).ok()) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Directory holding the backups of the inventory at `path`.
+fn backup_dir(path: &Path) -> PathBuf {
+    context::config_dir().join("backups").join(path_key(path))
+}
+
+/// Names a directory after the full path of a configuration file, e.g. `home%me%rman.toml`, so
+/// data kept per inventory doesn't mix.
+pub(crate) fn path_key(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    path.display().to_string().trim_start_matches('/').replace(['/', '\\', ':'], "%")
+}
+
+/// Stores `contents` as the newest backup of `path` in `dir`, with the permissions of `path`, and
+/// removes the oldest ones beyond the limit.
+fn backup(path: &Path, dir: &Path, contents: &str, suffix: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let stamp = Utc::now().format(STAMP).to_string();
+    let name = |count: u32, suffix: &str| match count {
+        0 => format!("{}{}.toml", stamp, suffix),
+        _ => format!("{}-{}{}.toml", stamp, count, suffix),
+    };
+    // Undone or not, backups sharing a timestamp need a count to keep them in order.
+    let count = (0..).find(|count| !["", UNDONE].iter().any(|other| dir.join(name(*count, other)).exists())).expect("some backup name is free");
+    let mut file = OpenOptions::new().write(true).create_new(true).open(dir.join(name(count, suffix)))?;
+    // Restricted before anything is written, the inventory may be readable by its owner only.
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents.as_bytes())?;
+    let keep = contents.parse::<toml::Value>().ok()
+        .and_then(|document| document.get("backups")?.get("keep")?.as_integer())
+        .map(|keep| keep.max(1) as usize)
+        .unwrap_or(DEFAULT_KEEP);
+    for old in backups_in(dir).into_iter().skip(keep) {
+        fs::remove_file(old.path)?;
+    }
+    Ok(())
+}
+
+/// The backups of the inventory at `path`, newest first.
+pub fn backups(path: &Path) -> std::vec::Vec<Backup> {
+    backups_in(&backup_dir(path))
+}
+
+/// The backups in `dir`, newest first.
+fn backups_in(dir: &Path) -> std::vec::Vec<Backup> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec!(),
+    };
+    let mut backups: std::vec::Vec<(Backup, u32)> = entries.filter_map(|entry| {
+        let path = entry.ok()?.path();
+        let name = path.file_name()?.to_str()?.strip_suffix(".toml")?.to_string();
+        let (name, undone) = match name.strip_suffix(UNDONE) {
+            Some(name) => (name.to_string(), true),
+            None => (name, false),
+        };
+        // Backups sharing a timestamp are counted on in the order they were made.
+        let (stamp, count) = match name.split_once('-') {
+            Some((stamp, count)) => (stamp, count.parse().ok()?),
+            None => (name.as_str(), 0),
+        };
+        let saved = NaiveDateTime::parse_from_str(stamp, STAMP).ok()?;
+        Some((Backup { path, saved, undone }, count))
+    }).collect();
+    backups.sort_by_key(|(backup, count)| std::cmp::Reverse((backup.saved, *count)));
+    backups.into_iter().map(|(backup, _)| backup).collect()
+}
+
+/// This function handles all `$rman config` commands.
+pub fn base(command: ConfigCommand) {
+    match command {
+        ConfigCommand::History => show_history(&host::config_path()),   // config "history"
+        ConfigCommand::Undo => undo(&host::config_path()),              // config "undo"
+        ConfigCommand::Check => {                                       // config "check"
+            if let Err(err) = validate::check(&host::config_path()) {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        ConfigCommand::Refresh => sources::refresh(&host::config_path()), // config "refresh"
+    }
+}
+
+/// Lists the backups of the inventory, newest first, with the hosts each later version added and removed.
+fn show_history(path: &Path) {
+    let backups = backups(path);
+    if backups.is_empty() {
+        println!("No backups of {} yet, one is kept every time it changes", path.display());
+        return;
+    }
+    println!("Backups of {}, newest first:", path.display());
+    // Every backup is compared with the version that replaced it.
+    let mut newer = aliases(path);
+    for (i, backup) in backups.iter().enumerate() {
+        let older = aliases(&backup.path);
+        let added: std::vec::Vec<String> = newer.iter().filter(|alias| !older.contains(alias)).map(|alias| format!("+{}", alias)).collect();
+        let removed: std::vec::Vec<String> = older.iter().filter(|alias| !newer.contains(alias)).map(|alias| format!("-{}", alias)).collect();
+        let changes = match added.len() + removed.len() {
+            0 => String::from("hosts unchanged"),
+            _ => added.into_iter().chain(removed).collect::<std::vec::Vec<_>>().join(" "),
+        };
+        let saved = Local.from_utc_datetime(&backup.saved).format("%Y-%m-%d %H:%M:%S");
+        let undone = if backup.undone { "  (undone)" } else { "" };
+        println!("{:>3}  {}  {:>3} hosts, then {}{}", i + 1, saved, older.len(), changes, undone);
+        newer = older;
+    }
+}
+
+/// Aliases of the hosts of an inventory file, none if it can't be read.
+fn aliases(path: &Path) -> std::vec::Vec<String> {
+    host::read_hosts(path).map(|hosts| hosts.into_iter().map(|host| host.alias).collect()).unwrap_or_default()
+}
+
+/// Restores the newest backup that wasn't undone itself. The replaced version is kept as an
+/// undone backup, so an undo can be reverted by hand.
+fn undo(path: &Path) {
+    let dir = backup_dir(path);
+    let restore = match restorable(&dir) {
+        Some(restore) => restore,
+        None => {
+            println!("Nothing to undo, {} has no backups", path.display());
+            return;
+        }
+    };
+    let restored = match fs::read_to_string(&restore.path) {
+        Ok(restored) => restored,
+        Err(err) => {
+            println!("Unable to read {}: {}", restore.path.display(), err);
+            return;
+        }
+    };
+    let saved = Local.from_utc_datetime(&restore.saved).format("%Y-%m-%d %H:%M:%S").to_string();
+    let current = fs::read_to_string(path).unwrap_or_default();
+    if args::check_mode() {
+        let name = path.display().to_string();
+        println!("Check mode, undo would restore the version replaced at {}:\n{}", saved, diff::unified(&current, &restored, &name, &name));
+        return;
+    }
+    match restore_backup(path, &dir, &restore, &restored, &current) {
+        Ok(()) => {
+            println!("Restored {} as it was before {}", path.display(), saved);
+            history::record("config undo", &path.display().to_string(), vec!(), vec!());
+        }
+        Err(err) => println!("Unable to undo: {}", err),
+    }
+}
+
+/// The newest backup in `dir` that wasn't undone itself, if any.
+fn restorable(dir: &Path) -> Option<Backup> {
+    backups_in(dir).into_iter().find(|backup| !backup.undone)
+}
+
+/// Replaces the inventory at `path`, read as `current`, with the `restored` contents of the
+/// backup `restore` and keeps `current` as an undone backup in `dir`.
+fn restore_backup(path: &Path, dir: &Path, restore: &Backup, restored: &str, current: &str) -> io::Result<()> {
+    let _lock = lock(path)?;
+    // Compared under the lock, like `save`, so a concurrent change isn't lost unnoticed.
+    if fs::read_to_string(path).unwrap_or_default() != current {
+        return Err(io::Error::other("the file was changed by another rman meanwhile"));
+    }
+    if !current.is_empty() {
+        backup(path, dir, current, UNDONE)?;
+    }
+    replace(path, restored)?;
+    fs::remove_file(&restore.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A configuration file and backup directory of its own for each test, removed by `clean_up`.
+    fn inventory(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("rman-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        (dir.join("rman.toml"), dir.join("backups"))
+    }
+
+    fn clean_up(path: &Path) {
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    /// Saves `contents` over the current file.
+    fn change(path: &Path, dir: &Path, contents: &str) {
+        save_backed_up(path, dir, contents, &fs::read_to_string(path).unwrap_or_default()).unwrap();
+    }
+
+    fn undo_once(path: &Path, dir: &Path) {
+        let backup = restorable(dir).unwrap();
+        let restored = fs::read_to_string(&backup.path).unwrap();
+        restore_backup(path, dir, &backup, &restored, &fs::read_to_string(path).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_earlier_versions_in_turn() {
+        let (path, dir) = inventory("undo");
+        for version in ["alias = \"a\"\n", "alias = \"b\"\n", "alias = \"c\"\n"].iter() {
+            change(&path, &dir, version);
+        }
+        assert_eq!(backups_in(&dir).len(), 2);
+
+        undo_once(&path, &dir);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "alias = \"b\"\n");
+        // The undone version is kept, but not restored by the next undo.
+        assert!(backups_in(&dir)[0].undone);
+        undo_once(&path, &dir);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "alias = \"a\"\n");
+        assert!(restorable(&dir).is_none());
+        assert_eq!(backups_in(&dir).iter().filter(|backup| backup.undone).count(), 2);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn save_refuses_to_overwrite_changes_made_since_reading() {
+        let (path, dir) = inventory("conflict");
+        change(&path, &dir, "alias = \"a\"\n");
+        assert!(save_backed_up(&path, &dir, "alias = \"c\"\n", "alias = \"b\"\n").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "alias = \"a\"\n");
+        clean_up(&path);
+    }
+
+    #[test]
+    fn keeps_the_configured_number_of_backups() {
+        let (path, dir) = inventory("keep");
+        for i in 0..5 {
+            change(&path, &dir, &format!("alias = \"host{}\"\n\n[backups]\nkeep = 2\n", i));
+        }
+        let kept = backups_in(&dir);
+        assert_eq!(kept.len(), 2);
+        // The newest backups survive, even when they share a timestamp.
+        assert_eq!(fs::read_to_string(&kept[0].path).unwrap(), "alias = \"host3\"\n\n[backups]\nkeep = 2\n");
+        assert_eq!(fs::read_to_string(&kept[1].path).unwrap(), "alias = \"host2\"\n\n[backups]\nkeep = 2\n");
+        clean_up(&path);
+    }
+
+    #[test]
+    fn backups_keep_the_permissions_of_the_inventory() {
+        let (path, dir) = inventory("mode");
+        change(&path, &dir, "alias = \"a\"\n");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        change(&path, &dir, "alias = \"b\"\n");
+        let mode = fs::metadata(&backups_in(&dir)[0].path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        clean_up(&path);
+    }
+}
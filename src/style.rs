@@ -0,0 +1,35 @@
+//! Provides ANSI colouring for terminal output.
+//!
+//! Colours are only emitted when stdout is a terminal and `NO_COLOR` is not set.
+
+use std::io::IsTerminal;
+
+/// Returns whether coloured output should be produced.
+fn enabled() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+/// Wraps `text` in the given ANSI SGR code.
+fn paint(text: &str, code: &str) -> String {
+    if enabled() {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn green(text: &str) -> String {
+    paint(text, "32")
+}
+
+pub fn yellow(text: &str) -> String {
+    paint(text, "33")
+}
+
+pub fn red(text: &str) -> String {
+    paint(text, "31")
+}
+
+pub fn bold(text: &str) -> String {
+    paint(text, "1")
+}
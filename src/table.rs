@@ -0,0 +1,107 @@
+//! Provides a small table renderer that fits its output to the terminal width.
+
+use crate::style;
+use std::io::IsTerminal;
+use terminal_size::{terminal_size, Width};
+
+/// Columns are never shrunk below this many characters.
+const MIN_WIDTH: usize = 6;
+
+/// A table cell, optionally coloured with one of the `style` functions.
+pub struct Cell {
+    pub text: String,
+    pub paint: Option<fn(&str) -> String>,
+}
+
+/// A table made of a header row and data rows.
+pub struct Table {
+    pub headers: std::vec::Vec<String>,
+    pub rows: std::vec::Vec<std::vec::Vec<Cell>>,
+}
+
+impl Cell {
+    pub fn plain(text: &str) -> Cell {
+        Cell { text: text.to_string(), paint: None }
+    }
+
+    pub fn painted(text: &str, paint: fn(&str) -> String) -> Cell {
+        Cell { text: text.to_string(), paint: Some(paint) }
+    }
+}
+
+/// Width available for output, `None` if stdout isn't a terminal and nothing should be truncated.
+fn terminal_width() -> Option<usize> {
+    if let Some(columns) = std::env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()) {
+        return Some(columns);
+    }
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    terminal_size().map(|(Width(width), _)| width as usize)
+}
+
+/// Cuts `text` down to `width` characters, marking the cut with `…`.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let mut cut: String = text.chars().take(width.saturating_sub(1)).collect();
+        cut.push('…');
+        cut
+    }
+}
+
+impl Table {
+    /// Prints the table. Unless `wide` is set, the widest columns are truncated until the table fits the terminal.
+    pub fn print(&self, wide: bool) {
+        let mut widths: std::vec::Vec<usize> = self.headers.iter().map(|header| header.chars().count()).collect();
+        for row in self.rows.iter() {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.text.chars().count());
+            }
+        }
+
+        // Shrink the widest column one character at a time until everything fits.
+        if let (false, Some(available)) = (wide, terminal_width()) {
+            let separators = 2 * widths.len().saturating_sub(1);
+            while widths.iter().sum::<usize>() + separators > available {
+                let (widest, width) = match widths.iter().enumerate().max_by_key(|(_, width)| **width) {
+                    Some((i, width)) => (i, *width),
+                    None => break,
+                };
+                if width <= MIN_WIDTH {
+                    break;
+                }
+                widths[widest] -= 1;
+            }
+        }
+
+        let header: std::vec::Vec<String> = self.headers.iter().enumerate()
+            .map(|(i, header)| format!("{:<width$}", truncate(header, widths[i]), width = widths[i]))
+            .collect();
+        println!("{}", style::bold(header.join("  ").trim_end()));
+        for row in self.rows.iter() {
+            let line: std::vec::Vec<String> = row.iter().enumerate().map(|(i, cell)| {
+                let padded = format!("{:<width$}", truncate(&cell.text, widths[i]), width = widths[i]);
+                match cell.paint {
+                    Some(paint) => paint(&padded),
+                    None => padded,
+                }
+            }).collect();
+            println!("{}", line.join("  ").trim_end());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_long_text_with_a_marker() {
+        assert_eq!(truncate("web01", 6), "web01");
+        assert_eq!(truncate("web01", 5), "web01");
+        assert_eq!(truncate("frontend-web01", 6), "front…");
+        assert_eq!(truncate("ünïcödé", 4), "ünï…");
+    }
+}
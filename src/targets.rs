@@ -0,0 +1,231 @@
+//! Provides expansion of host target specifications into hosts from the inventory.
+//!
+//! A target specification is a comma separated list of:
+//! * exact aliases: `web01`,
+//! * glob patterns: `web-*`, `db?`,
+//! * regexes prefixed with `~`: `~^db\d+$`, `~^web\d{1,3}$`,
+//! * bracket ranges: `web[01:12]`, `web[1,3,7:9]`, `rack[a:c]`.
+//!
+//! Ranges keep the zero padding of their lower bound, so `web[01:12]` expands to `web01` ... `web12`.
+
+use crate::host::Host;
+use glob::Pattern;
+use regex::Regex;
+
+/// Resolves a target specification against the inventory, in inventory order and without duplicates.
+/// Every entry of the specification has to match at least one host.
+/// # Examples
+/// ```
+/// use rman::Host;
+/// use rman::targets::resolve;
+///
+/// let hosts: Vec<Host> = ["web01", "web02", "web05", "db1"].iter().map(|alias| Host::new(alias, "10.0.0.1", "root", "~/.ssh/key")).collect();
+/// let selected = resolve("web[01:04],~^db\\d+$", &hosts)?;
+/// assert_eq!(selected.len(), 3);
+/// # Ok::<(), String>(())
+/// ```
+pub fn resolve(spec: &str, hosts: &[Host]) -> Result<std::vec::Vec<Host>, String> {
+    let mut matched = vec![false; hosts.len()];
+    for part in split_list(spec) {
+        let is_match = matcher(&part)?;
+        let mut found = false;
+        for (i, host) in hosts.iter().enumerate() {
+            if is_match(&host.alias) {
+                matched[i] = true;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(format!("No host matches '{}'", part));
+        }
+    }
+    Ok(hosts.iter().zip(matched).filter(|(_, matched)| *matched).map(|(host, _)| host.clone()).collect())
+}
+
+/// Checks the syntax of a target specification without resolving it against an inventory.
+pub fn check(spec: &str) -> Result<(), String> {
+    for part in split_list(spec) {
+        matcher(&part).map(|_| ())?;
+    }
+    Ok(())
+}
+
+/// Predicate on aliases built from one part of a target specification.
+type Matcher = Box<dyn Fn(&str) -> bool>;
+
+/// Matcher of aliases for one part of a target specification.
+fn matcher(part: &str) -> Result<Matcher, String> {
+    match part.strip_prefix('~') {
+        Some(regex) => {
+            let regex = Regex::new(regex).map_err(|err| format!("Invalid regex '{}': {}", regex, err))?;
+            Ok(Box::new(move |alias| regex.is_match(alias)))
+        }
+        None => {
+            let mut patterns = vec!();
+            for name in expand(part)? {
+                patterns.push(Pattern::new(&name).map_err(|err| format!("Invalid pattern '{}': {}", name, err))?);
+            }
+            Ok(Box::new(move |alias| patterns.iter().any(|pattern| pattern.matches(alias))))
+        }
+    }
+}
+
+/// Splits a comma separated list, ignoring commas inside `[]`, `{}` and `()`, such as those of
+/// ranges and regex quantifiers like `\d{1,3}`, and commas escaped with a backslash.
+pub fn split_list(spec: &str) -> std::vec::Vec<String> {
+    let mut parts = vec!();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in spec.chars() {
+        if escaped {
+            escaped = false;
+            current.push(c);
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '[' | '{' | '(' => depth += 1,
+            ']' | '}' | ')' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    parts.push(current);
+    parts.into_iter().map(|part| part.trim().to_string()).filter(|part| !part.is_empty()).collect()
+}
+
+/// Most hosts a pattern may expand to, so a typo such as `web[0:999999999]` fails instead of
+/// exhausting memory.
+pub const MAX_EXPANSION: usize = 10_000;
+
+/// Expands every bracket range in `pattern`, e.g. `web[01:03]` into `web01`, `web02` and `web03`.
+/// Brackets that aren't ranges, such as the glob class `[ab]`, are left untouched.
+pub fn expand(pattern: &str) -> Result<std::vec::Vec<String>, String> {
+    let mut expanded = vec!(String::new());
+    let mut rest = pattern;
+    while let Some(open) = rest.find('[') {
+        let close = match rest[open..].find(']') {
+            Some(offset) => open + offset,
+            None => break,
+        };
+        let prefix = &rest[..open];
+        let values = match range_values(&rest[open + 1..close])? {
+            Some(values) => values,
+            None => vec!(rest[open..=close].to_string()),
+        };
+        if expanded.len().saturating_mul(values.len()) > MAX_EXPANSION {
+            return Err(format!("'{}' expands to more than {} hosts", pattern, MAX_EXPANSION));
+        }
+        expanded = expanded.iter()
+            .flat_map(|head| values.iter().map(move |value| format!("{}{}{}", head, prefix, value)))
+            .collect();
+        rest = &rest[close + 1..];
+    }
+    Ok(expanded.into_iter().map(|head| format!("{}{}", head, rest)).collect())
+}
+
+/// Values of a bracket range body such as `01:12` or `1,3,7:9`, `None` if the body isn't a range.
+fn range_values(body: &str) -> Result<Option<std::vec::Vec<String>>, String> {
+    if !(body.contains(':') || body.contains(',')) {
+        return Ok(None);
+    }
+    let mut values = vec!();
+    for item in body.split(',') {
+        let (from, to) = match item.find(':') {
+            Some(colon) => (&item[..colon], &item[colon + 1..]),
+            None => (item, item),
+        };
+        let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        let is_letter = |s: &str| s.len() == 1 && s.chars().all(|c| c.is_ascii_alphabetic());
+        if is_number(from) && is_number(to) {
+            let parse = |s: &str| s.parse::<u64>().map_err(|err| format!("Invalid range '[{}]', {}: {}", body, s, err));
+            let (start, end) = (parse(from)?, parse(to)?);
+            if start > end {
+                return Err(format!("Invalid range '[{}]', {} is greater than {}", body, from, to));
+            }
+            if values.len() as u64 + (end - start) >= MAX_EXPANSION as u64 {
+                return Err(format!("Invalid range '[{}]', it has more than {} values", body, MAX_EXPANSION));
+            }
+            for n in start..=end {
+                values.push(format!("{:0width$}", n, width = from.len()));
+            }
+        } else if is_letter(from) && is_letter(to) {
+            let (start, end) = (from.as_bytes()[0], to.as_bytes()[0]);
+            if start > end {
+                return Err(format!("Invalid range '[{}]', {} is after {}", body, from, to));
+            }
+            for c in start..=end {
+                values.push((c as char).to_string());
+            }
+        } else {
+            // Not a range, e.g. a glob class that happens to contain a comma.
+            return Ok(None);
+        }
+    }
+    Ok(Some(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(aliases: &[&str]) -> std::vec::Vec<Host> {
+        aliases.iter().map(|alias| Host::new(alias, "10.0.0.1", "root", "~/.ssh/key")).collect()
+    }
+
+    #[test]
+    fn expands_numeric_ranges_keeping_padding() {
+        assert_eq!(expand("web[01:03]").unwrap(), vec!("web01", "web02", "web03"));
+        assert_eq!(expand("web[8:10]").unwrap(), vec!("web8", "web9", "web10"));
+        assert_eq!(expand("web[1,3,7:8]").unwrap(), vec!("web1", "web3", "web7", "web8"));
+    }
+
+    #[test]
+    fn expands_letter_ranges_and_several_brackets() {
+        assert_eq!(expand("rack[a:b]-[1:2]").unwrap(), vec!("racka-1", "racka-2", "rackb-1", "rackb-2"));
+    }
+
+    #[test]
+    fn leaves_glob_classes_alone() {
+        assert_eq!(expand("db[ab]").unwrap(), vec!("db[ab]"));
+        assert_eq!(expand("web-*").unwrap(), vec!("web-*"));
+    }
+
+    #[test]
+    fn rejects_invalid_and_oversized_ranges() {
+        assert!(expand("web[3:1]").is_err());
+        assert!(expand("web[1:99999999999999999999]").is_err());
+        assert!(expand("web[0:999999999]").is_err());
+        assert!(expand("web[0:999]-[0:999]").is_err());
+        assert_eq!(expand("web[1:10000]").unwrap().len(), MAX_EXPANSION);
+    }
+
+    #[test]
+    fn splits_lists_outside_brackets() {
+        assert_eq!(split_list("web[1,3], db1 ,,~^x$"), vec!("web[1,3]", "db1", "~^x$"));
+        assert_eq!(split_list("~^db\\d{1,3}$,~^(web|lb)[0-9]{2,}$,~^a\\,b$"), vec!("~^db\\d{1,3}$", "~^(web|lb)[0-9]{2,}$", "~^a\\,b$"));
+    }
+
+    #[test]
+    fn resolves_in_inventory_order_without_duplicates() {
+        let inventory = hosts(&["db1", "web01", "web02", "web10"]);
+        let aliases = |spec: &str| resolve(spec, &inventory).map(|hosts| hosts.into_iter().map(|host| host.alias).collect::<std::vec::Vec<_>>());
+        assert_eq!(aliases("web*,db1,web01").unwrap(), vec!("db1", "web01", "web02", "web10"));
+        assert_eq!(aliases("~^web0\\d$").unwrap(), vec!("web01", "web02"));
+        assert_eq!(aliases("~^web\\d{1,2}$,db1").unwrap(), vec!("db1", "web01", "web02", "web10"));
+        assert!(aliases("web01,nope").is_err());
+        assert!(aliases("~(").is_err());
+    }
+
+    #[test]
+    fn checks_syntax_without_an_inventory() {
+        assert!(check("web[01:12],~^db\\d+$,lb?").is_ok());
+        assert!(check("~(").is_err());
+        assert!(check("web[3:1]").is_err());
+    }
+}
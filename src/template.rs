@@ -0,0 +1,142 @@
+//! Provides `$rman all template` and `$rman host template`, which render a local file for every
+//! host and upload it where it differs from the remote copy.
+//!
+//! The template uses the same `{{placeholders}}` as templated commands, inserted as they are
+//! rather than shell-quoted. Each host's difference is shown as a unified diff before uploading,
+//! and with `--check` only the diff is shown.
+
+use crate::args;
+use crate::cli::TemplateArgs;
+use crate::diff;
+use crate::history::{self, HostResult};
+use crate::host::Host;
+use crate::render::{Escape, Renderer};
+use crate::ssh_con::{push_file, run_remote_command, shell_quote};
+use crate::style;
+use chrono::Local;
+
+/// Marker printed instead of the mode when the remote file doesn't exist.
+const MISSING: &str = "__rman_missing";
+
+/// How to install the rendered files.
+struct Options {
+    mode: Option<usize>,                // Defaults to the current mode, or 644 for new files.
+    backup: bool,                       // Keep the old file as `<dest>.<timestamp>`.
+    on_change: Option<String>,          // Command run after the file changed, rendered per host.
+}
+
+/// Outcome of installing a template on one host.
+enum Outcome {
+    Unchanged,
+    Changed,
+    Failed(String),
+}
+
+/// Renders the template on every host, exiting non-zero if any host failed.
+pub fn run(hosts: std::vec::Vec<Host>, args: TemplateArgs) {
+    let template = match std::fs::read_to_string(&args.src) {
+        Ok(template) => template,
+        Err(err) => {
+            println!("Unable to read {}: {}", args.src.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let options = Options { mode: args.mode, backup: args.backup, on_change: args.on_change };
+    let renderer = Renderer::load();
+
+    let mut results = vec!();
+    let mut failed = false;
+    for host in hosts.iter() {
+        let start = std::time::Instant::now();
+        let outcome = apply(&renderer, host, &template, &args.dest, &options);
+        let (status, output) = match outcome {
+            Outcome::Unchanged => {
+                println!("{}: {}", style::green("ok"), host.alias);
+                ("ok", None)
+            }
+            Outcome::Changed => {
+                let label = if args::check_mode() { "would change" } else { "changed" };
+                println!("{}: {}", style::yellow(label), host.alias);
+                ("ok", None)
+            }
+            Outcome::Failed(err) => {
+                println!("{}: {}\n    {}", style::red("failed"), host.alias, err.trim().replace('\n', "\n    "));
+                failed = true;
+                ("failed", Some(err))
+            }
+        };
+        results.push(HostResult {
+            alias: host.alias.clone(),
+            status: status.to_string(),
+            exit_status: None,
+            duration_ms: start.elapsed().as_millis() as u64,
+            output,
+        });
+    }
+    let command = format!("{} -> {}", args.src.display(), args.dest);
+    history::record("template", &command, hosts.into_iter().map(|host| host.alias).collect(), results);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Renders the template for a host, prints the diff to the remote file and uploads it if it changed.
+fn apply(renderer: &Renderer, host: &Host, template: &str, dest: &str, options: &Options) -> Outcome {
+    let rendered = match renderer.render(host, template, Escape::None) {
+        Ok(rendered) => rendered,
+        Err(err) => return Outcome::Failed(err),
+    };
+    let quoted = shell_quote(dest);
+    let current = run_remote_command(host, &format!("if [ -f {0} ]; then stat -c %a -- {0} && cat -- {0}; else echo {1}; fi", quoted, MISSING));
+    if !current.connected || current.exit_status.unwrap_or(0) != 0 {
+        return Outcome::Failed(current.output);
+    }
+    // The first line holds the current mode, the rest is the file.
+    let (current_mode, current_contents) = match current.output.split_once('\n') {
+        Some((mode, contents)) if mode != MISSING => (usize::from_str_radix(mode.trim(), 8).ok(), contents),
+        _ => (None, ""),
+    };
+    let new_name = format!("{}:{}", host.alias, dest);
+    let old_name = if current_mode.is_some() { new_name.clone() } else { String::from("/dev/null") };
+    let mode = options.mode.or(current_mode).unwrap_or(0o644);
+    if current_mode.is_some() && current_contents == rendered && current_mode == Some(mode) {
+        return Outcome::Unchanged;
+    }
+    let changes = diff::unified(current_contents, &rendered, &old_name, &new_name);
+    if !changes.is_empty() {
+        println!("{}", changes);
+    }
+    if current_mode.is_some() && current_mode != Some(mode) {
+        println!("mode {:o} -> {:o}", current_mode.unwrap_or_default(), mode);
+    }
+    if args::check_mode() {
+        return Outcome::Changed;
+    }
+
+    if options.backup && current_mode.is_some() {
+        let backup = format!("{}.{}", dest, Local::now().format("%Y%m%d%H%M%S"));
+        let copied = run_remote_command(host, &format!("cp -p -- {} {}", quoted, shell_quote(&backup)));
+        if copied.exit_status.unwrap_or(0) != 0 {
+            return Outcome::Failed(format!("Unable to back up {}: {}", dest, copied.output));
+        }
+    }
+    if let Err(err) = push_file(host, rendered.as_bytes(), dest, mode) {
+        return Outcome::Failed(err);
+    }
+    // scp only applies the mode to new files, so set it explicitly.
+    let chmod = run_remote_command(host, &format!("chmod {:o} -- {}", mode, quoted));
+    if chmod.exit_status.unwrap_or(0) != 0 {
+        return Outcome::Failed(chmod.output);
+    }
+    if let Some(ref on_change) = options.on_change {
+        let command = match renderer.render(host, on_change, Escape::Shell) {
+            Ok(command) => command,
+            Err(err) => return Outcome::Failed(err),
+        };
+        let result = run_remote_command(host, &command);
+        if result.exit_status.unwrap_or(0) != 0 {
+            return Outcome::Failed(format!("{} failed: {}", command, result.output));
+        }
+    }
+    Outcome::Changed
+}
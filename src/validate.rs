@@ -0,0 +1,694 @@
+//! Validates configuration files, every time the inventory is loaded and with `rman config check`.
+//!
+//! Errors make rman refuse the inventory rather than guess what it meant: invalid TOML, host lists
+//! with a different number of values than `alias`, empty or duplicate aliases, empty or invalid
+//! addresses and settings of the wrong type. Warnings are only listed by `rman config check`:
+//! private keys that are missing or readable by other users, hosts without an ssh user or key,
+//! unknown fields, groups no host is tagged with, tags naming no group once the file defines groups,
+//! and variables or protected targets naming no host. Hosts of dynamic sources aren't known here,
+//! only the `[sources.<name>]` sections are checked. rman has no jump hosts, so a `jump` setting
+//! is reported like any other unknown field.
+
+use crate::host::{self, Host};
+use crate::style;
+use crate::targets;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Lists of host values separated by `|`, one value per alias.
+const HOST_LISTS: [&str; 9] = ["alias", "ip", "ssh_user", "pk_path", "description", "tags", "port", "timeout", "become"];
+
+/// Keys of `[thresholds]`, see `health`.
+const THRESHOLDS: [&str; 12] = [
+    "load_warn", "load_crit", "disk_warn", "disk_crit", "inode_warn", "inode_crit",
+    "mem_warn", "mem_crit", "swap_warn", "swap_crit", "failed_units_warn", "failed_units_crit",
+];
+
+/// How bad a problem is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,      // The inventory isn't used until it is fixed.
+    Warning,    // The inventory is used, but probably not as intended.
+}
+
+/// A problem found in a configuration file.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: Option<usize>,    // Line of the problem, starting at 1, if it could be located.
+    pub column: Option<usize>,  // Column of the value within the line, starting at 1, for values of host lists.
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Where the problem is, e.g. `rman.toml:3:18`.
+    pub fn location(&self, path: &Path) -> String {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}", path.display(), line, column),
+            (Some(line), None) => format!("{}:{}", path.display(), line),
+            _ => path.display().to_string(),
+        }
+    }
+}
+
+/// Validates the configuration file at `path`. A missing file is a valid, empty inventory.
+/// # Examples
+/// ```
+/// use rman::validate::{validate, Severity};
+///
+/// let path = std::env::temp_dir().join("rman-validate-example.toml");
+/// std::fs::write(&path, "alias = \"web01|web01\"\nip = \"10.0.0.5|10.0.0.300\"\n").unwrap();
+/// let errors: Vec<String> = validate(&path).into_iter()
+///     .filter(|diagnostic| diagnostic.severity == Severity::Error)
+///     .map(|diagnostic| diagnostic.message)
+///     .collect();
+/// assert_eq!(errors.len(), 2);
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn validate(path: &Path) -> std::vec::Vec<Diagnostic> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return vec!(),
+        Err(err) => return vec!(Diagnostic { severity: Severity::Error, line: None, column: None, message: format!("unable to read the file: {}", err) }),
+    };
+    let mut validator = Validator { lines: Lines::index(&contents), diagnostics: vec!() };
+    match contents.parse::<toml::Value>() {
+        Ok(toml::Value::Table(document)) => validator.document(path, &document),
+        Ok(_) => validator.error("", "the file isn't a TOML table".to_string()),
+        Err(err) => {
+            // The position is reported separately, drop it from the message.
+            let message = err.to_string();
+            let message = message.rfind(" at line ").map(|end| message[..end].to_string()).unwrap_or(message);
+            let (line, column) = err.line_col().map(|(line, column)| (Some(line + 1), Some(column + 1))).unwrap_or((None, None));
+            validator.diagnostics.push(Diagnostic { severity: Severity::Error, line, column, message: format!("invalid TOML: {}", message) });
+        }
+    }
+    let mut diagnostics = validator.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line.is_none(), diagnostic.line, diagnostic.column));
+    diagnostics
+}
+
+/// Fails with the errors of the configuration file at `path`, if it has any.
+pub(crate) fn errors(path: &Path) -> Result<(), String> {
+    let errors: std::vec::Vec<String> = validate(path).into_iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .map(|diagnostic| format!("  {}: {}", diagnostic.location(path), diagnostic.message))
+        .collect();
+    match errors.len() {
+        0 => Ok(()),
+        _ => Err(format!("the inventory is invalid, fix it or restore it with `rman config undo`:\n{}", errors.join("\n"))),
+    }
+}
+
+/// Checks the values of a host before it is written, as a `|` in any of them would shift the
+/// values of every following host.
+pub fn check_host(host: &Host) -> Result<(), String> {
+    check_alias(&host.alias)?;
+    if !is_valid_address(&host.ip) {
+        return Err(format!("Invalid IP address or hostname '{}' for host {}", host.ip, host.alias));
+    }
+    let mut values = vec!(&host.ssh_user, &host.pk_path, &host.description);
+    values.extend(host.tags.iter());
+    match values.into_iter().find(|value| value.contains('|')) {
+        Some(value) => Err(format!("'{}' of host {} can't contain '|', which separates hosts in the configuration file", value, host.alias)),
+        None => Ok(()),
+    }
+}
+
+/// Checks an alias can be written and targeted: it can't be empty or contain the separators of
+/// host lists and target specifications.
+fn check_alias(alias: &str) -> Result<(), String> {
+    if alias.is_empty() {
+        return Err(String::from("Aliases can't be empty"));
+    }
+    if alias.contains(|c: char| c.is_whitespace() || c == ',' || c == '|') {
+        return Err(format!("Alias '{}' can't contain spaces, commas or '|'", alias));
+    }
+    Ok(())
+}
+
+/// Whether `address` is an IP address or a valid hostname.
+/// # Examples
+/// ```
+/// use rman::validate::is_valid_address;
+///
+/// assert!(is_valid_address("10.0.0.5"));
+/// assert!(is_valid_address("web-01.example.com"));
+/// assert!(!is_valid_address("10.0.0.300"));
+/// assert!(!is_valid_address("web 01"));
+/// ```
+pub fn is_valid_address(address: &str) -> bool {
+    if address.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    // Dotted numbers that aren't an IPv4 address, such as 10.0.0.300, are typos rather than names.
+    if address.split('.').all(|label| label.chars().all(|c| c.is_ascii_digit())) {
+        return false;
+    }
+    let name = address.strip_suffix('.').unwrap_or(address);
+    // Underscores aren't allowed by RFC 1123, but resolvers accept them and internal names use them.
+    name.len() <= 253 && name.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// This function handles `$rman config check`, printing every diagnostic and failing if the
+/// configuration file has errors.
+pub fn check(path: &Path) -> Result<(), String> {
+    if !path.exists() {
+        println!("{} doesn't exist yet, create it with `rman init`", path.display());
+        return Ok(());
+    }
+    let diagnostics = validate(path);
+    for diagnostic in diagnostics.iter() {
+        let severity = match diagnostic.severity {
+            Severity::Error => style::red("error"),
+            Severity::Warning => style::yellow("warning"),
+        };
+        println!("{}: {}: {}", diagnostic.location(path), severity, diagnostic.message);
+    }
+    let errors = diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count();
+    let warnings = diagnostics.len() - errors;
+    match (errors, warnings) {
+        (0, 0) => println!("{} is {}", path.display(), style::green("valid")),
+        (0, _) => println!("{} is {} with {} warnings", path.display(), style::green("valid"), warnings),
+        _ => return Err(format!("{} has {} errors and {} warnings, rman refuses to use it until the errors are fixed", path.display(), errors, warnings)),
+    }
+    Ok(())
+}
+
+/// Lines the keys and tables of a TOML file are on, by their dotted path such as `groups.web.port`.
+/// Found by scanning the lines, as the parsed document doesn't keep positions.
+struct Lines {
+    keys: BTreeMap<String, usize>,      // Line of each key and table header.
+    text: std::vec::Vec<String>,        // The lines themselves, to find values within them.
+}
+
+impl Lines {
+    fn index(contents: &str) -> Lines {
+        let mut keys = BTreeMap::new();
+        let mut table = String::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                let name = line.trim_start_matches('[').split(']').next().unwrap_or("");
+                table = dotted(name);
+                keys.entry(table.clone()).or_insert(i + 1);
+            } else if let Some((key, _)) = line.split_once('=').filter(|_| !line.starts_with('#')) {
+                let key = match table.as_str() {
+                    "" => dotted(key),
+                    table => format!("{}.{}", table, dotted(key)),
+                };
+                keys.entry(key).or_insert(i + 1);
+            }
+        }
+        Lines { keys, text: contents.lines().map(String::from).collect() }
+    }
+
+    /// Line of a key, or of the closest table containing it, e.g. for keys of inline tables.
+    fn line(&self, key: &str) -> Option<usize> {
+        let mut key = key;
+        loop {
+            if let Some(line) = self.keys.get(key) {
+                return Some(*line);
+            }
+            key = &key[..key.rfind('.')?];
+        }
+    }
+
+    /// Line and column of the value at `index` of a host list such as `ip = "a|b|c"`. The column
+    /// is left out if escapes make it uncertain.
+    fn entry(&self, key: &str, index: usize) -> (Option<usize>, Option<usize>) {
+        let line = match self.keys.get(key) {
+            Some(line) => *line,
+            None => return (None, None),
+        };
+        let text = &self.text[line - 1];
+        let start = match text.find(['"', '\'']) {
+            Some(quote) => quote + 1,
+            None => return (Some(line), None),
+        };
+        if text[start..].contains('\\') {
+            return (Some(line), None);
+        }
+        let offset: usize = text[start..].split('|').take(index).map(|value| value.chars().count() + 1).sum();
+        (Some(line), Some(text[..start].chars().count() + offset + 1))
+    }
+}
+
+/// Normalizes a key or table name, e.g. `groups . "web"` to `groups.web`.
+fn dotted(name: &str) -> String {
+    name.split('.').map(|part| part.trim().trim_matches(|c| c == '"' || c == '\'')).collect::<std::vec::Vec<_>>().join(".")
+}
+
+/// Collects the diagnostics of one file.
+struct Validator {
+    lines: Lines,
+    diagnostics: std::vec::Vec<Diagnostic>,
+}
+
+impl Validator {
+    fn error(&mut self, key: &str, message: String) {
+        let line = self.lines.line(key);
+        self.diagnostics.push(Diagnostic { severity: Severity::Error, line, column: None, message });
+    }
+
+    fn warning(&mut self, key: &str, message: String) {
+        let line = self.lines.line(key);
+        self.diagnostics.push(Diagnostic { severity: Severity::Warning, line, column: None, message });
+    }
+
+    /// An error about the value at `index` of the host list `key`.
+    fn entry_error(&mut self, key: &str, index: usize, message: String) {
+        let (line, column) = self.lines.entry(key, index);
+        self.diagnostics.push(Diagnostic { severity: Severity::Error, line, column, message });
+    }
+
+    /// A warning about the value at `index` of the host list `key`.
+    fn entry_warning(&mut self, key: &str, index: usize, message: String) {
+        let (line, column) = self.lines.entry(key, index);
+        self.diagnostics.push(Diagnostic { severity: Severity::Warning, line, column, message });
+    }
+
+    fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    fn document(&mut self, path: &Path, document: &toml::value::Table) {
+        let lists = self.host_lists(document);
+        let aliases = lists.get("alias").cloned().unwrap_or_default();
+        for (key, value) in document.iter() {
+            match key.as_str() {
+                key if HOST_LISTS.contains(&key) => (),
+                "defaults" => self.settings("defaults", value, false),
+                "groups" => match value.as_table() {
+                    Some(groups) => {
+                        for (name, group) in groups.iter() {
+                            self.settings(&format!("groups.{}", name), group, true);
+                        }
+                    }
+                    None => self.error(key, String::from("`groups` must be a table of [groups.<name>] sections")),
+                },
+                "vars" => match value.as_table() {
+                    Some(vars) => {
+                        for (alias, table) in vars.iter() {
+                            let key = format!("vars.{}", alias);
+                            if !table.is_table() {
+                                self.error(&key, format!("variables of host {} must be a table", alias));
+                            } else if !aliases.contains(alias) {
+                                self.warning(&key, format!("[vars.{}] names no host, its variables are unused", alias));
+                            }
+                        }
+                    }
+                    None => self.error(key, String::from("`vars` must be a table of [vars.<alias>] sections")),
+                },
+                "guard" => self.guard(value, &aliases),
+                "sources" => match value.as_table() {
+                    Some(sources) => {
+                        for (name, source) in sources.iter() {
+                            self.source(&format!("sources.{}", name), source);
+                        }
+                    }
+                    None => self.error(key, String::from("`sources` must be a table of [sources.<name>] sections")),
+                },
+                "thresholds" => self.section(key, value, &THRESHOLDS, |value| value.is_integer() || value.is_float(), "a number"),
+                "history" => self.section(key, value, &["capture_output"], toml::Value::is_bool, "true or false"),
+                "backups" => self.section(key, value, &["keep"], |value| value.as_integer().is_some_and(|keep| keep >= 1), "a number of at least 1"),
+                key => self.warning(key, format!("unknown field `{}`", key)),
+            }
+        }
+        self.groups(document, &lists);
+        // The hosts are only built once their lists are known to line up.
+        if !self.has_errors() {
+            self.keys(path, &aliases);
+        }
+    }
+
+    /// Checks the host lists, returning their values by key if each is a string.
+    fn host_lists(&mut self, document: &toml::value::Table) -> BTreeMap<String, std::vec::Vec<String>> {
+        let mut lists = BTreeMap::new();
+        for key in HOST_LISTS.iter() {
+            match document.get(*key) {
+                Some(toml::Value::String(values)) => {
+                    lists.insert(key.to_string(), values.split('|').map(String::from).collect::<std::vec::Vec<String>>());
+                }
+                Some(_) => self.error(key, format!("`{}` must be a string of values separated by '|'", key)),
+                None => (),
+            }
+        }
+        // A file without hosts has an empty or no alias list.
+        let aliases = match lists.get("alias") {
+            Some(aliases) if aliases.len() > 1 || !aliases[0].is_empty() => aliases.clone(),
+            _ => {
+                lists.insert(String::from("alias"), vec!());
+                return lists;
+            }
+        };
+        if !lists.contains_key("ip") && document.get("ip").is_none() {
+            self.error("alias", String::from("`ip` is missing, every host needs an IP address or hostname"));
+        }
+        // Lists of another length would pair values with the wrong hosts, they aren't checked further.
+        lists.retain(|key, values| {
+            if values.len() == aliases.len() {
+                return true;
+            }
+            let line = self.lines.line(key);
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Error, line, column: None,
+                message: format!("`{}` has {} values but `alias` has {}, every host needs one value in each list, even if empty", key, values.len(), aliases.len()),
+            });
+            false
+        });
+
+        let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+        for (i, alias) in aliases.iter().enumerate() {
+            if let Err(err) = check_alias(alias) {
+                self.entry_error("alias", i, format!("{} (host {})", err, i + 1));
+            } else if let Some(first) = seen.get(alias.as_str()) {
+                self.entry_error("alias", i, format!("duplicate alias '{}', host {} has it already", alias, first + 1));
+            } else {
+                seen.insert(alias, i);
+            }
+        }
+        let value = |key: &str, i: usize| lists.get(key).map(|values| values[i].clone()).unwrap_or_default();
+        for (i, alias) in aliases.iter().enumerate() {
+            let ip = value("ip", i);
+            if !lists.contains_key("ip") {
+                // Reported once above, or as a list of the wrong length.
+            } else if ip.is_empty() {
+                self.entry_error("ip", i, format!("host {} has no IP address or hostname", alias));
+            } else if !is_valid_address(&ip) {
+                self.entry_error("ip", i, format!("invalid IP address or hostname '{}' for host {}", ip, alias));
+            }
+            let port = value("port", i);
+            if !port.is_empty() && !port.parse::<u16>().is_ok_and(|port| port > 0) {
+                self.entry_error("port", i, format!("invalid port '{}' for host {}, expected 1 to 65535", port, alias));
+            }
+            let timeout = value("timeout", i);
+            if !timeout.is_empty() && timeout.parse::<u64>().is_err() {
+                self.entry_error("timeout", i, format!("invalid timeout '{}' for host {}, expected seconds", timeout, alias));
+            }
+        }
+        lists.insert(String::from("alias"), aliases);
+        lists
+    }
+
+    /// Checks the connection settings of `[defaults]` or a `[groups.<name>]` section.
+    fn settings(&mut self, table: &str, value: &toml::Value, is_group: bool) {
+        let settings = match value.as_table() {
+            Some(settings) => settings,
+            None => {
+                self.error(table, format!("`{}` must be a table", table));
+                return;
+            }
+        };
+        for (key, value) in settings.iter() {
+            let path = format!("{}.{}", table, key);
+            let valid = match key.as_str() {
+                "ssh_user" | "pk_path" => value.is_str(),
+                "port" => value.as_integer().is_some_and(|port| (1..=65535).contains(&port)),
+                "timeout" => value.as_integer().is_some_and(|timeout| timeout >= 0),
+                "become" => value.is_str() || value.is_bool(),
+                "vars" if is_group => value.is_table(),
+                _ => {
+                    self.warning(&path, format!("unknown field `{}` in [{}]", key, table));
+                    continue;
+                }
+            };
+            if !valid {
+                let expected = match key.as_str() {
+                    "port" => "a port from 1 to 65535",
+                    "timeout" => "a number of seconds",
+                    "become" => "true, false or a user name",
+                    "vars" => "a table",
+                    _ => "a string",
+                };
+                self.error(&path, format!("`{}` in [{}] must be {}", key, table, expected));
+            }
+        }
+    }
+
+    /// Checks a `[sources.<name>]` section, which needs either a command or a file.
+    fn source(&mut self, table: &str, value: &toml::Value) {
+        let source = match value.as_table() {
+            Some(source) => source,
+            None => return self.error(table, format!("`{}` must be a table", table)),
+        };
+        for (key, value) in source.iter() {
+            let path = format!("{}.{}", table, key);
+            let (valid, expected) = match key.as_str() {
+                "command" => (value.as_array().is_some_and(|args| !args.is_empty() && args.iter().all(toml::Value::is_str)), "a non-empty list of strings"),
+                "file" => (value.is_str(), "a string"),
+                "ttl" => (value.as_integer().is_some_and(|ttl| ttl >= 0), "a number of seconds"),
+                "timeout" => (value.as_integer().is_some_and(|timeout| timeout >= 1), "a number of seconds of at least 1"),
+                _ => {
+                    self.warning(&path, format!("unknown field `{}` in [{}]", key, table));
+                    continue;
+                }
+            };
+            if !valid {
+                self.error(&path, format!("`{}` in [{}] must be {}", key, table, expected));
+            }
+        }
+        if source.contains_key("command") == source.contains_key("file") {
+            self.error(table, format!("[{}] needs either `command` or `file`", table));
+        }
+    }
+
+    /// Checks the `[guard]` section, including that its protected targets match hosts.
+    fn guard(&mut self, value: &toml::Value, aliases: &[String]) {
+        let guard = match value.as_table() {
+            Some(guard) => guard,
+            None => return self.error("guard", String::from("`guard` must be a table")),
+        };
+        let hosts: std::vec::Vec<Host> = aliases.iter().map(|alias| Host::new(alias, "", "", "")).collect();
+        for (key, value) in guard.iter() {
+            let path = format!("guard.{}", key);
+            let strings = match value.as_array().map(|values| values.iter().map(toml::Value::as_str).collect::<Option<std::vec::Vec<&str>>>()) {
+                Some(Some(strings)) => strings,
+                _ if key == "protected" || key == "denylist" => {
+                    self.error(&path, format!("`{}` in [guard] must be a list of strings", key));
+                    continue;
+                }
+                _ => {
+                    self.warning(&path, format!("unknown field `{}` in [guard]", key));
+                    continue;
+                }
+            };
+            match key.as_str() {
+                "protected" => {
+                    for part in strings.iter().flat_map(|spec| targets::split_list(spec)) {
+                        match targets::resolve(&part, &hosts) {
+                            Ok(_) => (),
+                            Err(_) if !part.starts_with('~') && !part.contains(['*', '?', '[']) => {
+                                self.warning(&path, format!("protected host '{}' isn't in the inventory", part));
+                            }
+                            Err(err) => self.warning(&path, format!("protected target '{}' matches no host: {}", part, err)),
+                        }
+                    }
+                }
+                "denylist" => {
+                    for pattern in strings.iter() {
+                        if let Err(err) = regex::Regex::new(pattern) {
+                            self.warning(&path, format!("invalid denylist pattern '{}', it is ignored: {}", pattern, err));
+                        }
+                    }
+                }
+                _ => self.warning(&path, format!("unknown field `{}` in [guard]", key)),
+            }
+        }
+    }
+
+    /// Checks the keys of a section that only holds values of one kind.
+    fn section<F: Fn(&toml::Value) -> bool>(&mut self, name: &str, value: &toml::Value, keys: &[&str], valid: F, expected: &str) {
+        let section = match value.as_table() {
+            Some(section) => section,
+            None => return self.error(name, format!("`{}` must be a table", name)),
+        };
+        for (key, value) in section.iter() {
+            let path = format!("{}.{}", name, key);
+            if !keys.contains(&key.as_str()) {
+                self.warning(&path, format!("unknown field `{}` in [{}]", key, name));
+            } else if !valid(value) {
+                self.error(&path, format!("`{}` in [{}] must be {}", key, name, expected));
+            }
+        }
+    }
+
+    /// Warns about `[groups.<name>]` sections no host is tagged with, as their settings apply to
+    /// none, and about tags without a group, which are likely typos once the file defines groups.
+    /// Tags are also used to select hosts, so they don't need a group in a file without any.
+    fn groups(&mut self, document: &toml::value::Table, lists: &BTreeMap<String, std::vec::Vec<String>>) {
+        let groups = match document.get("groups").and_then(toml::Value::as_table) {
+            Some(groups) => groups,
+            None => return,
+        };
+        let tags: std::vec::Vec<std::vec::Vec<String>> = lists.get("tags").map(|tags| tags.iter().map(|tags| host::parse_tags(tags)).collect()).unwrap_or_default();
+        for name in groups.keys().filter(|name| !tags.iter().flatten().any(|tag| tag == *name)) {
+            self.warning(&format!("groups.{}", name), format!("no host is tagged {}, so [groups.{}] applies to none", name, name));
+        }
+        // Each undefined tag is reported once, at the first host using it.
+        let aliases = lists.get("alias").cloned().unwrap_or_default();
+        let mut reported: std::vec::Vec<&str> = vec!();
+        for (i, host_tags) in tags.iter().enumerate() {
+            for tag in host_tags.iter().filter(|tag| !groups.contains_key(*tag)) {
+                if !reported.contains(&tag.as_str()) {
+                    reported.push(tag);
+                    let alias = aliases.get(i).map(String::as_str).unwrap_or("");
+                    self.entry_warning("tags", i, format!("host {} is tagged {}, but there is no [groups.{}], so no group settings apply", alias, tag, tag));
+                }
+            }
+        }
+    }
+
+    /// Warns about hosts without an ssh user or private key, and about keys that are missing or
+    /// that other users can read, which ssh refuses to use.
+    fn keys(&mut self, path: &Path, aliases: &[String]) {
+        let hosts = match host::parse_hosts(path) {
+            Ok(hosts) => hosts,
+            Err(err) => return self.error("", format!("unable to read the hosts: {}", err)),
+        };
+        // Hosts usually share keys, each is checked once.
+        let mut keys: BTreeMap<String, std::vec::Vec<&Host>> = BTreeMap::new();
+        for host in hosts.iter() {
+            if host.ssh_user.is_empty() {
+                self.warning("alias", format!("host {} has no ssh user, set one for it, one of its groups or [defaults]", host.alias));
+            }
+            if host.pk_path.is_empty() {
+                self.warning("alias", format!("host {} has no private key, set one for it, one of its groups or [defaults]", host.alias));
+            } else {
+                keys.entry(host.pk_path.clone()).or_default().push(host);
+            }
+        }
+        for (key, users) in keys.iter() {
+            let problem = match std::fs::metadata(expand_home(key)) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::from("doesn't exist"),
+                Err(err) => format!("can't be read: {}", err),
+                Ok(metadata) => match readable_by_others(&metadata) {
+                    Some(mode) => format!("can be read by other users (mode {:o}) and ssh refuses such keys, run `chmod 600 {}`", mode, key),
+                    None => continue,
+                },
+            };
+            let names: std::vec::Vec<&str> = users.iter().map(|host| host.alias.as_str()).collect();
+            // Point at where the first host using the key gets it from.
+            let (line, column) = match users[0].inherited.get("pk_path").map(String::as_str) {
+                Some("[defaults]") => (self.lines.line("defaults.pk_path"), None),
+                Some(origin) => (origin.strip_prefix("group ").and_then(|group| self.lines.line(&format!("groups.{}.pk_path", group))), None),
+                None => match aliases.iter().position(|alias| *alias == users[0].alias) {
+                    Some(index) => self.lines.entry("pk_path", index),
+                    None => (None, None),
+                },
+            };
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Warning, line, column,
+                message: format!("private key {} of {} {}", key, names.join(", "), problem),
+            });
+        }
+    }
+}
+
+/// Expands a leading `~/` to the home directory, like ssh does for identity files.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// The permissions of a file if users other than its owner can access it.
+#[cfg(unix)]
+fn readable_by_others(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 { Some(mode) } else { None }
+}
+
+#[cfg(not(unix))]
+fn readable_by_others(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Validates `contents` as a configuration file, the file is removed again.
+    fn run(name: &str, contents: &str) -> std::vec::Vec<Diagnostic> {
+        let path = std::env::temp_dir().join(format!("rman-validate-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let diagnostics = validate(&path);
+        std::fs::remove_file(&path).unwrap();
+        diagnostics
+    }
+
+    fn messages(diagnostics: &[Diagnostic], severity: Severity) -> std::vec::Vec<String> {
+        diagnostics.iter().filter(|diagnostic| diagnostic.severity == severity).map(|diagnostic| diagnostic.message.clone()).collect()
+    }
+
+    /// Hosts whose keys exist and aren't readable by others, so only the interesting warnings remain.
+    fn hosts(tags: &str) -> String {
+        let key = std::env::temp_dir().join(format!("rman-validate-key-{}", std::process::id()));
+        std::fs::write(&key, "key").unwrap();
+        #[cfg(unix)]
+        std::fs::set_permissions(&key, std::os::unix::fs::PermissionsExt::from_mode(0o600)).unwrap();
+        format!("alias = \"web01|web02\"\nip = \"10.0.0.5|10.0.0.6\"\nssh_user = \"root|root\"\npk_path = \"{0}|{0}\"\ntags = \"{1}\"\n", key.display(), tags)
+    }
+
+    #[test]
+    fn accepts_a_valid_inventory() {
+        let diagnostics = run("valid", &format!("{}\n[groups.web]\nport = 2222\n", hosts("web|web")));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn locates_errors_in_host_lists() {
+        let diagnostics = run("lists", "alias = \"web01|web01\"\nip = \"10.0.0.5|10.0.0.300\"\n");
+        let errors = messages(&diagnostics, Severity::Error);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("duplicate alias 'web01'"));
+        assert!(errors[1].contains("invalid IP address or hostname '10.0.0.300'"));
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (Some(1), Some(16)));
+        assert_eq!((diagnostics[1].line, diagnostics[1].column), (Some(2), Some(16)));
+    }
+
+    #[test]
+    fn rejects_lists_of_another_length_and_invalid_settings() {
+        let errors = messages(&run("lengths", "alias = \"a|b\"\nip = \"10.0.0.5\"\n[defaults]\nport = 70000\n"), Severity::Error);
+        assert!(errors.iter().any(|error| error.contains("`ip` has 1 values but `alias` has 2")), "{:?}", errors);
+        assert!(errors.iter().any(|error| error.contains("`port` in [defaults] must be a port from 1 to 65535")), "{:?}", errors);
+        assert!(messages(&run("toml", "alias = \"a\nip"), Severity::Error)[0].starts_with("invalid TOML"));
+    }
+
+    #[test]
+    fn warns_about_unused_and_undefined_groups() {
+        let diagnostics = run("groups", &format!("{}\n[groups.web]\nport = 2222\n[groups.db]\nport = 5432\n", hosts("web|wbe,prod")));
+        assert!(messages(&diagnostics, Severity::Error).is_empty());
+        let warnings = messages(&diagnostics, Severity::Warning);
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings.iter().any(|warning| warning.contains("no host is tagged db")));
+        assert!(warnings.iter().any(|warning| warning.contains("host web02 is tagged wbe, but there is no [groups.wbe]")));
+        assert!(warnings.iter().any(|warning| warning.contains("host web02 is tagged prod")));
+    }
+
+    #[test]
+    fn tags_need_no_group_in_files_without_groups() {
+        assert!(run("tags", &hosts("web|db")).is_empty());
+    }
+
+    #[test]
+    fn warns_about_unknown_fields_such_as_jump_hosts() {
+        let warnings = messages(&run("unknown", &format!("{}jump = \"bastion\"\n[vars.web03]\nrole = \"x\"\n", hosts("|"))), Severity::Warning);
+        assert!(warnings.iter().any(|warning| warning == "unknown field `jump`"), "{:?}", warnings);
+        assert!(warnings.iter().any(|warning| warning.contains("[vars.web03] names no host")));
+    }
+
+    #[test]
+    fn checks_hosts_before_they_are_added() {
+        assert!(check_host(&Host::new("web01", "10.0.0.5", "root", "~/.ssh/id")).is_ok());
+        assert!(check_host(&Host::new("web|01", "10.0.0.5", "root", "~/.ssh/id")).is_err());
+        assert!(check_host(&Host::new("web01", "999.0.0.1", "root", "~/.ssh/id")).is_err());
+        assert!(is_valid_address("db-1.example.com") && is_valid_address("::1") && !is_valid_address("-bad"));
+    }
+}
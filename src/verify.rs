@@ -0,0 +1,462 @@
+//! Provides `$rman verify`, which evaluates declarative compliance checks on every host.
+//!
+//! ```toml
+//! targets = "web-*"                   # hosts to check, defaults to every host
+//! where = "os=ubuntu"                 # optionally narrowed by cached facts
+//!
+//! [[check]]
+//! name = "sshd config locked down"
+//! file = "/etc/ssh/sshd_config"
+//! mode = "600"                        # optional, as are owner and group
+//! owner = "root"
+//!
+//! [[check]]
+//! name = "https listening"
+//! port = 443                          # protocol = "udp" for UDP ports
+//!
+//! [[check]]
+//! name = "nginx running"
+//! process = "nginx"
+//!
+//! [[check]]
+//! name = "openssl patched"
+//! package = "openssl"
+//! version = ">=3.0.2"                 # optional, =, !=, <, <=, > or >=, compared component-wise
+//!
+//! [[check]]
+//! name = "no forwarding"
+//! sysctl = "net.ipv4.ip_forward"
+//! value = "0"
+//!
+//! [[check]]
+//! name = "nginx version"
+//! command = "nginx -v 2>&1"
+//! matches = "nginx/1\\.2[0-9]"        # optional regex, the command also has to exit with `rc` (default 0)
+//! ```
+//!
+//! All checks of a host are gathered with a single remote command and evaluated locally.
+
+use crate::filter;
+use crate::host::{get_hosts, Host};
+use crate::ssh_con::{run_remote_command, shell_quote};
+use crate::style;
+use crate::table::{Cell, Table};
+use crate::targets;
+use regex::Regex;
+use serde_derive::Deserialize;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+/// A file of checks.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Checks {
+    pub targets: Option<String>,
+    #[serde(rename = "where")]
+    pub where_expr: Option<String>,
+    #[serde(default, rename = "check")]
+    pub checks: std::vec::Vec<Check>,
+}
+
+/// A single assertion. Exactly one of `file`, `port`, `process`, `package`, `sysctl` and `command` has to be given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Check {
+    pub name: String,
+    pub file: Option<String>,
+    pub mode: Option<String>,           // Octal mode the file must have.
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub port: Option<u16>,
+    pub protocol: Option<String>,       // tcp (default) or udp.
+    pub process: Option<String>,
+    pub package: Option<String>,
+    pub version: Option<String>,        // e.g. ">=3.0.2", a bare version must match exactly.
+    pub sysctl: Option<String>,
+    pub value: Option<String>,          // Expected kernel parameter value.
+    pub command: Option<String>,
+    pub matches: Option<String>,        // Regex the command output must match.
+    pub rc: Option<i32>,                // Expected exit status of the command, defaults to 0.
+}
+
+/// Result of one check on one host.
+pub enum Verdict {
+    Pass,
+    Fail(String),
+    Error(String),                      // The check could not be evaluated, e.g. the host is unreachable.
+}
+
+/// Shell function printing the installed version of a package, or nothing if it isn't installed.
+const PKGVER: &str = "pkgver() { if command -v dpkg-query >/dev/null 2>&1; then dpkg-query -W -f='${Status} ${Version}' \"$1\" 2>/dev/null | grep 'ok installed' | awk '{print $4}'; \
+elif command -v rpm >/dev/null 2>&1; then rpm -q --qf '%{VERSION}-%{RELEASE}' \"$1\" 2>/dev/null | grep -v 'not installed'; \
+elif command -v pacman >/dev/null 2>&1; then pacman -Q \"$1\" 2>/dev/null | awk '{print $2}'; \
+elif command -v apk >/dev/null 2>&1; then apk list -I \"$1\" 2>/dev/null | awk '{print $1}' | sed \"s/^$1-//\"; fi; }; ";
+
+impl Check {
+    /// Number of assertion kinds given, which has to be exactly one.
+    fn kinds(&self) -> usize {
+        [self.file.is_some(), self.port.is_some(), self.process.is_some(), self.package.is_some(), self.sysctl.is_some(), self.command.is_some()]
+            .iter().filter(|kind| **kind).count()
+    }
+
+    /// Shell snippet printing what the check needs to know about the host.
+    fn probe(&self) -> String {
+        if let Some(ref file) = self.file {
+            format!("stat -c '%a %U %G' -- {} 2>/dev/null || echo __rman_missing", shell_quote(file))
+        } else if let Some(port) = self.port {
+            // The status of `ss` is printed instead of a count if it failed, e.g. with 127 if it is missing.
+            let flag = if self.protocol.as_deref() == Some("udp") { "-Hlnu" } else { "-Hlnt" };
+            format!("ports=$(ss {} 2>/dev/null); rc=$?; if [ $rc -ne 0 ]; then echo \"__rman_rc $rc\"; else printf '%s\\n' \"$ports\" | awk '{{print $4}}' | grep -c ':{}$'; fi", flag, port)
+        } else if let Some(ref process) = self.process {
+            format!("pgrep -x -- {} >/dev/null && echo running || echo stopped", shell_quote(process))
+        } else if let Some(ref package) = self.package {
+            format!("pkgver {}", shell_quote(package))
+        } else if let Some(ref sysctl) = self.sysctl {
+            format!("sysctl -n -- {} 2>/dev/null || echo __rman_missing", shell_quote(sysctl))
+        } else {
+            // The exit status is printed last, so the output may contain anything.
+            format!("( {} ) 2>&1; echo \"__rman_rc $?\"", self.command.as_deref().unwrap_or("true"))
+        }
+    }
+
+    /// Decides the check from the output of its probe.
+    fn evaluate(&self, output: &str) -> Verdict {
+        let output = output.trim();
+        let fail = |reason: String| Verdict::Fail(reason);
+        if let Some(ref file) = self.file {
+            if output == "__rman_missing" {
+                return fail(format!("{} does not exist", file));
+            }
+            let fields: std::vec::Vec<&str> = output.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Verdict::Error(format!("unexpected stat output '{}'", output));
+            }
+            if let Some(ref mode) = self.mode {
+                let expected = u32::from_str_radix(mode, 8).ok();
+                if expected.is_none() || u32::from_str_radix(fields[0], 8).ok() != expected {
+                    return fail(format!("mode is {}, expected {}", fields[0], mode));
+                }
+            }
+            if let Some(ref owner) = self.owner {
+                if fields[1] != owner {
+                    return fail(format!("owner is {}, expected {}", fields[1], owner));
+                }
+            }
+            if let Some(ref group) = self.group {
+                if fields[2] != group {
+                    return fail(format!("group is {}, expected {}", fields[2], group));
+                }
+            }
+            Verdict::Pass
+        } else if let Some(port) = self.port {
+            match output.strip_prefix("__rman_rc ") {
+                Some("127") => return Verdict::Error(String::from("ss is not installed on the host")),
+                Some(rc) => return Verdict::Error(format!("ss failed with exit status {}", rc)),
+                None => (),
+            }
+            match output.parse::<u32>() {
+                Ok(0) => fail(format!("nothing listens on port {}", port)),
+                Ok(_) => Verdict::Pass,
+                Err(_) => Verdict::Error(String::from("unable to list listening ports")),
+            }
+        } else if let Some(ref process) = self.process {
+            if output == "running" { Verdict::Pass } else { fail(format!("{} is not running", process)) }
+        } else if let Some(ref package) = self.package {
+            if output.is_empty() {
+                return fail(format!("{} is not installed", package));
+            }
+            match self.version {
+                Some(ref version) => match version_matches(output, version) {
+                    Ok(true) => Verdict::Pass,
+                    Ok(false) => fail(format!("{} {} is installed, expected {}", package, output, version.trim())),
+                    Err(err) => Verdict::Error(err),
+                },
+                None => Verdict::Pass,
+            }
+        } else if let Some(ref sysctl) = self.sysctl {
+            if output == "__rman_missing" {
+                return fail(format!("{} does not exist", sysctl));
+            }
+            // Multi-valued parameters are separated by tabs.
+            let actual = output.split_whitespace().collect::<std::vec::Vec<_>>().join(" ");
+            let expected = self.value.as_deref().unwrap_or("").split_whitespace().collect::<std::vec::Vec<_>>().join(" ");
+            if actual == expected { Verdict::Pass } else { fail(format!("{} is {}, expected {}", sysctl, actual, expected)) }
+        } else {
+            let (body, rc) = match output.rfind("__rman_rc ") {
+                Some(at) => (output[..at].trim_end(), output[at + 10..].trim().parse::<i32>().ok()),
+                None => return Verdict::Error(String::from("the command did not finish")),
+            };
+            let expected_rc = self.rc.unwrap_or(0);
+            if rc != Some(expected_rc) {
+                return fail(format!("exited with {}, expected {}", rc.map(|rc| rc.to_string()).unwrap_or_default(), expected_rc));
+            }
+            match self.matches {
+                Some(ref pattern) => match Regex::new(pattern) {
+                    Ok(regex) if regex.is_match(body) => Verdict::Pass,
+                    Ok(_) => fail(format!("output does not match {}: {}", pattern, body.lines().next().unwrap_or(""))),
+                    Err(err) => Verdict::Error(err.to_string()),
+                },
+                None => Verdict::Pass,
+            }
+        }
+    }
+}
+
+/// This function handles `$rman verify`.
+/// Exits with status 1 if any check failed or could not be evaluated.
+pub fn base(path: &Path, junit: Option<PathBuf>, wide: bool) {
+    let checks = match load(path) {
+        Ok(checks) => checks,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut hosts = match get_hosts().and_then(|inventory| targets::resolve(checks.targets.as_deref().unwrap_or("*"), &inventory)) {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(ref expr) = checks.where_expr {
+        hosts = match filter::select(hosts, expr, false) {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let verdicts: std::vec::Vec<std::vec::Vec<Verdict>> = hosts.iter().map(|host| run(host, &checks.checks)).collect();
+    print_matrix(&checks.checks, &hosts, &verdicts, wide);
+    if let Some(ref junit) = junit {
+        match std::fs::write(junit, junit_xml(&checks.checks, &hosts, &verdicts)) {
+            Ok(_) => println!("JUnit report written to {}", junit.display()),
+            Err(err) => println!("Unable to write {}: {}", junit.display(), err),
+        }
+    }
+    let failed = verdicts.iter().flatten().filter(|verdict| !matches!(verdict, Verdict::Pass)).count();
+    let total = verdicts.iter().map(std::vec::Vec::len).sum::<usize>();
+    if failed == 0 {
+        println!("{} {} checks passed on {} hosts", style::green("PASS"), total, hosts.len());
+    } else {
+        println!("{} {} of {} checks failed", style::red("FAIL"), failed, total);
+        std::process::exit(1);
+    }
+}
+
+/// Reads and checks a file of checks.
+pub fn load(path: &Path) -> Result<Checks, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {}", path.display(), err))?;
+    let checks: Checks = toml::from_str(&contents).map_err(|err| format!("Invalid checks {}: {}", path.display(), err))?;
+    for check in checks.checks.iter() {
+        if check.kinds() != 1 {
+            return Err(format!("Check '{}' needs exactly one of file, port, process, package, sysctl or command", check.name));
+        }
+        if check.sysctl.is_some() && check.value.is_none() {
+            return Err(format!("Check '{}' needs the expected value of {}", check.name, check.sysctl.as_deref().unwrap_or_default()));
+        }
+        if let Some(ref protocol) = check.protocol {
+            if check.port.is_none() {
+                return Err(format!("Check '{}' gives a protocol without a port", check.name));
+            }
+            if protocol != "tcp" && protocol != "udp" {
+                return Err(format!("Check '{}' has an unknown protocol '{}', expected tcp or udp", check.name, protocol));
+            }
+        }
+        if let Some(ref pattern) = check.matches {
+            Regex::new(pattern).map_err(|err| format!("Check '{}': {}", check.name, err))?;
+        }
+        if let Some(ref version) = check.version {
+            version_matches("0", version).map_err(|err| format!("Check '{}': {}", check.name, err))?;
+        }
+    }
+    Ok(checks)
+}
+
+/// Whether an installed package version satisfies a requirement such as `>=3.0.2` or `1.2.3-1`.
+fn version_matches(installed: &str, requirement: &str) -> Result<bool, String> {
+    let requirement = requirement.trim();
+    let split = requirement.find(|c: char| !"<>=!".contains(c)).unwrap_or(requirement.len());
+    let (op, wanted) = (&requirement[..split], requirement[split..].trim());
+    if wanted.is_empty() {
+        return Err(format!("version requirement '{}' has no version", requirement));
+    }
+    let ordering = compare_versions(installed, wanted);
+    match op {
+        "" | "=" | "==" => Ok(ordering == Ordering::Equal),
+        "!=" => Ok(ordering != Ordering::Equal),
+        "<" => Ok(ordering == Ordering::Less),
+        "<=" => Ok(ordering != Ordering::Greater),
+        ">" => Ok(ordering == Ordering::Greater),
+        ">=" => Ok(ordering != Ordering::Less),
+        _ => Err(format!("unknown operator '{}' in version requirement '{}'", op, requirement)),
+    }
+}
+
+/// Orders package versions component by component, so `1.10` is newer than `1.9`. The epoch
+/// (`2:`) is compared first, then the upstream version and then the package revision after the
+/// last `-`, but only if `wanted` names one, so `1.2.3` matches an installed `1.2.3-1ubuntu1`.
+fn compare_versions(installed: &str, wanted: &str) -> Ordering {
+    fn split(version: &str) -> (u64, &str, Option<&str>) {
+        let (epoch, rest) = match version.split_once(':') {
+            Some((epoch, rest)) if epoch.chars().all(|c| c.is_ascii_digit()) => (epoch.parse().unwrap_or(0), rest),
+            _ => (0, version),
+        };
+        match rest.rsplit_once('-') {
+            Some((upstream, revision)) => (epoch, upstream, Some(revision)),
+            None => (epoch, rest, None),
+        }
+    }
+    fn components(version: &str) -> std::vec::Vec<u64> {
+        version.split(|c: char| !c.is_ascii_digit()).filter(|part| !part.is_empty()).filter_map(|part| part.parse().ok()).collect()
+    }
+    fn compare(lhs: &str, rhs: &str) -> Ordering {
+        let (lhs, rhs) = (components(lhs), components(rhs));
+        // Missing trailing components count as zero, so `3.0` == `3.0.0`.
+        (0..lhs.len().max(rhs.len()))
+            .map(|i| lhs.get(i).unwrap_or(&0).cmp(rhs.get(i).unwrap_or(&0)))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+    let (installed_epoch, installed_upstream, installed_revision) = split(installed.trim());
+    let (wanted_epoch, wanted_upstream, wanted_revision) = split(wanted);
+    installed_epoch.cmp(&wanted_epoch)
+        .then_with(|| compare(installed_upstream, wanted_upstream))
+        .then_with(|| match wanted_revision {
+            Some(revision) => compare(installed_revision.unwrap_or(""), revision),
+            None => Ordering::Equal,
+        })
+}
+
+/// Evaluates every check on a host with a single remote command.
+pub fn run(host: &Host, checks: &[Check]) -> std::vec::Vec<Verdict> {
+    let mut script = String::from(PKGVER);
+    for (i, check) in checks.iter().enumerate() {
+        script.push_str(&format!("echo '## {}'; {}; ", i, check.probe()));
+    }
+    let result = run_remote_command(host, &script);
+    if !result.connected {
+        return checks.iter().map(|_| Verdict::Error(result.output.clone())).collect();
+    }
+    // Split the output into the sections introduced by `## <index>`.
+    let mut sections = vec!(String::new(); checks.len());
+    let mut current: Option<usize> = None;
+    for line in result.output.lines() {
+        if let Some(index) = line.strip_prefix("## ").and_then(|index| index.parse::<usize>().ok()).filter(|index| *index < checks.len()) {
+            current = Some(index);
+        } else if let Some(index) = current {
+            sections[index].push_str(line);
+            sections[index].push('\n');
+        }
+    }
+    checks.iter().zip(sections.iter()).map(|(check, output)| check.evaluate(output)).collect()
+}
+
+/// Prints a check by host matrix of PASS / FAIL, followed by the reason of every failure.
+fn print_matrix(checks: &[Check], hosts: &[Host], verdicts: &[std::vec::Vec<Verdict>], wide: bool) {
+    let mut headers = vec!(String::from("check"));
+    headers.extend(hosts.iter().map(|host| host.alias.clone()));
+    let rows = checks.iter().enumerate().map(|(i, check)| {
+        let mut row = vec!(Cell::plain(&check.name));
+        row.extend(verdicts.iter().map(|host_verdicts| match host_verdicts[i] {
+            Verdict::Pass => Cell::painted("PASS", style::green),
+            Verdict::Fail(_) => Cell::painted("FAIL", style::red),
+            Verdict::Error(_) => Cell::painted("ERROR", style::yellow),
+        }));
+        row
+    }).collect();
+    Table { headers, rows }.print(wide);
+
+    for (host, host_verdicts) in hosts.iter().zip(verdicts.iter()) {
+        for (check, verdict) in checks.iter().zip(host_verdicts.iter()) {
+            match verdict {
+                Verdict::Fail(reason) => println!("{} {}: {}: {}", style::red("FAIL"), host.alias, check.name, reason),
+                Verdict::Error(reason) => println!("{} {}: {}: {}", style::yellow("ERROR"), host.alias, check.name, reason.trim()),
+                Verdict::Pass => (),
+            }
+        }
+    }
+}
+
+/// Renders the results as JUnit XML, one test suite per host and one test case per check.
+fn junit_xml(checks: &[Check], hosts: &[Host], verdicts: &[std::vec::Vec<Verdict>]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"rman verify\">\n");
+    for (host, host_verdicts) in hosts.iter().zip(verdicts.iter()) {
+        let failures = host_verdicts.iter().filter(|verdict| matches!(verdict, Verdict::Fail(_))).count();
+        let errors = host_verdicts.iter().filter(|verdict| matches!(verdict, Verdict::Error(_))).count();
+        xml.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n", escape_xml(&host.alias), checks.len(), failures, errors));
+        for (check, verdict) in checks.iter().zip(host_verdicts.iter()) {
+            let case = format!("    <testcase classname=\"{}\" name=\"{}\"", escape_xml(&host.alias), escape_xml(&check.name));
+            match verdict {
+                Verdict::Pass => xml.push_str(&format!("{}/>\n", case)),
+                Verdict::Fail(reason) => xml.push_str(&format!("{}>\n      <failure message=\"{}\"/>\n    </testcase>\n", case, escape_xml(reason))),
+                Verdict::Error(reason) => xml.push_str(&format!("{}>\n      <error message=\"{}\"/>\n    </testcase>\n", case, escape_xml(reason.trim()))),
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Escapes text for use in an XML attribute.
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_versions_component_wise() {
+        assert!(version_matches("1.10.0", ">1.9").unwrap());
+        assert!(!version_matches("1.9.5", ">=1.10").unwrap());
+        assert!(version_matches("3.0.2-0ubuntu1.10", ">=3.0.2").unwrap());
+        assert!(version_matches("3.0", "=3.0.0").unwrap());
+        assert!(!version_matches("3.0.13", "<3.0.2").unwrap());
+    }
+
+    #[test]
+    fn compares_epochs_first_and_revisions_only_when_asked() {
+        assert!(version_matches("1:1.2.0-1", ">2.0").unwrap());
+        assert!(version_matches("1.2.3-1ubuntu1", "1.2.3").unwrap());
+        assert!(!version_matches("1.2.3-1ubuntu1", "1.2.3-2").unwrap());
+        assert!(version_matches("1.2.3-10", ">1.2.3-9").unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_requirements() {
+        assert!(version_matches("1.0", ">=").is_err());
+        assert!(version_matches("1.0", "=>1.0").is_err());
+    }
+
+    fn load_str(name: &str, contents: &str) -> Result<Checks, String> {
+        let path = std::env::temp_dir().join(format!("rman-verify-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let checks = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        checks
+    }
+
+    #[test]
+    fn reports_a_missing_ss_as_an_error_rather_than_a_failure() {
+        let checks = load_str("port", "[[check]]\nname = \"https\"\nport = 443\n").unwrap();
+        let check = &checks.checks[0];
+        assert!(matches!(check.evaluate("2\n"), Verdict::Pass));
+        assert!(matches!(check.evaluate("0\n"), Verdict::Fail(_)));
+        assert!(matches!(check.evaluate("__rman_rc 127\n"), Verdict::Error(ref err) if err.contains("not installed")));
+        assert!(matches!(check.evaluate("__rman_rc 1\n"), Verdict::Error(_)));
+    }
+
+    #[test]
+    fn rejects_unknown_protocols() {
+        assert!(load_str("udp", "[[check]]\nname = \"dns\"\nport = 53\nprotocol = \"udp\"\n").is_ok());
+        let err = load_str("sctp", "[[check]]\nname = \"dns\"\nport = 53\nprotocol = \"UDP \"\n").err().unwrap();
+        assert!(err.contains("unknown protocol 'UDP '"), "{}", err);
+        assert!(load_str("no-port", "[[check]]\nname = \"x\"\nprocess = \"sshd\"\nprotocol = \"tcp\"\n").is_err());
+    }
+}